@@ -0,0 +1,72 @@
+// BLS Certificate Scaling Benchmark
+// Tests: aggregation, verification and signer-set size from 16 to 1024 validators
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, BenchmarkId};
+use ed25519_dalek::SigningKey;
+use rand::rngs::OsRng;
+use sublinear_bft_scifest::bitset::SignerBitmap;
+use sublinear_bft_scifest::bls_crypto::{aggregate_signatures_with_metrics, verify_aggregated, BlsSecretKey};
+use sublinear_bft_scifest::committee::{Committee, LOCALHOST_CHAIN_ID};
+use sublinear_bft_scifest::crypto::SigningIntent;
+use sublinear_bft_scifest::types::ValidatorId;
+
+const SIZES: [usize; 5] = [16, 64, 256, 512, 1024];
+
+fn committee(n: usize) -> (Vec<BlsSecretKey>, Committee) {
+    let keys: Vec<_> = (0..n).map(|_| BlsSecretKey::generate(&mut OsRng)).collect();
+    let ed = (0..n).map(|i| {
+        let mut seed = [0u8; 32];
+        seed[..4].copy_from_slice(&(i as u32 + 1).to_le_bytes());
+        SigningKey::from_bytes(&seed).verifying_key().to_bytes()
+    }).collect();
+    let committee = Committee::localhost(keys.iter().map(|sk| (sk.public_key(), sk.prove_possession(LOCALHOST_CHAIN_ID))).collect(), ed, 20000).unwrap();
+    (keys, committee)
+}
+
+fn bench_certificates(c: &mut Criterion) {
+    let mut group = c.benchmark_group("bls_certificate");
+    group.sample_size(20);
+    for &n in &SIZES {
+        let (keys, committee) = committee(n);
+        let msg = committee.signing_context().message(&SigningIntent::VertexCertificate([7u8; 32]));
+        let quorum = committee.quorum_threshold();
+        // Signers past 64 included: the last quorum-many validators
+        let signatures: Vec<_> = (n - quorum as usize..n).map(|id| (id as ValidatorId, keys[id].sign(&msg))).collect();
+
+        group.bench_with_input(BenchmarkId::new("aggregate", n), &n, |b, _| {
+            b.iter(|| black_box(aggregate_signatures_with_metrics(&signatures, &committee, quorum).unwrap()))
+        });
+
+        let (agg, bitmap, _) = aggregate_signatures_with_metrics(&signatures, &committee, quorum).unwrap();
+        println!("n={}: quorum signer set is {} bytes on the wire", n, bitmap.encoded_len());
+        group.bench_with_input(BenchmarkId::new("verify", n), &n, |b, _| {
+            b.iter(|| black_box(verify_aggregated(&msg, &agg, &committee, &bitmap, quorum)))
+        });
+    }
+
+    group.finish();
+}
+
+fn bench_signer_sets(c: &mut Criterion) {
+    let mut group = c.benchmark_group("signer_bitmap");
+
+    for &n in &SIZES {
+        let ids: Vec<ValidatorId> = (0..n as ValidatorId).filter(|i| i % 10 != 3).collect();
+        group.bench_with_input(BenchmarkId::new("encode", n), &n, |b, &n| {
+            b.iter(|| black_box(SignerBitmap::from_ids(n, ids.iter().copied()).unwrap()))
+        });
+        let bitmap = SignerBitmap::from_ids(n, ids.iter().copied()).unwrap();
+        group.bench_with_input(BenchmarkId::new("decode", n), &n, |b, _| {
+            b.iter(|| black_box(bitmap.ids()))
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_certificates,
+    bench_signer_sets
+);
+criterion_main!(benches);
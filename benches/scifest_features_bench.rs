@@ -1,10 +1,18 @@
 // SciFest Features Comprehensive Benchmark
-// Tests: Geo-Latency, Fault Injection, ML Batching
+// Tests: Geo-Latency, Fault Injection, ML Batching, Simulated Consensus
 
+use std::sync::Arc;
+use std::time::Duration;
 use criterion::{black_box, criterion_group, criterion_main, Criterion, BenchmarkId};
+use ed25519_dalek::SigningKey;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use sublinear_bft_scifest::bls_crypto::BlsSecretKey;
+use sublinear_bft_scifest::committee::{Committee, LOCALHOST_CHAIN_ID};
 use sublinear_bft_scifest::geo_latency::{GeoLatencyMatrix, Region};
 use sublinear_bft_scifest::fault_injector::{FaultInjector, ResilienceMetrics};
 use sublinear_bft_scifest::ml_predictor::AdaptiveBatchPredictor;
+use sublinear_bft_scifest::simulator::{SimConfig, Simulator};
 
 fn bench_geo_latency(c: &mut Criterion) {
     let matrix = GeoLatencyMatrix::new();
@@ -84,11 +92,42 @@ fn bench_combined_overhead(c: &mut Criterion) {
     group.finish();
 }
 
+fn bench_simulated_consensus(c: &mut Criterion) {
+    let regions = vec![Region::USEast, Region::EUWest, Region::APNortheast, Region::SAEast];
+    let mut rng = StdRng::seed_from_u64(0);
+    let bls = regions.iter().map(|_| {
+        let sk = BlsSecretKey::generate(&mut rng);
+        (sk.public_key(), sk.prove_possession(LOCALHOST_CHAIN_ID))
+    }).collect();
+    let ed = (0..regions.len()).map(|i| SigningKey::from_bytes(&[i as u8 + 1; 32]).verifying_key().to_bytes()).collect();
+    let committee = Arc::new(Committee::localhost(bls, ed, 10000).unwrap());
+
+    let mut group = c.benchmark_group("simulated_consensus");
+    group.sample_size(10);
+    group.bench_function("global_4_validators_100s", |b| {
+        b.iter(|| {
+            let config = SimConfig {
+                seed: 1,
+                regions: regions.clone(),
+                jitter: Duration::from_millis(20),
+                anchor_timeout: Duration::from_millis(500),
+                max_anchor_timeout: Duration::from_secs(8),
+                crashed: vec![],
+            };
+            let mut sim = Simulator::new(committee.clone(), config).unwrap();
+            sim.run_for(Duration::from_secs(100));
+            black_box(sim.nodes[0].state.dag.committed_round)
+        });
+    });
+    group.finish();
+}
+
 criterion_group!(
     benches,
     bench_geo_latency,
     bench_fault_injection,
     bench_ml_predictor,
-    bench_combined_overhead
+    bench_combined_overhead,
+    bench_simulated_consensus
 );
 criterion_main!(benches);
@@ -0,0 +1,112 @@
+// Transport Benchmark
+// Tests: TCP vs QUIC on loopback (and in-process channels as a floor), a burst of vertices and votes from one validator to three peers
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use ed25519_dalek::SigningKey;
+use rand::rngs::OsRng;
+use rkyv::ser::serializers::AllocSerializer;
+use rkyv::ser::Serializer;
+use tokio::sync::mpsc;
+use sublinear_bft_scifest::bls_crypto::BlsSecretKey;
+use sublinear_bft_scifest::committee::{Committee, LOCALHOST_CHAIN_ID};
+use sublinear_bft_scifest::net::TcpNetwork;
+use sublinear_bft_scifest::quic::QuicNetwork;
+use sublinear_bft_scifest::transport::{ChannelNetwork, Lane, Transport, TransportHandle};
+use sublinear_bft_scifest::types::{CoA, Event, Message, Vertex};
+
+const N: usize = 4;
+const BURSTS: [usize; 2] = [100, 1000];
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Tcp,
+    Quic,
+    Memory,
+}
+
+fn committee(port_offset: u16) -> Committee {
+    let bls = (0..N).map(|_| {
+        let sk = BlsSecretKey::generate(&mut OsRng);
+        (sk.public_key(), sk.prove_possession(LOCALHOST_CHAIN_ID))
+    }).collect();
+    let ed = (0..N).map(|i| SigningKey::from_bytes(&[i as u8 + 1; 32]).verifying_key().to_bytes()).collect();
+    Committee::localhost(bls, ed, port_offset).unwrap()
+}
+
+async fn start<T: Transport>(network: T) -> (Arc<dyn TransportHandle>, mpsc::Receiver<Event>) {
+    let (tx, rx) = mpsc::channel(1_000_000);
+    (network.start(tx).await, rx)
+}
+
+/// Every node of a localhost committee; returns the handles (kept alive) and each node's events.
+async fn cluster(kind: Kind, port_offset: u16) -> (Vec<Arc<dyn TransportHandle>>, Vec<mpsc::Receiver<Event>>) {
+    let committee = committee(port_offset);
+    let mut nodes = Vec::new();
+    let mut mesh = ChannelNetwork::mesh(&committee).into_iter();
+    for id in 0..N as u32 {
+        let identity = SigningKey::from_bytes(&[id as u8 + 1; 32]);
+        nodes.push(match kind {
+            Kind::Tcp => start(TcpNetwork::new(id, &committee, identity)).await,
+            Kind::Quic => start(QuicNetwork::new(id, &committee, identity)).await,
+            Kind::Memory => start(mesh.next().unwrap()).await,
+        });
+    }
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    nodes.into_iter().unzip()
+}
+
+fn serialize(msg: &Message) -> Vec<u8> {
+    let mut ser = AllocSerializer::<1024>::default();
+    ser.serialize_value(msg).unwrap();
+    ser.into_serializer().into_inner().to_vec()
+}
+
+/// Alternating vertices (with a full set of parents) and votes, as node 0 sends them.
+fn burst(len: usize) -> Vec<(Lane, Vec<u8>)> {
+    (0..len).map(|i| if i % 2 == 0 {
+        Message::Vertex(Vertex { epoch: 0, round: i as u64, author: 0, batch_hash: [1; 32], parents: vec![[2; 32]; N], reconfiguration: None })
+    } else {
+        Message::CoA(CoA { batch_hash: [3; 32], signatures: vec![(0, vec![4; 48])] })
+    }).map(|msg| (Lane::of(&msg), serialize(&msg))).collect()
+}
+
+/// Time until every peer has received the whole burst.
+async fn deliver(handles: &[Arc<dyn TransportHandle>], receivers: &mut [mpsc::Receiver<Event>], burst: &[(Lane, Vec<u8>)]) -> Duration {
+    let start = Instant::now();
+    for (lane, msg_bytes) in burst {
+        handles[0].broadcast(*lane, msg_bytes.clone()).await;
+    }
+    for rx in receivers[1..].iter_mut() {
+        for _ in 0..burst.len() {
+            rx.recv().await.unwrap();
+        }
+    }
+    start.elapsed()
+}
+
+fn bench_transports(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_multi_thread().worker_threads(4).enable_all().build().unwrap();
+    let mut group = c.benchmark_group("transport_loopback");
+    group.sample_size(20);
+    for (name, kind, port_offset) in [("tcp", Kind::Tcp, 22000), ("quic", Kind::Quic, 22010), ("memory", Kind::Memory, 0)] {
+        let (handles, mut receivers) = runtime.block_on(cluster(kind, port_offset));
+        for &len in &BURSTS {
+            let burst = burst(len);
+            group.bench_with_input(BenchmarkId::new(name, len), &len, |b, _| {
+                b.iter_custom(|iters| runtime.block_on(async {
+                    let mut total = Duration::ZERO;
+                    for _ in 0..iters {
+                        total += deliver(&handles, &mut receivers, &burst).await;
+                    }
+                    total
+                }))
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_transports);
+criterion_main!(benches);
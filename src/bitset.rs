@@ -0,0 +1,280 @@
+// Signer sets for aggregated certificates
+// Variable-length, sized to the committee, and compact on the wire
+
+use serde::{Deserialize, Serialize};
+use rkyv::{Archive, Deserialize as RkyvDeserialize, Serialize as RkyvSerialize};
+use crate::types::ValidatorId;
+
+/// Largest committee a signer set can describe.
+pub const MAX_SIGNERS: usize = 1 << 16;
+
+const DENSE: u8 = 0;
+const RUNS: u8 = 1;
+
+/// Set of validator ids out of a committee of `size` validators.
+///
+/// Kept in its wire encoding: a tag, the committee size, then either one bit per
+/// validator or the lengths of alternating absent/present runs, whichever is
+/// shorter. A near-full set takes a few bytes at any committee size, and any set
+/// at most 3 + n/8. `from_ids` always encodes a set the same way, so equal sets
+/// compare equal.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize, Archive, RkyvDeserialize, RkyvSerialize)]
+#[archive(check_bytes)]
+pub struct SignerBitmap {
+    encoded: Vec<u8>,
+}
+
+impl SignerBitmap {
+    /// None if an id is not below `size`.
+    pub fn from_ids(size: usize, ids: impl IntoIterator<Item = ValidatorId>) -> Option<Self> {
+        if size > MAX_SIGNERS {
+            return None;
+        }
+        let mut present = vec![false; size];
+        for id in ids {
+            *present.get_mut(id as usize)? = true;
+        }
+
+        let mut dense = vec![DENSE];
+        put_varint(&mut dense, size as u64);
+        let mut mask = vec![0u8; size.div_ceil(8)];
+        for i in (0..size).filter(|&i| present[i]) {
+            mask[i / 8] |= 1 << (i % 8);
+        }
+        dense.extend(mask);
+
+        // Runs start with an absent one (possibly empty); a trailing absent run is implied
+        let mut runs = vec![RUNS];
+        put_varint(&mut runs, size as u64);
+        let (mut current, mut length) = (false, 0u64);
+        for &p in &present {
+            if p != current {
+                put_varint(&mut runs, length);
+                current = p;
+                length = 0;
+            }
+            length += 1;
+        }
+        if current {
+            put_varint(&mut runs, length);
+        }
+
+        let encoded = if runs.len() < dense.len() { runs } else { dense };
+        Some(Self { encoded })
+    }
+
+    /// Walks the encoding once, without allocating, calling `members` with the
+    /// start and length of each run of members in ascending order. Returns the
+    /// committee size, or None if the encoding is malformed or is not the one
+    /// `from_ids` gives the set, so equal sets from the wire still compare
+    /// equal. Runs may already have been reported when None is returned.
+    fn scan(&self, mut members: impl FnMut(usize, usize)) -> Option<usize> {
+        let (&tag, mut rest) = self.encoded.split_first()?;
+        let size = get_varint(&mut rest)? as usize;
+        if size > MAX_SIGNERS {
+            return None;
+        }
+        let dense_len = 1 + varint_len(size as u64) + size.div_ceil(8);
+        match tag {
+            DENSE => {
+                if rest.len() != size.div_ceil(8) || (!size.is_multiple_of(8) && rest[size / 8] >> (size % 8) != 0) {
+                    return None;
+                }
+                // Length of the runs form, which must not have been shorter
+                let mut runs_len = 1 + varint_len(size as u64);
+                let (mut current, mut start) = (false, 0);
+                for i in 0..size {
+                    if (rest[i / 8] & (1 << (i % 8)) != 0) != current {
+                        runs_len += varint_len((i - start) as u64);
+                        if current {
+                            members(start, i - start);
+                        }
+                        current = !current;
+                        start = i;
+                    }
+                }
+                if current {
+                    runs_len += varint_len((size - start) as u64);
+                    members(start, size - start);
+                }
+                (runs_len >= dense_len).then_some(size)
+            }
+            RUNS => {
+                // Only the leading absent run may be empty, and the last run is present
+                let (mut current, mut at, mut count) = (false, 0, 0);
+                while !rest.is_empty() {
+                    let length = get_varint(&mut rest)? as usize;
+                    if length > size - at || (length == 0 && count > 0) {
+                        return None;
+                    }
+                    if current {
+                        members(at, length);
+                    }
+                    at += length;
+                    current = !current;
+                    count += 1;
+                }
+                (count % 2 == 0 && self.encoded.len() < dense_len).then_some(size)
+            }
+            _ => None,
+        }
+    }
+
+    /// Size of the committee the set is drawn from, or None if malformed.
+    pub fn size(&self) -> Option<usize> {
+        self.scan(|_, _| {})
+    }
+
+    /// Members in ascending order; empty if malformed.
+    pub fn ids(&self) -> Vec<ValidatorId> {
+        let mut ids = Vec::new();
+        match self.scan(|start, length| ids.extend((start..start + length).map(|i| i as ValidatorId))) {
+            Some(_) => ids,
+            None => Vec::new(),
+        }
+    }
+
+    pub fn contains(&self, id: ValidatorId) -> bool {
+        let id = id as usize;
+        let mut found = false;
+        self.scan(|start, length| found |= (start..start + length).contains(&id)).is_some() && found
+    }
+
+    pub fn len(&self) -> usize {
+        let mut len = 0;
+        self.scan(|_, length| len += length).map_or(0, |_| len)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Bytes the set takes on the wire, before rkyv framing.
+    pub fn encoded_len(&self) -> usize {
+        self.encoded.len()
+    }
+}
+
+/// LEB128.
+fn put_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn varint_len(value: u64) -> usize {
+    (64 - value.leading_zeros() as usize).max(1).div_ceil(7)
+}
+
+/// None if truncated or not the shortest encoding of the value.
+fn get_varint(input: &mut &[u8]) -> Option<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = input.split_first()?;
+        *input = rest;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return (byte != 0 || shift == 0).then_some(value);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip_at_any_size() {
+        for size in [1, 4, 63, 64, 65, 256, 1000] {
+            let sets: [Vec<ValidatorId>; 4] = [
+                vec![],
+                (0..size as ValidatorId).collect(),
+                (0..size as ValidatorId).step_by(3).collect(),
+                (0..size as ValidatorId).filter(|&i| i != size as ValidatorId / 2).collect(),
+            ];
+            for ids in sets {
+                let bitmap = SignerBitmap::from_ids(size, ids.iter().copied()).unwrap();
+                assert_eq!(bitmap.size(), Some(size));
+                assert_eq!(bitmap.ids(), ids);
+                assert!(bitmap.encoded_len() <= 3 + size.div_ceil(8));
+            }
+        }
+    }
+
+    #[test]
+    fn test_near_full_sets_stay_small() {
+        let full = SignerBitmap::from_ids(1000, 0..1000).unwrap();
+        let missing_two = SignerBitmap::from_ids(1000, (0..1000).filter(|&i| i != 17 && i != 600)).unwrap();
+        assert!(full.encoded_len() <= 6);
+        assert!(missing_two.encoded_len() <= 12);
+        assert!(!missing_two.contains(600) && missing_two.contains(999));
+        assert_eq!(missing_two.len(), 998);
+    }
+
+    #[test]
+    fn test_equal_sets_encode_equally() {
+        let a = SignerBitmap::from_ids(100, [70, 3, 99]).unwrap();
+        let b = SignerBitmap::from_ids(100, [3, 99, 70, 3]).unwrap();
+        assert_eq!(a, b);
+        assert_ne!(a, SignerBitmap::from_ids(101, [3, 70, 99]).unwrap());
+    }
+
+    #[test]
+    fn test_queries_agree_with_the_encoding() {
+        for size in [0usize, 1, 7, 9, 12] {
+            for mask in 0u32..1 << size {
+                let ids: Vec<ValidatorId> = (0..size as ValidatorId).filter(|&i| mask & (1 << i) != 0).collect();
+                let bitmap = SignerBitmap::from_ids(size, ids.iter().copied()).unwrap();
+                assert_eq!(bitmap.len(), ids.len());
+                assert!((0..size as ValidatorId + 2).all(|i| bitmap.contains(i) == ids.contains(&i)));
+            }
+        }
+        // Whatever is accepted from the wire is the encoding `from_ids` gives it
+        let bytes = [0u8, 1, 2, 3, 5, 0x0f, 0x80, 0xff];
+        for tag in [DENSE, RUNS] {
+            for size in 0..12u8 {
+                for a in bytes {
+                    for b in bytes {
+                        for rest in [vec![], vec![a], vec![a, b]] {
+                            let bitmap = SignerBitmap { encoded: [vec![tag, size], rest].concat() };
+                            if let Some(n) = bitmap.size() {
+                                assert_eq!(SignerBitmap::from_ids(n, bitmap.ids()), Some(bitmap));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_out_of_range_and_malformed() {
+        assert!(SignerBitmap::from_ids(4, [4]).is_none());
+        let non_canonical = [
+            // Empty runs in the middle, an explicit trailing absent run, padding bits set
+            vec![RUNS, 4, 0, 2, 0, 1],
+            vec![RUNS, 4, 0, 3],
+            vec![RUNS, 4, 0, 3, 1],
+            vec![RUNS, 4, 1, 3, 0],
+            vec![DENSE, 4, 0xf3],
+            // Runs where the dense form is shorter, and dense where runs are
+            vec![RUNS, 9, 0, 1, 1, 1, 1, 1, 1, 1, 1, 1],
+            [vec![DENSE, 64], vec![0xff; 8]].concat(),
+            // Overlong varint for the size
+            vec![RUNS, 0x84, 0x00, 0, 4],
+        ];
+        for encoded in non_canonical {
+            let bitmap = SignerBitmap { encoded };
+            assert_eq!(bitmap.size(), None, "{:?}", bitmap.encoded);
+            assert!(bitmap.ids().is_empty());
+        }
+        for encoded in [vec![], vec![7, 4], vec![DENSE, 9, 0xff], vec![RUNS, 4, 3, 2], vec![RUNS, 0x80]] {
+            let bitmap = SignerBitmap { encoded };
+            assert_eq!(bitmap.size(), None);
+            assert!(bitmap.ids().is_empty());
+        }
+    }
+}
@@ -2,8 +2,10 @@
 use blst::min_pk as blst_core;
 use blst::blst_scalar;
 use std::time::Instant;
-use std::collections::HashMap;
-use crate::types::{BlsSignature, BlsPublicKey, ValidatorId, SignerBitmap};
+use std::collections::{HashMap, HashSet};
+use crate::types::{BlsSignature, BlsPublicKey, Hash, ValidatorId, SignerBitmap};
+use crate::committee::Committee;
+use crate::crypto::{SigningContext, SigningIntent, SigningMessage};
 use once_cell::sync::Lazy;
 use parking_lot::Mutex;
 
@@ -21,14 +23,34 @@ pub struct BlsAggregateMetrics {
     pub signature_count: usize,
 }
 
-/// Global cache for uncompressed public keys to avoid redundant ECC ops
-static PK_CACHE: Lazy<Mutex<HashMap<BlsPublicKey, blst_core::PublicKey>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+/// Domain separation tag for proofs of possession, distinct from the signing tag
+/// so no signature on a message can double as a proof.
+const POP_DST: &[u8] = b"BLS_POP_BLS12381G1_XMD:SHA-256_SSWU_RO_POP_";
+
+/// Uncompressed public keys whose proof of possession has been checked, with
+/// the chain and proof it was checked for. Keys only enter through
+/// `verify_proof_of_possession`, so aggregation (and `AGG_PK_CACHE`, built
+/// from these) never sees a rogue key.
+static PK_CACHE: Lazy<Mutex<HashMap<BlsPublicKey, ProvenKey>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+struct ProvenKey {
+    key: blst_core::PublicKey,
+    chain_id: String,
+    proof: BlsSignature,
+}
 
 /// Global cache for uncompressed signatures
 static SIG_CACHE: Lazy<Mutex<HashMap<BlsSignature, blst_core::Signature>>> = Lazy::new(|| Mutex::new(HashMap::new()));
 
-/// Global cache for aggregate public keys by bitmap
-static AGG_PK_CACHE: Lazy<Mutex<HashMap<SignerBitmap, blst_core::PublicKey>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+/// Global cache for aggregate public keys by (committee fingerprint, bitmap).
+/// A bitmap names different keys in different committees, so entries are
+/// scoped to the committee (and so the epoch) they were built from.
+static AGG_PK_CACHE: Lazy<Mutex<HashMap<(Hash, SignerBitmap), blst_core::PublicKey>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Drop the cached aggregate keys of a committee whose epoch has ended.
+pub fn retire_committee(committee: &Committee) {
+    AGG_PK_CACHE.lock().retain(|(fingerprint, _), _| fingerprint != committee.fingerprint());
+}
 
 /// BLS12-381 secret key using blst
 #[derive(Clone)]
@@ -44,9 +66,9 @@ impl BlsSecretKey {
         Self { sk }
     }
 
-    pub fn sign(&self, msg: &[u8]) -> BlsSignature {
+    pub fn sign(&self, msg: &SigningMessage) -> BlsSignature {
         let dst = b"BLS_SIG_BLS12381G1_XMD:SHA-256_SSWU_RO_NUL_";
-        let sig = self.sk.sign(msg, dst, &[]);
+        let sig = self.sk.sign(msg.as_bytes(), dst, &[]);
         sig.compress().to_vec()
     }
 
@@ -54,26 +76,43 @@ impl BlsSecretKey {
         let pk = self.sk.sk_to_pk();
         pk.compress().to_vec()
     }
+
+    /// Signature over our own public key under `POP_DST`, for `chain_id`;
+    /// published with the key.
+    pub fn prove_possession(&self, chain_id: &str) -> BlsSignature {
+        let msg = pop_message(chain_id, &self.public_key());
+        self.sk.sign(msg.as_bytes(), POP_DST, &[]).compress().to_vec()
+    }
+
+    pub fn to_bytes(&self) -> [u8; 32] {
+        self.sk.to_bytes()
+    }
+
+    /// None unless `bytes` is a valid big-endian scalar.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        blst_core::SecretKey::from_bytes(bytes).ok().map(|sk| Self { sk })
+    }
 }
 
 pub fn aggregate_signatures_with_metrics(
     signatures: &[(ValidatorId, BlsSignature)],
-    expected_quorum: usize,
+    committee: &Committee,
+    quorum_stake: u64,
 ) -> Result<(BlsSignature, SignerBitmap, BlsAggregateMetrics), &'static str> {
     let start = Instant::now();
     
-    if signatures.len() < expected_quorum {
-        return Err("Insufficient signatures");
+    if committee.stake_of(signatures.iter().map(|(id, _)| *id)) < quorum_stake {
+        return Err("Insufficient stake");
     }
 
-    let mut bitmap: SignerBitmap = 0;
+    let mut signers = HashSet::new();
     let mut decoded_sigs = Vec::new();
 
     {
         let mut sig_cache = SIG_CACHE.lock();
         for (id, sig_bytes) in signatures {
-            if *id >= 64 { return Err("ID out of range"); }
-            if (bitmap & (1u64 << id)) != 0 { return Err("Duplicate signer"); }
+            if *id as usize >= committee.size() { return Err("ID out of range"); }
+            if !signers.insert(*id) { return Err("Duplicate signer"); }
             
             let sig = if let Some(s) = sig_cache.get(sig_bytes) {
                 s.clone()
@@ -85,9 +124,9 @@ pub fn aggregate_signatures_with_metrics(
             };
             
             decoded_sigs.push(sig);
-            bitmap |= 1u64 << id;
         }
     }
+    let bitmap = SignerBitmap::from_ids(committee.size(), signers).ok_or("ID out of range")?;
     
     let sig_refs: Vec<&blst_core::Signature> = decoded_sigs.iter().collect();
     let agg = blst_core::AggregateSignature::aggregate(&sig_refs, true)
@@ -114,18 +153,18 @@ fn generate_random_scalars(count: usize) -> Vec<blst_scalar> {
 }
 
 pub fn verify_aggregated_with_metrics(
-    msg: &[u8],
+    msg: &SigningMessage,
     agg_sig: &BlsSignature,
-    public_keys: &[(ValidatorId, BlsPublicKey)],
-    bitmap: SignerBitmap,
-    expected_quorum: usize,
+    committee: &Committee,
+    bitmap: &SignerBitmap,
+    quorum_stake: u64,
 ) -> (bool, BlsVerifyMetrics) {
-    let items = vec![(msg, agg_sig, public_keys, bitmap, expected_quorum)];
+    let items = vec![(msg, agg_sig, committee, bitmap, quorum_stake)];
     verify_aggregated_batch_with_metrics(items)
 }
 
 pub fn verify_aggregated_batch_with_metrics(
-    items: Vec<(&[u8], &BlsSignature, &[(ValidatorId, BlsPublicKey)], SignerBitmap, usize)>,
+    items: Vec<(&SigningMessage, &BlsSignature, &Committee, &SignerBitmap, u64)>,
 ) -> (bool, BlsVerifyMetrics) {
     let start = Instant::now();
     let dst = b"BLS_SIG_BLS12381G1_XMD:SHA-256_SSWU_RO_NUL_";
@@ -138,7 +177,11 @@ pub fn verify_aggregated_batch_with_metrics(
     let mut all_sigs = Vec::with_capacity(items.len());
     let mut all_pks = Vec::with_capacity(items.len());
 
-    for (msg, agg_sig_bytes, pks_list, bitmap, expected_quorum) in &items {
+    for (msg, agg_sig_bytes, committee, bitmap, quorum_stake) in &items {
+        // Checked before the caches, so a cached aggregate key cannot skip it
+        if bitmap.size() != Some(committee.size()) || committee.bitmap_stake(bitmap) < *quorum_stake {
+            return (false, BlsVerifyMetrics { verify_micros: 0, pairing_count: 0 });
+        }
         let sig = {
             let mut sig_cache = SIG_CACHE.lock();
             if let Some(s) = sig_cache.get(*agg_sig_bytes) {
@@ -156,45 +199,33 @@ pub fn verify_aggregated_batch_with_metrics(
         // Use cache for aggregate public key
         let agg_pk: blst_core::PublicKey = {
             let mut cache = AGG_PK_CACHE.lock();
-            if let Some(pk) = cache.get(bitmap) {
+            let key = (*committee.fingerprint(), (*bitmap).clone());
+            if let Some(pk) = cache.get(&key) {
                 pk.clone()
             } else {
                 let mut group_pks = Vec::new();
-                let mut pk_cache = PK_CACHE.lock();
-                let mut count = 0;
+                let pk_cache = PK_CACHE.lock();
                 
-                for (id, pk_bytes) in *pks_list {
-                    if (bitmap & (1u64 << id)) != 0 {
-                        let pk: blst_core::PublicKey = if let Some(p) = pk_cache.get(pk_bytes) {
-                            p.clone()
-                        } else {
-                            let p = match blst_core::PublicKey::uncompress(pk_bytes) {
-                                Ok(p) => p,
-                                Err(_) => return (false, BlsVerifyMetrics { verify_micros: 0, pairing_count: 0 }),
-                            };
-                            pk_cache.insert(pk_bytes.clone(), p.clone());
-                            p
-                        };
-                        group_pks.push(pk);
-                        count += 1;
+                for id in bitmap.ids() {
+                    // A key without a checked proof of possession is never aggregated
+                    let pk_bytes = &committee.validators[id as usize].bls_public_key;
+                    match pk_cache.get(pk_bytes) {
+                        Some(proven) => group_pks.push(proven.key),
+                        None => return (false, BlsVerifyMetrics { verify_micros: 0, pairing_count: 0 }),
                     }
                 }
                 
-                if count < *expected_quorum {
-                    return (false, BlsVerifyMetrics { verify_micros: 0, pairing_count: 0 });
-                }
-                
                 let group_pk_refs: Vec<&blst_core::PublicKey> = group_pks.iter().collect();
                 let agg_pk_res = match blst_core::AggregatePublicKey::aggregate(&group_pk_refs, true) {
                     Ok(a) => a.to_public_key(),
                     Err(_) => return (false, BlsVerifyMetrics { verify_micros: 0, pairing_count: 0 }),
                 };
-                cache.insert(*bitmap, agg_pk_res.clone());
+                cache.insert(key, agg_pk_res.clone());
                 agg_pk_res
             }
         };
         
-        all_msgs.push(*msg);
+        all_msgs.push(msg.as_bytes());
         all_sigs.push(sig);
         all_pks.push(agg_pk);
     }
@@ -222,28 +253,87 @@ pub fn verify_aggregated_batch_with_metrics(
 }
 
 pub fn verify_aggregated(
-    msg: &[u8],
+    msg: &SigningMessage,
     agg_sig: &BlsSignature,
-    public_keys: &[(ValidatorId, BlsPublicKey)],
-    bitmap: SignerBitmap,
-    expected_quorum: usize,
+    committee: &Committee,
+    bitmap: &SignerBitmap,
+    quorum_stake: u64,
 ) -> bool {
-    verify_aggregated_with_metrics(msg, agg_sig, public_keys, bitmap, expected_quorum).0
+    verify_aggregated_with_metrics(msg, agg_sig, committee, bitmap, quorum_stake).0
+}
+
+/// A key is proven once per chain, so the proof is made in the genesis epoch
+/// whenever the key joins.
+fn pop_message(chain_id: &str, pk: &BlsPublicKey) -> SigningMessage {
+    SigningContext { chain_id: chain_id.to_string(), epoch: 0 }.message(&SigningIntent::ProofOfPossession(pk.clone()))
+}
+
+/// True if `pop` proves possession of the secret key behind `pk` on
+/// `chain_id`; `pk` must also be a valid key (in the subgroup, not the
+/// identity). Admits the key to `PK_CACHE`. The answer depends only on the
+/// inputs, never on what was checked before, so every node judges a committee
+/// the same way.
+pub fn verify_proof_of_possession(chain_id: &str, pk: &BlsPublicKey, pop: &BlsSignature) -> bool {
+    if PK_CACHE.lock().get(pk).is_some_and(|proven| proven.chain_id == chain_id && proven.proof == *pop) {
+        return true;
+    }
+    let (Ok(p), Ok(sig)) = (blst_core::PublicKey::uncompress(pk), blst_core::Signature::uncompress(pop)) else { return false };
+    if sig.verify(true, pop_message(chain_id, pk).as_bytes(), POP_DST, &[], &p, true) != blst::BLST_ERROR::BLST_SUCCESS {
+        return false;
+    }
+    PK_CACHE.lock().insert(pk.clone(), ProvenKey { key: p, chain_id: chain_id.to_string(), proof: pop.clone() });
+    true
+}
+
+/// Verify a single (non-aggregated) signature. Goes around `AGG_PK_CACHE`,
+/// which is keyed by signer set. Unproven keys are decoded but not cached.
+pub fn verify_signature(msg: &SigningMessage, sig: &BlsSignature, public_key: &BlsPublicKey) -> bool {
+    let dst = b"BLS_SIG_BLS12381G1_XMD:SHA-256_SSWU_RO_NUL_";
+    let cached = PK_CACHE.lock().get(public_key).map(|proven| proven.key);
+    let pk = match cached {
+        Some(p) => p,
+        None => {
+            let Ok(p) = blst_core::PublicKey::uncompress(public_key) else { return false };
+            p
+        }
+    };
+    let Ok(sig) = blst_core::Signature::uncompress(sig) else { return false };
+    sig.verify(true, msg.as_bytes(), dst, &[], &pk, true) == blst::BLST_ERROR::BLST_SUCCESS
+}
+
+/// Fixed committee keys so tests in different modules agree on signatures.
+#[cfg(test)]
+pub fn test_keys(n: usize) -> Vec<BlsSecretKey> {
+    use rand::SeedableRng;
+    let mut rng = rand::rngs::StdRng::seed_from_u64(7);
+    (0..n).map(|_| BlsSecretKey::generate(&mut rng)).collect()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use rand::rngs::OsRng;
+    use crate::committee::LOCALHOST_CHAIN_ID;
+
+    fn local_committee(keys: &[BlsSecretKey]) -> Committee {
+        let bls = keys.iter().map(|sk| (sk.public_key(), sk.prove_possession(LOCALHOST_CHAIN_ID))).collect();
+        let ed = (0..keys.len()).map(|i| ed25519_dalek::SigningKey::from_bytes(&[i as u8 + 1; 32]).verifying_key().to_bytes()).collect();
+        Committee::localhost(bls, ed, 10000).unwrap()
+    }
+
+    /// Vote for the vertex with hash [tag; 32] in `committee`'s epoch.
+    fn vote(committee: &Committee, tag: u8) -> SigningMessage {
+        committee.signing_context().message(&SigningIntent::VertexCertificate([tag; 32]))
+    }
 
     #[test]
     fn test_blst_basic() {
         let mut rng = OsRng;
         let sk = BlsSecretKey::generate(&mut rng);
-        let pk = sk.public_key();
-        let msg = b"hello blst";
-        let sig = sk.sign(msg);
-        let (valid, _) = verify_aggregated_with_metrics(msg, &sig, &[(0, pk)], 1, 1);
+        let committee = local_committee(std::slice::from_ref(&sk));
+        let msg = vote(&committee, 1);
+        let sig = sk.sign(&msg);
+        let (valid, _) = verify_aggregated_with_metrics(&msg, &sig, &committee, &SignerBitmap::from_ids(1, [0]).unwrap(), 1);
         assert!(valid);
     }
 
@@ -252,12 +342,89 @@ mod tests {
         let mut rng = OsRng;
         let sk1 = BlsSecretKey::generate(&mut rng);
         let sk2 = BlsSecretKey::generate(&mut rng);
-        let msg = b"consensus message";
-        let sig1 = sk1.sign(msg);
-        let sig2 = sk2.sign(msg);
-        let (agg, bitmap, _) = aggregate_signatures_with_metrics(&[(0, sig1), (1, sig2)], 2).unwrap();
-        let pks = vec![(0, sk1.public_key()), (1, sk2.public_key())];
-        let (valid, _) = verify_aggregated_with_metrics(msg, &agg, &pks, bitmap, 2);
+        let committee = local_committee(&[sk1.clone(), sk2.clone()]);
+        let msg = vote(&committee, 2);
+        let sig1 = sk1.sign(&msg);
+        let sig2 = sk2.sign(&msg);
+        let (agg, bitmap, _) = aggregate_signatures_with_metrics(&[(0, sig1), (1, sig2)], &committee, 2).unwrap();
+        let (valid, _) = verify_aggregated_with_metrics(&msg, &agg, &committee, &bitmap, 2);
         assert!(valid);
     }
+
+    #[test]
+    fn test_committee_beyond_64_validators() {
+        let keys: Vec<_> = (0..100).map(|_| BlsSecretKey::generate(&mut OsRng)).collect();
+        let committee = local_committee(&keys);
+        let msg = vote(&committee, 3);
+        let quorum = committee.quorum_threshold();
+        let sigs: Vec<_> = (33..100).map(|id| (id as ValidatorId, keys[id].sign(&msg))).collect();
+        let (agg, bitmap, _) = aggregate_signatures_with_metrics(&sigs, &committee, quorum).unwrap();
+        assert_eq!(bitmap.len(), 67);
+        assert!(bitmap.encoded_len() < 8);
+        assert!(verify_aggregated(&msg, &agg, &committee, &bitmap, quorum));
+
+        // A set sized for another committee is refused
+        let resized = SignerBitmap::from_ids(128, 33..100).unwrap();
+        assert!(!verify_aggregated(&msg, &agg, &committee, &resized, quorum));
+        assert!(aggregate_signatures_with_metrics(&[(100, keys[0].sign(&msg))], &committee, 0).is_err());
+    }
+
+    #[test]
+    fn test_aggregate_key_cache_is_scoped_to_committee() {
+        let mut committees = Vec::new();
+        for keys in [test_keys(3), (0..3).map(|_| BlsSecretKey::generate(&mut OsRng)).collect()] {
+            let committee = local_committee(&keys);
+            let msg = vote(&committee, 4);
+            let sigs: Vec<_> = (0..2).map(|id| (id as ValidatorId, keys[id].sign(&msg))).collect();
+            let (agg, bitmap, _) = aggregate_signatures_with_metrics(&sigs, &committee, 2).unwrap();
+            assert_eq!(bitmap, SignerBitmap::from_ids(3, [0, 1]).unwrap());
+            assert!(verify_aggregated(&msg, &agg, &committee, &bitmap, 2));
+            committees.push(committee);
+        }
+        for committee in &committees {
+            retire_committee(committee);
+            assert!(!AGG_PK_CACHE.lock().keys().any(|(f, _)| f == committee.fingerprint()));
+        }
+    }
+
+    #[test]
+    fn test_keys_need_proof_of_possession() {
+        let keys: Vec<_> = (0..2).map(|_| BlsSecretKey::generate(&mut OsRng)).collect();
+        let (pk, pop) = (keys[0].public_key(), keys[0].prove_possession(LOCALHOST_CHAIN_ID));
+        assert!(verify_proof_of_possession(LOCALHOST_CHAIN_ID, &pk, &pop));
+        assert!(!verify_proof_of_possession("another-chain", &pk, &pop));
+
+        // Another key's proof, or an ordinary signature over the proof message, proves nothing
+        let unproven = keys[1].public_key();
+        assert!(!verify_proof_of_possession(LOCALHOST_CHAIN_ID, &unproven, &pop));
+        let signed = keys[1].sign(&pop_message(LOCALHOST_CHAIN_ID, &unproven));
+        assert!(!verify_proof_of_possession(LOCALHOST_CHAIN_ID, &unproven, &signed));
+        assert!(!PK_CACHE.lock().contains_key(&unproven));
+
+        // A committee edited to hold an unproven key is refused aggregation
+        let mut committee = local_committee(&keys[..1]);
+        committee.validators[0].bls_public_key = unproven;
+        let msg = vote(&committee, 5);
+        let bitmap = SignerBitmap::from_ids(1, [0]).unwrap();
+        assert!(!verify_aggregated(&msg, &keys[1].sign(&msg), &committee, &bitmap, 1));
+        assert!(!AGG_PK_CACHE.lock().keys().any(|(f, _)| f == committee.fingerprint()));
+    }
+
+    #[test]
+    fn test_signatures_are_bound_to_their_context() {
+        let sk = BlsSecretKey::generate(&mut OsRng);
+        let pk = sk.public_key();
+        let context = SigningContext { chain_id: LOCALHOST_CHAIN_ID.into(), epoch: 3 };
+        let intent = SigningIntent::SkipVote { round: 7, anchor: 2 };
+        let sig = sk.sign(&context.message(&intent));
+        assert!(verify_signature(&context.message(&intent), &sig, &pk));
+
+        let other_epoch = SigningContext { epoch: 4, ..context.clone() };
+        let other_chain = SigningContext { chain_id: "another-chain".into(), ..context.clone() };
+        assert!(!verify_signature(&other_epoch.message(&intent), &sig, &pk));
+        assert!(!verify_signature(&other_chain.message(&intent), &sig, &pk));
+        for other in [SigningIntent::SkipVote { round: 7, anchor: 3 }, SigningIntent::Checkpoint { round: 7, digest: [2; 32] }] {
+            assert!(!verify_signature(&context.message(&other), &sig, &pk));
+        }
+    }
 }
@@ -0,0 +1,293 @@
+// Common coin for anchor election: a threshold BLS signature per anchor round
+// Dealt once per committee; shares travel encrypted to each validator's BLS key
+
+use blst::min_pk as blst_core;
+use blst::{blst_fr, blst_scalar, MultiPoint};
+use chacha20poly1305::aead::Aead;
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit, Nonce};
+use serde::{Deserialize, Serialize};
+use rkyv::{Archive, Deserialize as RkyvDeserialize, Serialize as RkyvSerialize};
+use crate::bls_crypto::BlsSecretKey;
+use crate::committee::{hex_bytes, hex_list};
+use crate::crypto::SigningMessage;
+use crate::types::{BlsPublicKey, BlsSignature, ValidatorId};
+
+/// Key derivation context for share encryption keys.
+const SHARE_KEY_CONTEXT: &str = "sublyne 2024 coin share encryption";
+
+/// Shares that reveal a coin in a committee of `n`: one more than the f < n/3
+/// validators that may be faulty, so they alone never can. Counted in
+/// validators rather than stake, since every validator holds one share.
+pub fn coin_threshold(n: usize) -> usize {
+    n.saturating_sub(1) / 3 + 1
+}
+
+/// Threshold key of a committee's coin, as dealt by whoever built the committee:
+/// a random polynomial f of degree `threshold - 1`, with f(0) the coin's secret
+/// key and validator i holding f(i + 1). Any `threshold` shares sign like the
+/// secret key, and BLS signatures are unique, so every node that combines
+/// shares gets the same coin. The dealer knows every coin in advance, so it
+/// is trusted with their unpredictability, though not with safety.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, Archive, RkyvDeserialize, RkyvSerialize)]
+#[archive(check_bytes)]
+pub struct CoinDealing {
+    /// Commitments to the coefficients of f, lowest first; the first is the
+    /// coin's public key.
+    #[serde(with = "hex_list")]
+    pub commitments: Vec<BlsPublicKey>,
+    /// Validator i's share, encrypted to its BLS public key.
+    pub shares: Vec<EncryptedShare>,
+}
+
+/// Hashed ElGamal to a BLS public key: an ephemeral key, and the share under a
+/// key derived from their Diffie-Hellman point.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, Archive, RkyvDeserialize, RkyvSerialize)]
+#[archive(check_bytes)]
+pub struct EncryptedShare {
+    #[serde(with = "hex_bytes")]
+    pub ephemeral: BlsPublicKey,
+    #[serde(with = "hex_bytes")]
+    pub ciphertext: Vec<u8>,
+}
+
+impl CoinDealing {
+    /// Deal a coin to validators with `recipients` as BLS keys, in id order.
+    pub fn deal<R: rand::RngCore>(rng: &mut R, recipients: &[BlsPublicKey]) -> Result<Self, String> {
+        let coefficients: Vec<BlsSecretKey> = (0..coin_threshold(recipients.len())).map(|_| BlsSecretKey::generate(rng)).collect();
+        let commitments = coefficients.iter().map(BlsSecretKey::public_key).collect();
+        let coefficients: Vec<Scalar> = coefficients.iter().map(Scalar::from_key).collect();
+        let shares = recipients.iter().enumerate().map(|(i, recipient)| {
+            // Horner's rule at x = i + 1
+            let x = share_point(i as ValidatorId);
+            let share = coefficients.iter().rev().fold(Scalar::from_u64(0), |acc, c| acc.mul(x).add(*c));
+            encrypt(rng, recipient, &share.to_key().ok_or("dealt a zero share")?)
+        }).collect::<Result<_, String>>()?;
+        Ok(Self { commitments, shares })
+    }
+
+    /// Shares needed to reveal a coin.
+    pub fn threshold(&self) -> usize {
+        self.commitments.len()
+    }
+
+    /// The dealing fits a committee of `n` and every point in it is valid.
+    /// Whether a share matches the commitments only its holder can check.
+    pub fn validate(&self, n: usize) -> Result<(), String> {
+        if self.threshold() != coin_threshold(n) || self.shares.len() != n {
+            return Err(format!("coin dealing is for {} shares of {}, the committee needs {} of {}",
+                self.threshold(), self.shares.len(), coin_threshold(n), n));
+        }
+        let points = self.commitments.iter().chain(self.shares.iter().map(|s| &s.ephemeral));
+        if !points.into_iter().all(|p| decode_key(p).is_some()) {
+            return Err("coin dealing holds an invalid point".into());
+        }
+        Ok(())
+    }
+
+    /// Public key of validator `id`'s share: the commitments evaluated at id + 1.
+    pub fn share_public_key(&self, id: ValidatorId) -> Option<BlsPublicKey> {
+        if id as usize >= self.shares.len() {
+            return None;
+        }
+        let commitments: Vec<blst_core::PublicKey> = self.commitments.iter().map(|c| decode_key(c)).collect::<Option<_>>()?;
+        let x = share_point(id);
+        let mut power = Scalar::from_u64(1);
+        let mut scalars = Vec::with_capacity(32 * commitments.len());
+        for _ in &commitments {
+            scalars.extend(power.to_le_bytes());
+            power = power.mul(x);
+        }
+        Some(commitments.mult(&scalars, 255).to_public_key().compress().to_vec())
+    }
+
+    /// Validator `id`'s share, decrypted with its BLS key and checked against
+    /// the commitments.
+    pub fn decrypt_share(&self, id: ValidatorId, key: &BlsSecretKey) -> Result<BlsSecretKey, String> {
+        let encrypted = self.shares.get(id as usize).ok_or("no coin share for this validator")?;
+        let ephemeral = decode_key(&encrypted.ephemeral).ok_or("invalid ephemeral key")?;
+        let shared = [ephemeral].mult(&Scalar::from_key(key).to_le_bytes(), 255).to_public_key().compress();
+        let cipher = ChaCha20Poly1305::new(&share_key(&encrypted.ephemeral, &key.public_key(), &shared).into());
+        let plaintext = cipher.decrypt(Nonce::from_slice(&[0; 12]), encrypted.ciphertext.as_slice())
+            .map_err(|_| "coin share does not decrypt under this key")?;
+        let share = BlsSecretKey::from_bytes(&plaintext).ok_or("coin share is not a valid key")?;
+        if Some(share.public_key()) != self.share_public_key(id) {
+            return Err("coin share does not match the dealing".into());
+        }
+        Ok(share)
+    }
+
+    /// `sig` is validator `id`'s share of the coin on `msg`.
+    pub fn verify_share(&self, id: ValidatorId, msg: &SigningMessage, sig: &BlsSignature) -> bool {
+        self.share_public_key(id).is_some_and(|pk| crate::bls_crypto::verify_signature(msg, sig, &pk))
+    }
+
+    /// The coin on `msg`, from `threshold` verified shares by distinct
+    /// validators. Checked against the coin's public key before it is returned.
+    pub fn combine(&self, msg: &SigningMessage, shares: &[(ValidatorId, BlsSignature)]) -> Option<BlsSignature> {
+        let ids: Vec<ValidatorId> = shares.iter().map(|(id, _)| *id).collect();
+        if ids.len() != self.threshold() || ids.iter().any(|&id| id as usize >= self.shares.len())
+            || (1..ids.len()).any(|i| ids[..i].contains(&ids[i]))
+        {
+            return None;
+        }
+        let sigs: Vec<blst_core::Signature> = shares.iter().map(|(_, sig)| blst_core::Signature::uncompress(sig).ok()).collect::<Option<_>>()?;
+        // Lagrange coefficients at 0: prod over j != i of x_j / (x_j - x_i)
+        let mut scalars = Vec::with_capacity(32 * ids.len());
+        for &i in &ids {
+            let (mut num, mut den) = (Scalar::from_u64(1), Scalar::from_u64(1));
+            for &j in ids.iter().filter(|&&j| j != i) {
+                num = num.mul(share_point(j));
+                den = den.mul(share_point(j).sub(share_point(i)));
+            }
+            scalars.extend(num.mul(den.inverse()).to_le_bytes());
+        }
+        let coin = sigs.mult(&scalars, 255).to_signature().compress().to_vec();
+        crate::bls_crypto::verify_signature(msg, &coin, &self.commitments[0]).then_some(coin)
+    }
+}
+
+/// Where validator `id`'s share sits on the polynomial; never 0, the secret.
+fn share_point(id: ValidatorId) -> Scalar {
+    Scalar::from_u64(id as u64 + 1)
+}
+
+/// A public key in the group and not the identity.
+fn decode_key(bytes: &[u8]) -> Option<blst_core::PublicKey> {
+    blst_core::PublicKey::key_validate(bytes).ok()
+}
+
+fn share_key(ephemeral: &[u8], recipient: &[u8], shared: &[u8]) -> [u8; 32] {
+    blake3::derive_key(SHARE_KEY_CONTEXT, &[ephemeral, recipient, shared].concat())
+}
+
+/// Each share is encrypted under a fresh ephemeral key, so the key it derives
+/// is used once and a fixed nonce is safe.
+fn encrypt<R: rand::RngCore>(rng: &mut R, recipient: &BlsPublicKey, share: &BlsSecretKey) -> Result<EncryptedShare, String> {
+    let recipient_key = decode_key(recipient).ok_or("invalid recipient key")?;
+    let ephemeral = BlsSecretKey::generate(rng);
+    let shared = [recipient_key].mult(&Scalar::from_key(&ephemeral).to_le_bytes(), 255).to_public_key().compress();
+    let cipher = ChaCha20Poly1305::new(&share_key(&ephemeral.public_key(), recipient, &shared).into());
+    let ciphertext = cipher.encrypt(Nonce::from_slice(&[0; 12]), share.to_bytes().as_slice())
+        .map_err(|_| "coin share encryption failed")?;
+    Ok(EncryptedShare { ephemeral: ephemeral.public_key(), ciphertext })
+}
+
+/// Element of the BLS12-381 scalar field.
+#[derive(Clone, Copy)]
+struct Scalar(blst_fr);
+
+// blst's field functions read and write only the values behind their pointers,
+// which all live on this stack frame.
+impl Scalar {
+    fn from_u64(n: u64) -> Self {
+        let mut out = blst_fr::default();
+        unsafe { blst::blst_fr_from_uint64(&mut out, [n, 0, 0, 0].as_ptr()) };
+        Self(out)
+    }
+
+    fn from_key(key: &BlsSecretKey) -> Self {
+        let (mut scalar, mut out) = (blst_scalar::default(), blst_fr::default());
+        unsafe {
+            blst::blst_scalar_from_bendian(&mut scalar, key.to_bytes().as_ptr());
+            blst::blst_fr_from_scalar(&mut out, &scalar);
+        }
+        Self(out)
+    }
+
+    fn add(self, other: Self) -> Self {
+        let mut out = blst_fr::default();
+        unsafe { blst::blst_fr_add(&mut out, &self.0, &other.0) };
+        Self(out)
+    }
+
+    fn sub(self, other: Self) -> Self {
+        let mut out = blst_fr::default();
+        unsafe { blst::blst_fr_sub(&mut out, &self.0, &other.0) };
+        Self(out)
+    }
+
+    fn mul(self, other: Self) -> Self {
+        let mut out = blst_fr::default();
+        unsafe { blst::blst_fr_mul(&mut out, &self.0, &other.0) };
+        Self(out)
+    }
+
+    /// Zero maps to zero.
+    fn inverse(self) -> Self {
+        let mut out = blst_fr::default();
+        unsafe { blst::blst_fr_inverse(&mut out, &self.0) };
+        Self(out)
+    }
+
+    fn to_scalar(self) -> blst_scalar {
+        let mut out = blst_scalar::default();
+        unsafe { blst::blst_scalar_from_fr(&mut out, &self.0) };
+        out
+    }
+
+    /// Little-endian, as blst's multi-scalar multiplication takes them.
+    fn to_le_bytes(self) -> [u8; 32] {
+        self.to_scalar().b
+    }
+
+    /// None for zero, which is not a secret key.
+    fn to_key(self) -> Option<BlsSecretKey> {
+        let mut bytes = [0u8; 32];
+        unsafe { blst::blst_bendian_from_scalar(bytes.as_mut_ptr(), &self.to_scalar()) };
+        BlsSecretKey::from_bytes(&bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use crate::bls_crypto::test_keys;
+    use crate::committee::LOCALHOST_CHAIN_ID;
+    use crate::crypto::{SigningContext, SigningIntent};
+
+    fn coin_message(round: u64) -> SigningMessage {
+        SigningContext { chain_id: LOCALHOST_CHAIN_ID.into(), epoch: 0 }.message(&SigningIntent::AnchorCoin { round })
+    }
+
+    #[test]
+    fn test_any_threshold_of_shares_gives_the_same_coin() {
+        let keys = test_keys(7);
+        let dealing = CoinDealing::deal(&mut rand::rngs::StdRng::seed_from_u64(1), &keys.iter().map(|k| k.public_key()).collect::<Vec<_>>()).unwrap();
+        dealing.validate(7).unwrap();
+        assert_eq!(dealing.threshold(), 3);
+
+        let msg = coin_message(4);
+        let shares: Vec<(ValidatorId, BlsSignature)> = (0..7).map(|id| {
+            let share = dealing.decrypt_share(id, &keys[id as usize]).unwrap();
+            (id, share.sign(&msg))
+        }).collect();
+        assert!(shares.iter().all(|(id, sig)| dealing.verify_share(*id, &msg, sig)));
+        assert!(!dealing.verify_share(1, &msg, &shares[0].1));
+
+        let coin = dealing.combine(&msg, &shares[..3]).unwrap();
+        assert_eq!(dealing.combine(&msg, &[shares[6].clone(), shares[2].clone(), shares[4].clone()]), Some(coin.clone()));
+        // Too few, repeated, or another round's shares
+        assert!(dealing.combine(&msg, &shares[..2]).is_none());
+        assert!(dealing.combine(&msg, &[shares[0].clone(), shares[0].clone(), shares[1].clone()]).is_none());
+        let other = dealing.decrypt_share(3, &keys[3]).unwrap().sign(&coin_message(6));
+        assert!(dealing.combine(&msg, &[shares[0].clone(), shares[1].clone(), (3, other)]).is_none());
+    }
+
+    #[test]
+    fn test_shares_open_only_for_their_holder() {
+        let keys = test_keys(4);
+        let dealing = CoinDealing::deal(&mut rand::rngs::OsRng, &keys.iter().map(|k| k.public_key()).collect::<Vec<_>>()).unwrap();
+        assert!(dealing.decrypt_share(0, &keys[0]).is_ok());
+        assert!(dealing.decrypt_share(0, &keys[1]).is_err());
+        assert!(dealing.decrypt_share(4, &keys[0]).is_err());
+
+        let mut swapped = dealing.clone();
+        swapped.shares.swap(0, 1);
+        assert!(swapped.decrypt_share(0, &keys[0]).is_err());
+        assert!(dealing.validate(5).is_err());
+        let mut broken = dealing.clone();
+        broken.commitments[1][3] ^= 1;
+        assert!(broken.validate(4).is_err());
+    }
+}
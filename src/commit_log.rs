@@ -0,0 +1,268 @@
+// Commit stream: the node's ordered output for applications
+// Append-only log of CommittedSubDags, optionally file-backed, with resumable subscriptions
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+use parking_lot::Mutex;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::mpsc;
+use rkyv::ser::serializers::AllocSerializer;
+use rkyv::ser::Serializer;
+use rkyv::{check_archived_root, Deserialize};
+use crate::crypto::hash_vertex;
+use crate::types::CommittedSubDag;
+
+/// Every committed sub-DAG in commit order, with `entries[i].index == i`.
+/// Subscribers get each entry exactly once: a replay from the index they ask
+/// for, then live appends.
+///
+/// The log resumes subscribers, not consensus: a restarted node starts again
+/// at round 1 of its genesis committee and re-commits from there, and nodes
+/// cannot fetch a DAG their peers have moved past.
+pub struct CommitLog {
+    file: Option<File>,
+    entries: Vec<CommittedSubDag>,
+    subscribers: Vec<mpsc::UnboundedSender<CommittedSubDag>>,
+}
+
+impl CommitLog {
+    pub fn in_memory() -> Self {
+        Self { file: None, entries: Vec::new(), subscribers: Vec::new() }
+    }
+
+    /// Open (or create) a log file and load what it holds. A record cut short by
+    /// a crash mid-write is truncated away.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut file = OpenOptions::new().read(true).append(true).create(true).open(path)?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+
+        let mut entries = Vec::new();
+        let mut pos = 0;
+        while pos + 4 <= bytes.len() {
+            let len = u32::from_le_bytes(bytes[pos..pos + 4].try_into().unwrap()) as usize;
+            let Some(record) = bytes.get(pos + 4..pos + 4 + len) else { break };
+            let sub_dag = decode(record)?;
+            if sub_dag.index != entries.len() as u64 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "commit log out of sequence"));
+            }
+            entries.push(sub_dag);
+            pos += 4 + len;
+        }
+        if pos < bytes.len() {
+            file.set_len(pos as u64)?;
+        }
+        Ok(Self { file: Some(file), entries, subscribers: Vec::new() })
+    }
+
+    /// Index the next appended sub-DAG must carry.
+    pub fn next_index(&self) -> u64 {
+        self.entries.len() as u64
+    }
+
+    pub fn get(&self, index: u64) -> Option<&CommittedSubDag> {
+        self.entries.get(index as usize)
+    }
+
+    /// Append the next sub-DAG, persist it, then hand it to subscribers.
+    /// After a restart consensus re-commits from the start; entries the log
+    /// already holds are skipped (`Ok(false)`), but they must order the same
+    /// vertices. Their certificates may differ: each aggregates whichever votes
+    /// the node collected.
+    pub fn append(&mut self, sub_dag: CommittedSubDag) -> io::Result<bool> {
+        if let Some(existing) = self.get(sub_dag.index) {
+            if !same_commit(existing, &sub_dag) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "commit diverges from log"));
+            }
+            return Ok(false);
+        }
+        if sub_dag.index != self.next_index() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "commit index skips ahead of log"));
+        }
+        if let Some(file) = self.file.as_mut() {
+            file.write_all(&encode(&sub_dag))?;
+            file.sync_data()?;
+        }
+        self.subscribers.retain(|tx| tx.send(sub_dag.clone()).is_ok());
+        self.entries.push(sub_dag);
+        Ok(true)
+    }
+
+    /// Stream every sub-DAG from `from_index` on. A consumer that processed up to
+    /// index i resumes with `subscribe(i + 1)`.
+    pub fn subscribe(&mut self, from_index: u64) -> mpsc::UnboundedReceiver<CommittedSubDag> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        for sub_dag in self.entries.iter().skip(from_index as usize) {
+            let _ = tx.send(sub_dag.clone());
+        }
+        self.subscribers.push(tx);
+        rx
+    }
+}
+
+/// Stream `log` to every client that connects to `listener`, until accepting
+/// fails. A client sends the index to start from (u64, little endian) and
+/// then reads sub-DAGs, replayed and then live, as `CommitStream` does.
+pub async fn serve(listener: UnixListener, log: Arc<Mutex<CommitLog>>) -> io::Result<()> {
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        let log = log.clone();
+        tokio::spawn(async move {
+            let mut from_index = [0u8; 8];
+            if stream.read_exact(&mut from_index).await.is_err() {
+                return;
+            }
+            let mut rx = log.lock().subscribe(u64::from_le_bytes(from_index));
+            while let Some(sub_dag) = rx.recv().await {
+                if stream.write_all(&encode(&sub_dag)).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+/// A node's commit stream, read from the socket it serves its log on.
+pub struct CommitStream {
+    stream: UnixStream,
+}
+
+impl CommitStream {
+    /// Subscribe from `from_index`; a consumer that processed up to index i
+    /// resumes with i + 1.
+    pub async fn connect(path: impl AsRef<Path>, from_index: u64) -> io::Result<Self> {
+        let mut stream = UnixStream::connect(path).await?;
+        stream.write_all(&from_index.to_le_bytes()).await?;
+        Ok(Self { stream })
+    }
+
+    /// The next sub-DAG in commit order, once the node has committed it.
+    pub async fn next(&mut self) -> io::Result<CommittedSubDag> {
+        let mut len = [0u8; 4];
+        self.stream.read_exact(&mut len).await?;
+        let mut record = vec![0u8; u32::from_le_bytes(len) as usize];
+        self.stream.read_exact(&mut record).await?;
+        decode(&record)
+    }
+}
+
+/// A length-prefixed record, as stored in the log file and sent to subscribers.
+fn encode(sub_dag: &CommittedSubDag) -> Vec<u8> {
+    let mut ser = AllocSerializer::<4096>::default();
+    ser.serialize_value(sub_dag).unwrap();
+    let bytes = ser.into_serializer().into_inner();
+    let mut record = (bytes.len() as u32).to_le_bytes().to_vec();
+    record.extend_from_slice(&bytes);
+    record
+}
+
+fn decode(record: &[u8]) -> io::Result<CommittedSubDag> {
+    // Copy into an aligned buffer for rkyv
+    let mut aligned = rkyv::AlignedVec::with_capacity(record.len());
+    aligned.extend_from_slice(record);
+    let archived = check_archived_root::<CommittedSubDag>(&aligned)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "corrupt commit log record"))?;
+    Ok(archived.deserialize(&mut rkyv::Infallible).unwrap())
+}
+
+/// Same place in the total order: index, epoch, anchor, round and vertices.
+fn same_commit(a: &CommittedSubDag, b: &CommittedSubDag) -> bool {
+    (a.index, a.epoch, a.anchor, a.round) == (b.index, b.epoch, b.anchor, b.round)
+        && a.vertices.iter().map(hash_vertex).eq(b.vertices.iter().map(hash_vertex))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{AggregatedCoA, SignerBitmap, Vertex};
+
+    fn sub_dag(index: u64) -> CommittedSubDag {
+        let vertex = Vertex { epoch: 0, round: index + 1, author: 0, batch_hash: [index as u8; 32], parents: vec![], reconfiguration: None };
+        let cert = AggregatedCoA { epoch: 0, batch_hash: [index as u8; 32], aggregated_signature: vec![1; 48], signer_bitmap: SignerBitmap::from_ids(4, 0..3).unwrap() };
+        CommittedSubDag { index, epoch: 0, anchor: [index as u8; 32], round: index + 1, vertices: vec![vertex], certificates: vec![cert] }
+    }
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("sublyne-{}-{}.log", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[tokio::test]
+    async fn test_subscriber_gets_replay_then_live() {
+        let mut log = CommitLog::in_memory();
+        log.append(sub_dag(0)).unwrap();
+        log.append(sub_dag(1)).unwrap();
+        let mut rx = log.subscribe(1);
+        log.append(sub_dag(2)).unwrap();
+        assert_eq!(rx.recv().await.unwrap().index, 1);
+        assert_eq!(rx.recv().await.unwrap().index, 2);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_recommit_after_restart_is_deduplicated() {
+        let mut log = CommitLog::in_memory();
+        assert!(log.append(sub_dag(0)).unwrap());
+        let mut rx = log.subscribe(0);
+        rx.try_recv().unwrap();
+        assert!(!log.append(sub_dag(0)).unwrap());
+        assert!(rx.try_recv().is_err());
+
+        let mut diverging = sub_dag(0);
+        diverging.anchor = [9; 32];
+        assert!(log.append(diverging).is_err());
+        let mut reordered = sub_dag(0);
+        reordered.vertices[0].batch_hash = [9; 32];
+        assert!(log.append(reordered).is_err());
+        assert!(log.append(sub_dag(5)).is_err());
+    }
+
+    #[test]
+    fn test_recommit_with_other_certificates_is_deduplicated() {
+        let path = temp_path("recertified");
+        {
+            let mut log = CommitLog::open(&path).unwrap();
+            log.append(sub_dag(0)).unwrap();
+        }
+        // After a restart the same vertex is certified by another set of votes
+        let mut log = CommitLog::open(&path).unwrap();
+        let mut recommitted = sub_dag(0);
+        recommitted.certificates[0].aggregated_signature = vec![2; 48];
+        recommitted.certificates[0].signer_bitmap = SignerBitmap::from_ids(4, 1..4).unwrap();
+        assert!(!log.append(recommitted).unwrap());
+        assert_eq!(log.get(0), Some(&sub_dag(0)));
+        assert!(log.append(sub_dag(1)).unwrap());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_reopen_resumes_from_index() {
+        let path = temp_path("reopen");
+        {
+            let mut log = CommitLog::open(&path).unwrap();
+            for i in 0..3 {
+                log.append(sub_dag(i)).unwrap();
+            }
+        }
+        // Simulate a crash halfway through writing a record
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&100u32.to_le_bytes()).unwrap();
+        file.write_all(&[0u8; 10]).unwrap();
+        drop(file);
+
+        let mut log = CommitLog::open(&path).unwrap();
+        assert_eq!(log.next_index(), 3);
+        assert_eq!(log.get(2), Some(&sub_dag(2)));
+        let mut rx = log.subscribe(2);
+        assert_eq!(rx.try_recv().unwrap().index, 2);
+        log.append(sub_dag(3)).unwrap();
+        assert_eq!(rx.try_recv().unwrap().index, 3);
+        drop(log);
+        assert_eq!(CommitLog::open(&path).unwrap().next_index(), 4);
+        let _ = std::fs::remove_file(&path);
+    }
+}
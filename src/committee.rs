@@ -0,0 +1,405 @@
+// Committee: the validator set every other component is parameterised by
+// Loaded from JSON and checked for consistency before use
+
+use std::collections::HashSet;
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use rkyv::{Archive, Deserialize as RkyvDeserialize, Serialize as RkyvSerialize};
+use crate::types::{BlsPublicKey, BlsSignature, Hash, SignerBitmap, ValidatorId};
+use crate::bitset::MAX_SIGNERS;
+use crate::coin::CoinDealing;
+use crate::crypto::SigningContext;
+
+/// Chain of `Committee::localhost` testnets and of the tests.
+pub const LOCALHOST_CHAIN_ID: &str = "sublyne-localhost";
+
+/// Longest chain id, in bytes.
+pub const MAX_CHAIN_ID_LEN: usize = 64;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, Archive, RkyvDeserialize, RkyvSerialize)]
+#[archive(check_bytes)]
+pub struct ValidatorInfo {
+    pub id: ValidatorId,
+    #[serde(with = "hex_bytes")]
+    pub bls_public_key: BlsPublicKey,
+    /// `BlsSecretKey::prove_possession` of the key for the committee's chain;
+    /// without it the key could be chosen to cancel others out of an aggregate.
+    #[serde(with = "hex_bytes")]
+    pub bls_proof_of_possession: BlsSignature,
+    #[serde(with = "hex_bytes")]
+    pub ed25519_public_key: Vec<u8>,
+    /// host:port the validator listens on.
+    pub address: String,
+    pub voting_power: u64,
+}
+
+/// Validators indexed by id: `validators[i].id == i`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Committee {
+    /// Epoch this committee governs; the genesis committee is epoch 0.
+    #[serde(default)]
+    pub epoch: u64,
+    /// Network this committee belongs to, fixed at genesis. Every signature is
+    /// bound to it, so none can be replayed on another network.
+    pub chain_id: String,
+    pub validators: Vec<ValidatorInfo>,
+    /// Threshold key of the coin that elects anchors, with every validator's share.
+    pub coin: CoinDealing,
+    /// H(epoch || BLS keys), set by `for_epoch`. Scopes caches keyed by signer set.
+    #[serde(skip)]
+    fingerprint: Hash,
+}
+
+impl Committee {
+    /// Genesis (epoch 0) committee of a new chain.
+    pub fn new(chain_id: &str, validators: Vec<ValidatorInfo>, coin: CoinDealing) -> Result<Self, String> {
+        Self::for_epoch(chain_id, 0, validators, coin)
+    }
+
+    /// Sorts validators by id and checks the set is usable. `coin` must be
+    /// dealt to the validators in id order.
+    pub fn for_epoch(chain_id: &str, epoch: u64, mut validators: Vec<ValidatorInfo>, coin: CoinDealing) -> Result<Self, String> {
+        validators.sort_by_key(|v| v.id);
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&epoch.to_le_bytes());
+        for v in &validators {
+            hasher.update(&v.bls_public_key);
+        }
+        let committee = Self { epoch, chain_id: chain_id.to_string(), validators, coin, fingerprint: *hasher.finalize().as_bytes() };
+        committee.validate()?;
+        Ok(committee)
+    }
+
+    /// Committee of a later epoch of the same chain.
+    pub fn next(&self, epoch: u64, validators: Vec<ValidatorInfo>, coin: CoinDealing) -> Result<Self, String> {
+        Self::for_epoch(&self.chain_id, epoch, validators, coin)
+    }
+
+    /// Committee from a JSON file: `{ "epoch": 0, "chain_id": "...", "validators": [ { "id": 0, ... }, ... ], "coin": { ... } }`.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let json = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let committee: Committee = serde_json::from_str(&json).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Self::for_epoch(&committee.chain_id, committee.epoch, committee.validators, committee.coin)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        std::fs::write(path, serde_json::to_string_pretty(self).unwrap())
+    }
+
+    /// Identity of a network: H(chain id || epoch || validators || coin) of its genesis committee.
+    pub fn genesis_hash(&self) -> Hash {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(b"genesis");
+        hasher.update(&(self.chain_id.len() as u32).to_le_bytes());
+        hasher.update(self.chain_id.as_bytes());
+        hasher.update(&crate::crypto::hash_committee(self.epoch, &self.validators, &self.coin));
+        *hasher.finalize().as_bytes()
+    }
+
+    /// Write the committee as a genesis file, i.e. with its hash. Returns the hash.
+    pub fn save_genesis(&self, path: impl AsRef<Path>) -> std::io::Result<Hash> {
+        let genesis_hash = self.genesis_hash();
+        let file = GenesisFile { genesis_hash: genesis_hash.to_vec(), committee: self.clone() };
+        std::fs::write(path, serde_json::to_string_pretty(&file).unwrap())?;
+        Ok(genesis_hash)
+    }
+
+    /// Load a genesis file. Its committee must hash to both the recorded hash
+    /// and `expected`, the one the operator was given.
+    pub fn load_genesis(path: impl AsRef<Path>, expected: &Hash) -> Result<Self, String> {
+        let path = path.as_ref();
+        let json = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let file: GenesisFile = serde_json::from_str(&json).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let committee = Self::for_epoch(&file.committee.chain_id, file.committee.epoch, file.committee.validators, file.committee.coin)?;
+        let genesis_hash = committee.genesis_hash();
+        if file.genesis_hash != genesis_hash {
+            return Err(format!("{}: contents do not match the recorded genesis hash", path.display()));
+        }
+        if genesis_hash != *expected {
+            return Err(format!("{}: genesis hash {} is not the expected {}", path.display(),
+                crate::crypto::to_hex(&genesis_hash), crate::crypto::to_hex(expected)));
+        }
+        Ok(committee)
+    }
+
+    /// Local testnet on `LOCALHOST_CHAIN_ID`: validator i listens on 127.0.0.1:(port_offset + i),
+    /// with unit voting power. BLS keys come with their proofs of possession.
+    /// The coin is dealt here, from the OS RNG.
+    pub fn localhost(bls_keys: Vec<(BlsPublicKey, BlsSignature)>, ed25519_public_keys: Vec<[u8; 32]>, port_offset: u16) -> Result<Self, String> {
+        Self::localhost_with_rng(&mut rand::rngs::OsRng, bls_keys, ed25519_public_keys, port_offset)
+    }
+
+    fn localhost_with_rng<R: rand::RngCore>(rng: &mut R, bls_keys: Vec<(BlsPublicKey, BlsSignature)>, ed25519_public_keys: Vec<[u8; 32]>, port_offset: u16) -> Result<Self, String> {
+        let validators: Vec<ValidatorInfo> = bls_keys.into_iter().zip(ed25519_public_keys).enumerate()
+            .map(|(i, ((bls_public_key, bls_proof_of_possession), ed))| ValidatorInfo {
+                id: i as ValidatorId,
+                bls_public_key,
+                bls_proof_of_possession,
+                ed25519_public_key: ed.to_vec(),
+                address: format!("127.0.0.1:{}", port_offset + i as u16),
+                voting_power: 1,
+            })
+            .collect();
+        let coin = CoinDealing::deal(rng, &validators.iter().map(|v| v.bls_public_key.clone()).collect::<Vec<_>>())?;
+        Self::new(LOCALHOST_CHAIN_ID, validators, coin)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.chain_id.is_empty() || self.chain_id.len() > MAX_CHAIN_ID_LEN {
+            return Err(format!("chain id must be 1 to {} bytes", MAX_CHAIN_ID_LEN));
+        }
+        if self.validators.is_empty() {
+            return Err("committee is empty".into());
+        }
+        if self.validators.len() > MAX_SIGNERS {
+            return Err(format!("committee has {} validators, at most {} are supported", self.validators.len(), MAX_SIGNERS));
+        }
+        self.validators.iter()
+            .try_fold(0u64, |total, v| total.checked_add(v.voting_power))
+            .ok_or("total voting power overflows u64")?;
+        let mut addresses = HashSet::new();
+        let mut bls_keys = HashSet::new();
+        let mut ed_keys = HashSet::new();
+        for (i, v) in self.validators.iter().enumerate() {
+            if v.id != i as ValidatorId {
+                return Err(format!("validator ids must be 0..{} without gaps, found {}", self.validators.len(), v.id));
+            }
+            if v.voting_power == 0 {
+                return Err(format!("validator {} has no voting power", v.id));
+            }
+            if !crate::bls_crypto::verify_proof_of_possession(&self.chain_id, &v.bls_public_key, &v.bls_proof_of_possession) {
+                return Err(format!("validator {} has an invalid BLS public key or proof of possession", v.id));
+            }
+            let ed_valid = <[u8; 32]>::try_from(v.ed25519_public_key.as_slice()).ok()
+                .is_some_and(|bytes| ed25519_dalek::VerifyingKey::from_bytes(&bytes).is_ok());
+            if !ed_valid {
+                return Err(format!("validator {} has an invalid ed25519 public key", v.id));
+            }
+            let port_ok = v.address.rsplit_once(':').is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok());
+            if !port_ok {
+                return Err(format!("validator {} has malformed address {:?}", v.id, v.address));
+            }
+            if !addresses.insert(&v.address) {
+                return Err(format!("address {} is used by more than one validator", v.address));
+            }
+            if !bls_keys.insert(&v.bls_public_key) || !ed_keys.insert(&v.ed25519_public_key) {
+                return Err(format!("validator {} reuses another validator's key", v.id));
+            }
+        }
+        self.coin.validate(self.validators.len())
+    }
+
+    pub fn fingerprint(&self) -> &Hash {
+        &self.fingerprint
+    }
+
+    /// What this committee's members sign under.
+    pub fn signing_context(&self) -> SigningContext {
+        SigningContext { chain_id: self.chain_id.clone(), epoch: self.epoch }
+    }
+
+    pub fn size(&self) -> usize {
+        self.validators.len()
+    }
+
+    pub fn total_stake(&self) -> u64 {
+        self.validators.iter().map(|v| v.voting_power).sum()
+    }
+
+    /// Voting power of `id`, 0 if it is not a member.
+    pub fn stake(&self, id: ValidatorId) -> u64 {
+        self.validator(id).map_or(0, |v| v.voting_power)
+    }
+
+    /// Summed voting power of distinct validators.
+    pub fn stake_of(&self, ids: impl IntoIterator<Item = ValidatorId>) -> u64 {
+        let ids: HashSet<ValidatorId> = ids.into_iter().collect();
+        ids.into_iter().map(|id| self.stake(id)).sum()
+    }
+
+    /// Summed voting power of the validators in a signer set.
+    pub fn bitmap_stake(&self, bitmap: &SignerBitmap) -> u64 {
+        self.stake_of(bitmap.ids())
+    }
+
+    /// Stake for a certificate: more than 2/3 of the total.
+    pub fn quorum_threshold(&self) -> u64 {
+        (self.total_stake() as u128 * 2 / 3) as u64 + 1
+    }
+
+    /// Stake that must include an honest validator: more than 1/3 of the total.
+    pub fn validity_threshold(&self) -> u64 {
+        self.total_stake() / 3 + 1
+    }
+
+    pub fn validator(&self, id: ValidatorId) -> Option<&ValidatorInfo> {
+        self.validators.get(id as usize)
+    }
+
+    pub fn bls_public_key(&self, id: ValidatorId) -> Option<&BlsPublicKey> {
+        self.validator(id).map(|v| &v.bls_public_key)
+    }
+
+    /// Id of the validator holding `bls_public_key`. Ids are positions, so they
+    /// can change from one epoch to the next; keys identify a validator.
+    pub fn id_of(&self, bls_public_key: &[u8]) -> Option<ValidatorId> {
+        self.validators.iter().find(|v| v.bls_public_key == bls_public_key).map(|v| v.id)
+    }
+
+    pub fn address(&self, id: ValidatorId) -> Option<&str> {
+        self.validator(id).map(|v| v.address.as_str())
+    }
+}
+
+/// Genesis file: the committee's fields plus `genesis_hash`, so it also loads
+/// as a plain committee file.
+#[derive(Serialize, Deserialize)]
+struct GenesisFile {
+    #[serde(with = "hex_bytes")]
+    genesis_hash: Vec<u8>,
+    #[serde(flatten)]
+    committee: Committee,
+}
+
+/// Keys as lowercase hex strings in config files.
+pub(crate) mod hex_bytes {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use crate::crypto::{from_hex, to_hex};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&to_hex(bytes))
+    }
+
+    /// Into `Vec<u8>`, or a fixed-size array such as a `Hash`.
+    pub fn deserialize<'de, D: Deserializer<'de>, T: TryFrom<Vec<u8>>>(deserializer: D) -> Result<T, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        let bytes = from_hex(&hex).ok_or_else(|| serde::de::Error::custom("malformed hex string"))?;
+        T::try_from(bytes).map_err(|_| serde::de::Error::custom("hex string has the wrong length"))
+    }
+}
+
+/// Lists of keys as lists of hex strings.
+pub(crate) mod hex_list {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use serde::ser::SerializeSeq;
+    use crate::crypto::{from_hex, to_hex};
+
+    pub fn serialize<S: Serializer>(list: &[Vec<u8>], serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(list.len()))?;
+        for bytes in list {
+            seq.serialize_element(&to_hex(bytes))?;
+        }
+        seq.end()
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Vec<u8>>, D::Error> {
+        Vec::<String>::deserialize(deserializer)?.iter()
+            .map(|hex| from_hex(hex).ok_or_else(|| serde::de::Error::custom("malformed hex string")))
+            .collect()
+    }
+}
+
+/// Committee of `n` localhost validators built from the fixed test keys, with
+/// a coin dealt from a fixed seed.
+#[cfg(test)]
+pub fn test_committee(n: usize) -> Committee {
+    use rand::SeedableRng;
+    let bls = crate::bls_crypto::test_keys(n).iter().map(|sk| (sk.public_key(), sk.prove_possession(LOCALHOST_CHAIN_ID))).collect();
+    let ed = (0..n).map(|i| ed25519_dalek::SigningKey::from_bytes(&[i as u8 + 1; 32]).verifying_key().to_bytes()).collect();
+    Committee::localhost_with_rng(&mut rand::rngs::StdRng::seed_from_u64(n as u64), bls, ed, 10000).unwrap()
+}
+
+/// `test_committee(n)`'s coin dealt again to `validators`, for committees built from its validators.
+#[cfg(test)]
+pub fn test_coin(validators: &[ValidatorInfo]) -> CoinDealing {
+    use rand::SeedableRng;
+    let keys: Vec<BlsPublicKey> = validators.iter().map(|v| v.bls_public_key.clone()).collect();
+    CoinDealing::deal(&mut rand::rngs::StdRng::seed_from_u64(validators.len() as u64), &keys).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_round_trip() {
+        let committee = test_committee(4);
+        let path = std::env::temp_dir().join(format!("sublyne-committee-{}.json", std::process::id()));
+        committee.save(&path).unwrap();
+        let loaded = Committee::load(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(loaded, committee);
+        assert_eq!((loaded.size(), loaded.quorum_threshold(), loaded.validity_threshold()), (4, 3, 2));
+
+        let next = committee.next(1, committee.validators.clone(), committee.coin.clone()).unwrap();
+        assert_ne!(next.fingerprint(), committee.fingerprint());
+        next.save(&path).unwrap();
+        let loaded = Committee::load(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(loaded, next);
+    }
+
+    #[test]
+    fn test_genesis_hash_is_checked() {
+        let committee = test_committee(4);
+        let path = std::env::temp_dir().join(format!("sublyne-genesis-{}.json", std::process::id()));
+        let genesis_hash = committee.save_genesis(&path).unwrap();
+        assert_eq!(Committee::load_genesis(&path, &genesis_hash).unwrap(), committee);
+        assert_eq!(Committee::load(&path).unwrap(), committee);
+        assert!(Committee::load_genesis(&path, &[0; 32]).is_err());
+
+        // Edited after the hash was taken
+        let edited = std::fs::read_to_string(&path).unwrap().replacen("\"voting_power\": 1", "\"voting_power\": 9", 1);
+        std::fs::write(&path, edited).unwrap();
+        assert!(Committee::load_genesis(&path, &genesis_hash).is_err());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_thresholds_follow_stake() {
+        // Unit stake matches the classic n - f and f + 1
+        for (n, quorum, validity) in [(3, 3, 2), (4, 3, 2), (7, 5, 3), (10, 7, 4)] {
+            let committee = test_committee(n);
+            assert_eq!(committee.quorum_threshold(), quorum);
+            assert_eq!(committee.validity_threshold(), validity);
+        }
+
+        let mut validators = test_committee(4).validators;
+        for (v, stake) in validators.iter_mut().zip([5, 1, 1, 1]) {
+            v.voting_power = stake;
+        }
+        let coin = test_coin(&validators);
+        let committee = Committee::new(LOCALHOST_CHAIN_ID, validators, coin).unwrap();
+        assert_eq!((committee.total_stake(), committee.quorum_threshold(), committee.validity_threshold()), (8, 6, 3));
+        assert_eq!(committee.bitmap_stake(&SignerBitmap::from_ids(4, [1, 2, 3]).unwrap()), 3);
+        assert_eq!(committee.bitmap_stake(&SignerBitmap::from_ids(4, [0, 1]).unwrap()), 6);
+        assert_eq!(committee.stake_of([0, 0, 9]), 5);
+    }
+
+    #[test]
+    fn test_inconsistent_committees_are_rejected() {
+        let Committee { validators: good, coin, .. } = test_committee(4);
+        let broken = |edit: &dyn Fn(&mut Vec<ValidatorInfo>)| {
+            let mut validators = good.clone();
+            edit(&mut validators);
+            Committee::new(LOCALHOST_CHAIN_ID, validators, coin.clone())
+        };
+        assert!(broken(&|_| {}).is_ok());
+        assert!(broken(&|vs| vs.clear()).is_err());
+        assert!(broken(&|vs| vs[2].id = 7).is_err());
+        assert!(broken(&|vs| vs[1].voting_power = 0).is_err());
+        assert!(broken(&|vs| { vs[1].voting_power = u64::MAX; vs[2].voting_power = 2; }).is_err());
+        assert!(broken(&|vs| vs[1].bls_public_key[5] ^= 1).is_err());
+        assert!(broken(&|vs| vs[1].bls_proof_of_possession = vs[2].bls_proof_of_possession.clone()).is_err());
+        assert!(Committee::new("another-chain", good.clone(), coin.clone()).is_err());
+        assert!(Committee::new("", good.clone(), coin.clone()).is_err());
+        // A coin dealt to another committee size
+        assert!(Committee::new(LOCALHOST_CHAIN_ID, good.clone(), test_committee(3).coin).is_err());
+        assert!(Committee::new(LOCALHOST_CHAIN_ID, good[..3].to_vec(), coin.clone()).is_err());
+        assert!(broken(&|vs| vs[1].ed25519_public_key.truncate(31)).is_err());
+        assert!(broken(&|vs| vs[3].address = vs[0].address.clone()).is_err());
+        assert!(broken(&|vs| vs[3].address = "localhost".into()).is_err());
+        assert!(broken(&|vs| vs[3].bls_public_key = vs[0].bls_public_key.clone()).is_err());
+    }
+}
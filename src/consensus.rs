@@ -1,82 +1,758 @@
 use std::collections::{HashMap, HashSet};
-use crate::types::{Vertex, Hash, ValidatorId, CoA, Event, SkipCert, CertifiedVertex};
+use std::sync::Arc;
+use crate::types::{Vertex, Hash, ValidatorId, CoA, Event, SkipCert, AggregatedCoA, AggregatedCertifiedVertex, SignedVertex, EquivocationEvidence, CommittedSubDag, ReconfigurationProposal, Request, Response, BlsSignature};
+use crate::bls_crypto::{verify_aggregated, verify_signature};
 use crate::dag::Dag;
-use crate::crypto::{derive_vrf_seed, hash};
+use crate::committee::Committee;
+use crate::crypto::{derive_vrf_seed, hash_committee, hash_vertex, SigningContext, SigningIntent, SigningMessage};
 use rkyv::ser::serializers::AllocSerializer;
 use rkyv::ser::Serializer;
 
+/// Most certified vertices sent in answer to one request.
+pub const MAX_CERTIFIED_ANSWER: usize = 256;
+
+/// Rounds that elect an anchor; the round after each one only votes for it.
+/// A later anchor has parents from a stake quorum in the round before it, which
+/// overlaps the f+1 stake that commits an anchor directly, so every anchor a
+/// node commits directly is in the history of every later anchor. Epochs start
+/// on the odd round after their boundary anchor, so anchors stay even.
+pub fn is_anchor_round(round: u64) -> bool {
+    round.is_multiple_of(2)
+}
+
 pub struct ConsensusState {
     pub dag: Dag,
     pub round: u64,
+    /// Our id in the current committee. A validator that left keeps its last id;
+    /// the node stops driving it.
     pub validator_id: ValidatorId,
-    pub n: usize,
-    pub f: usize,
+    pub epoch: u64,
+    pub committee: Arc<Committee>,
     pub coa_collectors: HashMap<Hash, HashMap<ValidatorId, Vec<u8>>>,
+    /// Partial signatures checked individually; these are never overwritten.
+    pub verified_partials: HashSet<(Hash, ValidatorId)>,
+    /// Invalid partial signatures dropped since the last call, by (vertex, claimed signer).
+    pub bad_partials: Vec<(Hash, ValidatorId)>,
+    /// Skip votes by (round, anchor); only verified ones are collected.
     pub skip_collectors: HashMap<(u64, u32), HashMap<ValidatorId, Vec<u8>>>,
+    /// Skip votes checked individually, by (round, anchor, voter).
+    pub verified_skip_votes: HashSet<(u64, u32, ValidatorId)>,
     pub has_signed_skip: HashSet<(u64, u32)>,
     pub has_signed_coa: HashSet<Hash>,
+    /// The one vertex we signed for each (round, author).
+    pub signed_slots: HashMap<(u64, ValidatorId), Hash>,
+    /// Every distinct vertex seen for each (round, author), in arrival order.
+    pub vertex_slots: HashMap<(u64, ValidatorId), Vec<Hash>>,
+    /// Verified equivocation evidence by (round, author).
+    pub equivocations: HashMap<(u64, ValidatorId), EquivocationEvidence>,
+    /// Evidence detected locally, waiting to be broadcast.
+    pub new_evidence: Vec<EquivocationEvidence>,
+    /// Anchor election seed of every anchor round whose coin we have.
     pub vrf_seeds: HashMap<u64, Hash>,
+    /// Coin shares by anchor round; only verified ones are collected.
+    pub coin_shares: HashMap<u64, HashMap<ValidatorId, BlsSignature>>,
+    /// Coin shares checked individually, by (round, voter).
+    pub verified_coin_shares: HashSet<(u64, ValidatorId)>,
+    /// Anchor rounds whose coin we may help reveal, by certification of the round before.
+    pub released_coins: HashSet<u64>,
+    /// Anchor rounds whose coin share we owe, waiting for the node to sign and broadcast it.
+    pub pending_coin_shares: Vec<u64>,
     pub fallback_depth: u32,
+    /// Anchors committed so far, in commit order.
+    pub committed_anchors: Vec<Hash>,
+    /// Total order of committed vertices produced by `aether_sort`.
+    pub committed_log: Vec<Hash>,
+    /// Sub-DAGs committed since the last call, waiting for the node's commit log.
+    pub committed_sub_dags: Vec<CommittedSubDag>,
+    /// Skip certificates by round. They move the pacemaker past a dead anchor;
+    /// the commit rule itself never depends on them.
+    pub skip_certs: HashMap<u64, SkipCert>,
+    /// Skip votes we agreed to sign, waiting for the node to sign and broadcast them.
+    pub pending_skip_votes: Vec<(u64, u32)>,
+    /// Our own skip votes whose round timed out again, to broadcast once more.
+    pub resent_skip_votes: Vec<(u64, u32, Vec<u8>)>,
+    /// Skip certificates formed locally, waiting to be broadcast.
+    pub new_skip_certs: Vec<SkipCert>,
+    /// Verified aggregates that arrived before their vertex.
+    pub pending_aggregates: HashMap<Hash, AggregatedCoA>,
+    /// Vertices whose parents are not all certified yet. Released from
+    /// `certify_vertex` once they are.
+    pub pending_vertices: HashMap<Hash, Vertex>,
+    /// Parents that buffered vertices waited on when a round timed out, for the
+    /// node to fetch, by the author to ask.
+    pub missing_parents: Vec<(ValidatorId, Vec<Hash>)>,
+    pub max_pending_per_author: usize,
+    pub max_pending_per_round: usize,
+    /// Vertices validated into the DAG since the last call, for the node to vote on.
+    pub accepted_vertices: Vec<(Hash, Vertex)>,
+    /// Messages of the next epoch that arrived before we committed its boundary.
+    /// Replayed once we enter it.
+    pub future_events: Vec<Event>,
+    pub max_future_events: usize,
+    /// Committees of the epochs entered since the last call.
+    pub epoch_changes: Vec<Arc<Committee>>,
+    /// Committed proposals for the next epoch by `hash_committee`, with the
+    /// authors that made them.
+    pub reconfiguration_support: HashMap<Hash, (ReconfigurationProposal, HashSet<ValidatorId>)>,
 }
 
 impl ConsensusState {
-    pub fn new(validator_id: ValidatorId, n: usize) -> Self {
-        let f = (n - 1) / 3;
-        println!("DEBUG: Initializing ConsensusState for n={}, f={}", n, f);
+    pub fn new(validator_id: ValidatorId, committee: Arc<Committee>) -> Self {
+        let n = committee.size();
         Self {
-            dag: Dag::new(n, f),
+            dag: Dag::new(committee.clone()),
             round: 1,
             validator_id,
-            n,
-            f,
+            epoch: committee.epoch,
+            committee,
             coa_collectors: HashMap::new(),
+            verified_partials: HashSet::new(),
+            bad_partials: Vec::new(),
             skip_collectors: HashMap::new(),
+            verified_skip_votes: HashSet::new(),
             has_signed_skip: HashSet::new(),
             has_signed_coa: HashSet::new(),
+            signed_slots: HashMap::new(),
+            vertex_slots: HashMap::new(),
+            equivocations: HashMap::new(),
+            new_evidence: Vec::new(),
             vrf_seeds: HashMap::new(),
+            coin_shares: HashMap::new(),
+            verified_coin_shares: HashSet::new(),
+            released_coins: HashSet::new(),
+            pending_coin_shares: Vec::new(),
             fallback_depth: 0,
+            committed_anchors: Vec::new(),
+            committed_log: Vec::new(),
+            committed_sub_dags: Vec::new(),
+            skip_certs: HashMap::new(),
+            pending_skip_votes: Vec::new(),
+            resent_skip_votes: Vec::new(),
+            new_skip_certs: Vec::new(),
+            pending_aggregates: HashMap::new(),
+            pending_vertices: HashMap::new(),
+            missing_parents: Vec::new(),
+            max_pending_per_author: 64,
+            max_pending_per_round: 2 * n,
+            accepted_vertices: Vec::new(),
+            future_events: Vec::new(),
+            max_future_events: 64 * n,
+            epoch_changes: Vec::new(),
+            reconfiguration_support: HashMap::new(),
         }
     }
 
     pub fn on_event(&mut self, event: Event) {
+        match event_epoch(&event) {
+            // Late message from an epoch that has ended
+            Some(epoch) if epoch < self.epoch => return,
+            Some(epoch) if epoch > self.epoch => {
+                if epoch == self.epoch + 1 && self.future_events.len() < self.max_future_events {
+                    self.future_events.push(event);
+                }
+                return;
+            }
+            _ => {}
+        }
         match event {
             Event::VertexReceived(vertex) => self.handle_vertex(vertex),
             Event::CoAReceived(coa) => self.handle_coa(coa),
-            _ => {}
+            Event::AggregatedCoAReceived(agg) => self.handle_aggregated_coa(agg),
+            Event::SkipVoteReceived(_, round, anchor, voter, sig) => self.handle_skip_vote(round, anchor, voter, sig),
+            Event::SkipCertReceived(cert) => self.handle_skip_cert(cert),
+            Event::CoinShareReceived(_, round, voter, sig) => self.handle_coin_share(round, voter, sig),
+            Event::EquivocationReceived(evidence) => self.handle_equivocation(evidence),
+            Event::Timeout(round) => self.handle_timeout(round),
+            // Requests change nothing here; the node answers them with `answer`
+            Event::RequestReceived(..) => {}
+        }
+    }
+
+    /// What we can tell a peer that asked, from what is certified in our DAG.
+    pub fn answer(&self, request: &Request) -> Response {
+        match request {
+            Request::Certified(hashes) => Response::Certified(hashes.iter()
+                .filter_map(|h| Some(AggregatedCertifiedVertex {
+                    vertex: self.dag.vertices.get(h)?.clone(),
+                    agg_coa: self.dag.certs.get(h)?.clone(),
+                }))
+                .take(MAX_CERTIFIED_ANSWER)
+                .collect()),
         }
     }
 
     fn handle_vertex(&mut self, vertex: Vertex) {
-        let v_hash = crate::crypto::hash_vertex(&vertex);
+        let v_hash = hash_vertex(&vertex);
+        if self.dag.vertices.contains_key(&v_hash) || self.pending_vertices.contains_key(&v_hash) {
+            return;
+        }
+        if self.dag.parents_ready(&vertex) {
+            self.accept_vertex(v_hash, vertex);
+        } else {
+            self.buffer_vertex(v_hash, vertex);
+        }
+    }
+
+    /// Add a vertex whose parents are all certified, if it passes `validate_vertex`.
+    fn accept_vertex(&mut self, v_hash: Hash, vertex: Vertex) {
+        if !self.dag.validate_vertex(&vertex) {
+            return;
+        }
         self.dag.vertices.insert(v_hash, vertex.clone());
         self.dag.round_to_vertices.entry(vertex.round).or_default().push(v_hash);
+        let slot = (vertex.round, vertex.author);
+        let seen = self.vertex_slots.entry(slot).or_default();
+        seen.push(v_hash);
+        if seen.len() > 1 {
+            self.detect_equivocation(slot);
+        }
+        self.accepted_vertices.push((v_hash, vertex));
+        if let Some(agg) = self.pending_aggregates.remove(&v_hash) {
+            self.certify_vertex(agg);
+        }
+    }
+
+    /// Hold a vertex until its parents are certified. Dropped once its author or
+    /// its round has too many vertices waiting.
+    fn buffer_vertex(&mut self, v_hash: Hash, vertex: Vertex) {
+        let by_author = self.pending_vertices.values().filter(|v| v.author == vertex.author).count();
+        let in_round = self.pending_vertices.values().filter(|v| v.round == vertex.round).count();
+        if by_author >= self.max_pending_per_author || in_round >= self.max_pending_per_round {
+            return;
+        }
+        self.pending_vertices.insert(v_hash, vertex);
+    }
+
+    /// Accept buffered vertices of `round` whose parents are now all certified.
+    fn release_pending(&mut self, round: u64) {
+        let ready: Vec<Hash> = self.pending_vertices.iter()
+            .filter(|(_, v)| v.round == round && self.dag.parents_ready(v))
+            .map(|(h, _)| *h)
+            .collect();
+        for h in ready {
+            if let Some(vertex) = self.pending_vertices.remove(&h) {
+                self.accept_vertex(h, vertex);
+            }
+        }
+    }
+
+    /// Vertices accepted into the DAG since the last call. The node votes on these.
+    pub fn take_accepted_vertices(&mut self) -> Vec<(Hash, Vertex)> {
+        std::mem::take(&mut self.accepted_vertices)
+    }
+
+    /// Decide whether we may sign `vertex`, recording the vote if so. We sign only
+    /// validated vertices, and at most one per (round, author), whichever came first.
+    pub fn try_vote(&mut self, v_hash: Hash, vertex: &Vertex) -> bool {
+        if !self.dag.vertices.contains_key(&v_hash) {
+            return false;
+        }
+        let signed = *self.signed_slots.entry((vertex.round, vertex.author)).or_insert(v_hash);
+        signed == v_hash && self.has_signed_coa.insert(v_hash)
+    }
+
+    /// Build evidence once we hold two of the author's vertices for `slot`, each
+    /// with the author's verified vote. The vote usually trails the vertex, so
+    /// this runs again from `handle_coa`.
+    fn detect_equivocation(&mut self, slot: (u64, ValidatorId)) {
+        if self.equivocations.contains_key(&slot) {
+            return;
+        }
+        let Some(seen) = self.vertex_slots.get(&slot) else { return };
+        let mut signed = seen.iter().filter_map(|h| {
+            let signature = self.author_signature(h)?;
+            let sv = SignedVertex { vertex: self.dag.vertices.get(h)?.clone(), signature };
+            self.verify_signed_vertex(&sv).then_some(sv)
+        });
+        let (Some(first), Some(second)) = (signed.next(), signed.next()) else { return };
+        let evidence = EquivocationEvidence { first, second };
+        self.equivocations.insert(slot, evidence.clone());
+        self.new_evidence.push(evidence);
+    }
+
+    /// What validators sign to vote for `v_hash` in this epoch.
+    pub fn vote_message(&self, v_hash: &Hash) -> SigningMessage {
+        self.committee.signing_context().message(&SigningIntent::VertexCertificate(*v_hash))
+    }
+
+    fn verify_signed_vertex(&self, sv: &SignedVertex) -> bool {
+        // Signed in the vertex's own epoch
+        let context = SigningContext { chain_id: self.committee.chain_id.clone(), epoch: sv.vertex.epoch };
+        let msg = context.message(&SigningIntent::VertexCertificate(hash_vertex(&sv.vertex)));
+        self.committee.bls_public_key(sv.vertex.author)
+            .is_some_and(|pk| verify_signature(&msg, &sv.signature, pk))
+    }
+
+    /// Evidence holds if both vertices share round and author, differ, and carry
+    /// valid signatures from that author.
+    pub fn verify_evidence(&self, evidence: &EquivocationEvidence) -> bool {
+        let (a, b) = (&evidence.first.vertex, &evidence.second.vertex);
+        a.epoch == b.epoch
+            && a.round == b.round
+            && a.author == b.author
+            && hash_vertex(a) != hash_vertex(b)
+            && self.verify_signed_vertex(&evidence.first)
+            && self.verify_signed_vertex(&evidence.second)
+    }
+
+    fn handle_equivocation(&mut self, evidence: EquivocationEvidence) {
+        let slot = (evidence.first.vertex.round, evidence.first.vertex.author);
+        if self.equivocations.contains_key(&slot) || !self.verify_evidence(&evidence) {
+            return;
+        }
+        self.equivocations.insert(slot, evidence);
+    }
+
+    /// Equivocation evidence detected locally since the last call, for broadcasting.
+    pub fn take_new_evidence(&mut self) -> Vec<EquivocationEvidence> {
+        std::mem::take(&mut self.new_evidence)
+    }
+
+    fn handle_timeout(&mut self, round: u64) {
+        self.find_missing_parents();
+        if round <= self.dag.committed_round || self.skip_certs.contains_key(&round) {
+            return;
+        }
+        if self.anchor_vertex(round).is_some() {
+            return;
+        }
+        // No coin yet: our share may have been lost, so sign and send it again
+        if is_anchor_round(round) && self.anchor_seed(round).is_none() {
+            if self.released_coins.contains(&round) {
+                self.pending_coin_shares.push(round);
+            }
+            return;
+        }
+        let Some(anchor) = self.anchor_for_round(round) else { return };
+        if self.has_signed_skip.insert((round, anchor)) {
+            self.pending_skip_votes.push((round, anchor));
+            return;
+        }
+        // Timed out again: our vote may have been lost, or never got signed
+        match self.skip_collectors.get(&(round, anchor)).and_then(|c| c.get(&self.validator_id)) {
+            Some(sig) => self.resent_skip_votes.push((round, anchor, sig.clone())),
+            None => self.pending_skip_votes.push((round, anchor)),
+        }
+    }
+
+    /// A stalled round may be waiting on certificates we never received. Ask
+    /// each buffered vertex's author for its missing parents: it had them
+    /// certified to build on them. What comes back is checked like any vertex
+    /// and aggregate before it enters the DAG.
+    fn find_missing_parents(&mut self) {
+        let mut missing: HashMap<ValidatorId, HashSet<Hash>> = HashMap::new();
+        for vertex in self.pending_vertices.values().filter(|v| v.author != self.validator_id) {
+            let unknown = vertex.parents.iter().filter(|h| !self.dag.certs.contains_key(*h));
+            missing.entry(vertex.author).or_default().extend(unknown);
+        }
+        self.missing_parents = missing.into_iter()
+            .flat_map(|(author, hashes)| {
+                let hashes: Vec<Hash> = hashes.into_iter().collect();
+                hashes.chunks(MAX_CERTIFIED_ANSWER).map(|chunk| (author, chunk.to_vec())).collect::<Vec<_>>()
+            })
+            .collect();
+    }
+
+    fn handle_skip_vote(&mut self, round: u64, anchor: u32, voter: ValidatorId, sig: Vec<u8>) {
+        if self.skip_certs.contains_key(&round) || self.anchor_for_round(round) != Some(anchor) {
+            return;
+        }
+        if !self.verify_skip_vote(round, anchor, voter, &sig) {
+            return;
+        }
+        let collector = self.skip_collectors.entry((round, anchor)).or_default();
+        collector.insert(voter, sig);
+        if self.committee.stake_of(collector.keys().copied()) >= self.committee.quorum_threshold() {
+            let signatures: Vec<_> = collector.iter().map(|(&k, v)| (k, v.clone())).collect();
+            let cert = SkipCert { epoch: self.epoch, round, anchor_index: anchor, signatures };
+            self.new_skip_certs.push(cert.clone());
+            self.apply_skip_cert(cert);
+        }
+    }
+
+    fn handle_skip_cert(&mut self, cert: SkipCert) {
+        if self.skip_certs.contains_key(&cert.round) || self.anchor_for_round(cert.round) != Some(cert.anchor_index) {
+            return;
+        }
+        if !cert.signatures.iter().all(|(voter, sig)| self.verify_skip_vote(cert.round, cert.anchor_index, *voter, sig)) {
+            return;
+        }
+        let signers = cert.signatures.iter().map(|(id, _)| *id);
+        if self.committee.stake_of(signers) < self.committee.quorum_threshold() {
+            return;
+        }
+        self.apply_skip_cert(cert);
+    }
+
+    /// What validators sign to skip `anchor`'s round in this epoch.
+    pub fn skip_vote_message(&self, round: u64, anchor: ValidatorId) -> SigningMessage {
+        self.committee.signing_context().message(&SigningIntent::SkipVote { round, anchor })
+    }
+
+    /// Check one skip vote against the voter's BLS key, remembering the ones that hold.
+    fn verify_skip_vote(&mut self, round: u64, anchor: u32, voter: ValidatorId, sig: &Vec<u8>) -> bool {
+        if self.verified_skip_votes.contains(&(round, anchor, voter)) {
+            return true;
+        }
+        let valid = self.committee.bls_public_key(voter)
+            .is_some_and(|pk| verify_signature(&self.skip_vote_message(round, anchor), sig, pk));
+        if valid {
+            self.verified_skip_votes.insert((round, anchor, voter));
+        }
+        valid
+    }
+
+    fn apply_skip_cert(&mut self, cert: SkipCert) {
+        self.skip_collectors.remove(&(cert.round, cert.anchor_index));
+        self.skip_certs.insert(cert.round, cert);
+        self.fallback_depth += 1;
+    }
+
+    /// Skip votes the node still has to sign and broadcast.
+    pub fn take_pending_skip_votes(&mut self) -> Vec<(u64, u32)> {
+        std::mem::take(&mut self.pending_skip_votes)
+    }
+
+    /// Signed skip votes to broadcast again, as (round, anchor, signature).
+    pub fn take_resent_skip_votes(&mut self) -> Vec<(u64, u32, Vec<u8>)> {
+        std::mem::take(&mut self.resent_skip_votes)
+    }
+
+    /// Skip certificates formed locally since the last call, for broadcasting.
+    pub fn take_new_skip_certs(&mut self) -> Vec<SkipCert> {
+        std::mem::take(&mut self.new_skip_certs)
     }
 
     fn handle_coa(&mut self, coa: CoA) {
         let v_hash = coa.batch_hash;
+        // The first partial per signer stays until it fails verification, so a
+        // later one cannot displace a valid vote
         let collector = self.coa_collectors.entry(v_hash).or_default();
         for (id, sig) in coa.signatures {
-            collector.insert(id, sig);
+            collector.entry(id).or_insert(sig);
+        }
+        if let Some(v) = self.dag.vertices.get(&v_hash) {
+            let slot = (v.round, v.author);
+            if self.vertex_slots.get(&slot).is_some_and(|seen| seen.len() > 1) {
+                self.detect_equivocation(slot);
+            }
+        }
+    }
+
+    /// Check one collected partial signature, dropping and reporting it if bad.
+    fn verify_partial(&mut self, v_hash: &Hash, id: ValidatorId) -> bool {
+        if self.verified_partials.contains(&(*v_hash, id)) {
+            return true;
+        }
+        let Some(sig) = self.coa_collectors.get(v_hash).and_then(|c| c.get(&id)) else { return false };
+        let valid = self.committee.bls_public_key(id)
+            .is_some_and(|pk| verify_signature(&self.vote_message(v_hash), sig, pk));
+        if valid {
+            self.verified_partials.insert((*v_hash, id));
+        } else {
+            if let Some(c) = self.coa_collectors.get_mut(v_hash) {
+                c.remove(&id);
+            }
+            self.bad_partials.push((*v_hash, id));
+        }
+        valid
+    }
+
+    /// Fallback after an aggregate over `v_hash`'s votes failed to verify: check
+    /// every partial on its own and drop the bad ones, so the quorum only counts
+    /// valid votes. Returns the signers that were dropped.
+    pub fn verify_partials(&mut self, v_hash: &Hash) -> Vec<ValidatorId> {
+        let ids: Vec<ValidatorId> = self.coa_collectors.get(v_hash)
+            .map(|c| c.keys().copied().collect())
+            .unwrap_or_default();
+        ids.into_iter().filter(|&id| !self.verify_partial(v_hash, id)).collect()
+    }
+
+    /// Dropped partial signatures since the last call, for reporting. The node
+    /// takes votes only from their signer's own connection, so the signer named
+    /// is the peer that sent the bad vote.
+    pub fn take_bad_partials(&mut self) -> Vec<(Hash, ValidatorId)> {
+        std::mem::take(&mut self.bad_partials)
+    }
+
+    /// Aggregated certificate from a peer: verify it against the committee's BLS
+    /// keys before it can enter the DAG.
+    fn handle_aggregated_coa(&mut self, agg: AggregatedCoA) {
+        if self.dag.certs.contains_key(&agg.batch_hash) || self.pending_aggregates.contains_key(&agg.batch_hash) {
+            return;
+        }
+        let quorum = self.committee.quorum_threshold();
+        if !verify_aggregated(&self.vote_message(&agg.batch_hash), &agg.aggregated_signature, &self.committee, &agg.signer_bitmap, quorum) {
+            return;
+        }
+        if self.dag.vertices.contains_key(&agg.batch_hash) {
+            self.certify_vertex(agg);
+        } else {
+            self.pending_aggregates.insert(agg.batch_hash, agg);
         }
     }
 
-    pub fn certify_vertex(&mut self, v_hash: Hash, signatures: Vec<(ValidatorId, Vec<u8>)>) {
+    /// Insert a verified certificate.
+    pub fn certify_vertex(&mut self, agg: AggregatedCoA) {
+        let v_hash = agg.batch_hash;
         if let Some(vertex) = self.dag.vertices.get(&v_hash).cloned() {
             if !self.dag.certs.contains_key(&v_hash) {
-                let cv = CertifiedVertex { vertex: vertex.clone(), coa: CoA { batch_hash: v_hash, signatures } };
+                let round = vertex.round;
+                let cv = AggregatedCertifiedVertex { vertex, agg_coa: agg };
                 self.dag.insert_certified(cv, v_hash);
-                if vertex.round > self.dag.committed_round {
-                    self.dag.committed_round = vertex.round;
+                self.release_coin(round + 1);
+                self.release_pending(round + 1);
+                self.try_commit();
+            }
+        }
+    }
+
+    /// Owe our share of anchor `round`'s coin once a stake quorum of the round
+    /// before it is certified here. An honest validator's share is the only
+    /// thing the coin waits on, so it stays unknown until that round is
+    /// certified, after every vertex that could steer the anchor is fixed.
+    fn release_coin(&mut self, round: u64) {
+        if !is_anchor_round(round) || self.released_coins.contains(&round) {
+            return;
+        }
+        let Some(hashes) = self.dag.round_to_vertices.get(&(round - 1)) else { return };
+        let certified = self.committee.stake_of(hashes.iter()
+            .filter(|h| self.dag.certs.contains_key(*h))
+            .map(|h| self.dag.vertices[h].author));
+        if certified >= self.committee.quorum_threshold() {
+            self.released_coins.insert(round);
+            self.pending_coin_shares.push(round);
+        }
+    }
+
+    /// What validators sign with their coin share to reveal anchor `round`'s coin.
+    pub fn coin_message(&self, round: u64) -> SigningMessage {
+        self.committee.signing_context().message(&SigningIntent::AnchorCoin { round })
+    }
+
+    fn handle_coin_share(&mut self, round: u64, voter: ValidatorId, sig: BlsSignature) {
+        if !is_anchor_round(round) || self.vrf_seeds.contains_key(&round) {
+            return;
+        }
+        if self.coin_shares.get(&round).is_some_and(|c| c.contains_key(&voter)) {
+            return;
+        }
+        if !self.verified_coin_shares.contains(&(round, voter)) {
+            if !self.committee.coin.verify_share(voter, &self.coin_message(round), &sig) {
+                return;
+            }
+            self.verified_coin_shares.insert((round, voter));
+        }
+        let shares = self.coin_shares.entry(round).or_default();
+        shares.insert(voter, sig);
+        let threshold = self.committee.coin.threshold();
+        if shares.len() >= threshold {
+            let shares: Vec<_> = shares.iter().take(threshold).map(|(&id, sig)| (id, sig.clone())).collect();
+            if let Some(coin) = self.committee.coin.combine(&self.coin_message(round), &shares) {
+                self.apply_coin(round, coin);
+            }
+        }
+    }
+
+    /// Record anchor `round`'s coin and commit what it unblocks.
+    pub fn apply_coin(&mut self, round: u64, coin: BlsSignature) {
+        if self.vrf_seeds.contains_key(&round) {
+            return;
+        }
+        self.vrf_seeds.insert(round, derive_vrf_seed(vec![coin], round));
+        self.coin_shares.remove(&round);
+        self.try_commit();
+    }
+
+    /// Anchor rounds whose coin share we owe, for the node to sign and broadcast.
+    pub fn take_pending_coin_shares(&mut self) -> Vec<u64> {
+        std::mem::take(&mut self.pending_coin_shares)
+    }
+
+    /// Certified parents to request, and from whom, since the last call.
+    pub fn take_missing_parents(&mut self) -> Vec<(ValidatorId, Vec<Hash>)> {
+        std::mem::take(&mut self.missing_parents)
+    }
+
+    /// Election seed for `round`: H(coin || round), once the round's coin is
+    /// revealed. The coin is a threshold BLS signature, unique whichever shares
+    /// made it, so every node gets the same seed without waiting on any
+    /// particular validator.
+    pub fn anchor_seed(&self, round: u64) -> Option<Hash> {
+        self.vrf_seeds.get(&round).copied()
+    }
+
+    /// Validator whose vertex anchors `round`, or None if `round` is not an
+    /// anchor round or its coin is not revealed yet. Every node computes the
+    /// same answer.
+    pub fn anchor_for_round(&self, round: u64) -> Option<ValidatorId> {
+        if !is_anchor_round(round) {
+            return None;
+        }
+        let seed = self.anchor_seed(round)?;
+        let mut word = [0u8; 8];
+        word.copy_from_slice(&seed[..8]);
+        Some((u64::from_le_bytes(word) % self.committee.size() as u64) as ValidatorId)
+    }
+
+    /// The author's own vote on its vertex: its signature over it, for
+    /// equivocation evidence.
+    fn author_signature(&self, v_hash: &Hash) -> Option<Vec<u8>> {
+        let author = self.dag.vertices.get(v_hash)?.author;
+        self.coa_collectors.get(v_hash)?.get(&author).cloned()
+    }
+
+    /// Certified anchor vertex of `round`, if we have it. An equivocating anchor
+    /// can have several vertices in the round, but at most one gets certified.
+    pub fn anchor_vertex(&self, round: u64) -> Option<Hash> {
+        let author = self.anchor_for_round(round)?;
+        self.vertex_slots.get(&(round, author))?
+            .iter()
+            .find(|h| self.dag.certs.contains_key(*h))
+            .copied()
+    }
+
+    /// Lowest anchor round we have proposed in whose anchor is neither certified
+    /// nor skipped. This is the round the pacemaker should be timing.
+    pub fn waiting_anchor_round(&self) -> Option<u64> {
+        (self.dag.committed_round + 1..self.round)
+            .filter(|&r| is_anchor_round(r))
+            .find(|&r| !self.skip_certs.contains_key(&r) && self.anchor_vertex(r).is_none())
+    }
+
+    /// Stake of the authors of certified vertices in round+1 that reference `anchor` as a parent.
+    fn anchor_support(&self, anchor: &Hash, round: u64) -> u64 {
+        let Some(hashes) = self.dag.round_to_vertices.get(&(round + 1)) else { return 0 };
+        self.committee.stake_of(hashes.iter()
+            .filter(|h| self.dag.certs.contains_key(*h))
+            .filter_map(|h| self.dag.vertices.get(h))
+            .filter(|v| v.parents.contains(anchor))
+            .map(|v| v.author))
+    }
+
+    /// Bullshark commit rule: an anchor commits once certified vertices of the
+    /// next round holding more than 1/3 of the stake point at it, after any
+    /// earlier anchor in its causal history. Only anchors of earlier anchor
+    /// rounds are walked back to, which is what makes the rule agree across
+    /// nodes that saw different supporters (see `is_anchor_round`). Only the
+    /// oldest anchor of a chain is committed before the rest is re-evaluated.
+    fn try_commit(&mut self) {
+        while let Some((anchor, round)) = self.next_committable_anchor() {
+            self.commit_anchor(anchor, round);
+        }
+    }
+
+    /// Every anchor round up to the one committed must have its coin, or the
+    /// walk back would pass over an anchor other nodes can see.
+    fn next_committable_anchor(&self) -> Option<(Hash, u64)> {
+        let mut direct = None;
+        for r in (self.dag.committed_round + 1..self.dag.max_certified_round).filter(|&r| is_anchor_round(r)) {
+            self.anchor_seed(r)?;
+            if let Some(a) = self.anchor_vertex(r).filter(|a| self.anchor_support(a, r) >= self.committee.validity_threshold()) {
+                direct = Some((a, r));
+                break;
+            }
+        }
+        let (mut anchor, mut round) = direct?;
+        for r in (self.dag.committed_round + 1..round).rev().filter(|&r| is_anchor_round(r)) {
+            if let Some(prev) = self.anchor_vertex(r) {
+                if self.dag.has_path(&anchor, &prev) {
+                    anchor = prev;
+                    round = r;
                 }
             }
         }
+        Some((anchor, round))
+    }
+
+    fn commit_anchor(&mut self, anchor: Hash, round: u64) {
+        let Some(seed) = self.anchor_seed(round) else { return };
+        let sub_dag = self.dag.aether_sort(&anchor, &seed);
+        self.dag.ordered.extend(sub_dag.iter().copied());
+        let reconfiguration = self.record_proposals(&sub_dag);
+        self.committed_sub_dags.push(CommittedSubDag {
+            index: self.committed_anchors.len() as u64,
+            epoch: self.epoch,
+            anchor,
+            round,
+            vertices: sub_dag.iter().map(|h| self.dag.vertices[h].clone()).collect(),
+            certificates: sub_dag.iter().map(|h| self.dag.certs[h].clone()).collect(),
+        });
+        self.committed_log.extend(sub_dag);
+        self.committed_anchors.push(anchor);
+        self.dag.committed_round = round;
+        self.fallback_depth = 0;
+        if let Some(proposal) = reconfiguration {
+            if let Ok(committee) = self.committee.next(proposal.epoch, proposal.validators, *proposal.coin) {
+                self.enter_epoch(Arc::new(committee), round + 1);
+            }
+        }
+    }
+
+    /// Count the next-epoch proposals committed in `sub_dag`. The first committee
+    /// proposed alike by a stake quorum ends this epoch at the anchor's round, so
+    /// no minority can pick the next validator set.
+    fn record_proposals(&mut self, sub_dag: &[Hash]) -> Option<ReconfigurationProposal> {
+        for h in sub_dag {
+            let vertex = &self.dag.vertices[h];
+            let Some(proposal) = vertex.reconfiguration.as_ref().filter(|p| p.epoch == self.epoch + 1) else { continue };
+            let (_, authors) = self.reconfiguration_support
+                .entry(hash_committee(proposal.epoch, &proposal.validators, &proposal.coin))
+                .or_insert_with(|| (proposal.clone(), HashSet::new()));
+            authors.insert(vertex.author);
+            if self.committee.stake_of(authors.iter().copied()) >= self.committee.quorum_threshold() {
+                return Some(proposal.clone());
+            }
+        }
+        None
+    }
+
+    /// Switch to the next epoch's committee, with an empty DAG whose genesis
+    /// round is `first_round`. The commit stream, buffer limits and votes for
+    /// unknown vertices carry over; everything else, the coin included,
+    /// belonged to the old committee.
+    fn enter_epoch(&mut self, committee: Arc<Committee>, first_round: u64) {
+        let validator_id = self.committee.bls_public_key(self.validator_id)
+            .and_then(|pk| committee.id_of(pk))
+            .unwrap_or(self.validator_id);
+        let mut next = ConsensusState::new(validator_id, committee.clone());
+        next.dag = Dag::starting_at(committee.clone(), first_round);
+        next.round = first_round;
+        // Votes carry no epoch, so ones for vertices we never saw may be for the new one
+        next.coa_collectors = std::mem::take(&mut self.coa_collectors).into_iter()
+            .filter(|(h, _)| !self.dag.vertices.contains_key(h))
+            .collect();
+        next.committed_anchors = std::mem::take(&mut self.committed_anchors);
+        next.committed_log = std::mem::take(&mut self.committed_log);
+        next.committed_sub_dags = std::mem::take(&mut self.committed_sub_dags);
+        next.max_pending_per_author = self.max_pending_per_author;
+        next.max_pending_per_round = self.max_pending_per_round;
+        next.max_future_events = self.max_future_events;
+        next.epoch_changes = std::mem::take(&mut self.epoch_changes);
+        next.epoch_changes.push(committee);
+        let deferred = std::mem::take(&mut self.future_events);
+        crate::bls_crypto::retire_committee(&self.committee);
+        *self = next;
+        for event in deferred {
+            self.on_event(event);
+        }
+    }
+
+    /// Committees of the epochs entered since the last call, oldest first.
+    pub fn take_epoch_changes(&mut self) -> Vec<Arc<Committee>> {
+        std::mem::take(&mut self.epoch_changes)
+    }
+
+    /// Sub-DAGs committed since the last call, in commit order.
+    pub fn take_committed(&mut self) -> Vec<CommittedSubDag> {
+        std::mem::take(&mut self.committed_sub_dags)
     }
 
     pub fn get_pending_quorums(&self) -> Vec<(Hash, Vertex, Vec<(ValidatorId, Vec<u8>)>)> {
         let mut pending = Vec::new();
-        let quorum_size = self.n - self.f;
+        let quorum = self.committee.quorum_threshold();
         for (h, collector) in &self.coa_collectors {
-            if !self.dag.certs.contains_key(h) && collector.len() >= quorum_size {
+            if !self.dag.certs.contains_key(h) && self.committee.stake_of(collector.keys().copied()) >= quorum {
                 if let Some(vertex) = self.dag.vertices.get(h) {
                     let signatures: Vec<_> = collector.iter().map(|(&k, v)| (k, v.clone())).collect();
                     pending.push((*h, vertex.clone(), signatures));
@@ -86,3 +762,778 @@ impl ConsensusState {
         pending
     }
 }
+
+/// Epoch a message belongs to. Votes carry none: they sign a vertex hash, which
+/// already covers the vertex's epoch.
+fn event_epoch(event: &Event) -> Option<u64> {
+    match event {
+        Event::VertexReceived(v) => Some(v.epoch),
+        Event::AggregatedCoAReceived(agg) => Some(agg.epoch),
+        Event::SkipVoteReceived(epoch, ..) | Event::CoinShareReceived(epoch, ..) => Some(*epoch),
+        Event::SkipCertReceived(cert) => Some(cert.epoch),
+        Event::EquivocationReceived(evidence) => Some(evidence.first.vertex.epoch),
+        Event::CoAReceived(_) | Event::Timeout(_) | Event::RequestReceived(..) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bls_crypto::{test_keys, BlsSecretKey};
+    use crate::committee::{test_coin, test_committee, LOCALHOST_CHAIN_ID};
+    use crate::committee::ValidatorInfo;
+    use crate::types::{ReconfigurationProposal, SignerBitmap};
+
+    /// Node `id` of a 4-validator committee with the fixed test keys.
+    fn node(id: ValidatorId) -> ConsensusState {
+        ConsensusState::new(id, Arc::new(test_committee(4)))
+    }
+
+    fn add_vertex(state: &mut ConsensusState, round: u64, author: ValidatorId, parents: Vec<Hash>) -> Hash {
+        let v = Vertex { epoch: state.epoch, round, author, batch_hash: [author as u8; 32], parents, reconfiguration: None };
+        let h = crate::crypto::hash_vertex(&v);
+        state.on_event(Event::VertexReceived(v));
+        h
+    }
+
+    fn add_round(state: &mut ConsensusState, round: u64, authors: &[ValidatorId]) -> Vec<Hash> {
+        let mut parents = if round > state.dag.first_round { state.dag.round_to_vertices[&(round - 1)].clone() } else { vec![] };
+        parents.sort();
+        authors.iter().map(|&author| add_vertex(state, round, author, parents.clone())).collect()
+    }
+
+    /// What validators sign to vote for `h` in epoch 0 of the test chain.
+    fn vote_message(h: &Hash) -> SigningMessage {
+        SigningContext { chain_id: LOCALHOST_CHAIN_ID.into(), epoch: 0 }.message(&SigningIntent::VertexCertificate(*h))
+    }
+
+    fn skip_vote(keys: &[BlsSecretKey], voter: ValidatorId, round: u64, anchor: ValidatorId) -> Event {
+        let message = SigningContext { chain_id: LOCALHOST_CHAIN_ID.into(), epoch: 0 }.message(&SigningIntent::SkipVote { round, anchor });
+        Event::SkipVoteReceived(0, round, anchor, voter, keys[voter as usize].sign(&message))
+    }
+
+    fn vote(keys: &[BlsSecretKey], voter: ValidatorId, h: Hash) -> Event {
+        Event::CoAReceived(CoA { batch_hash: h, signatures: vec![(voter, keys[voter as usize].sign(&vote_message(&h)))] })
+    }
+
+    /// The author's vote followed by a certificate. Coins the certificate
+    /// releases are revealed right away.
+    fn certify(state: &mut ConsensusState, h: Hash) {
+        let author = state.dag.vertices[&h].author;
+        let signature = test_keys(4)[author as usize].sign(&state.vote_message(&h));
+        state.on_event(Event::CoAReceived(CoA { batch_hash: h, signatures: vec![(author, signature)] }));
+        state.certify_vertex(AggregatedCoA { epoch: state.epoch, batch_hash: h, aggregated_signature: vec![], signer_bitmap: SignerBitmap::from_ids(4, 0..4).unwrap() });
+        for round in state.take_pending_coin_shares() {
+            reveal_coin(state, round);
+        }
+    }
+
+    /// Validator `id`'s share of anchor `round`'s coin.
+    fn coin_share(state: &ConsensusState, id: ValidatorId, round: u64) -> Event {
+        let key = &test_keys(state.committee.size())[id as usize];
+        let share = state.committee.coin.decrypt_share(id, key).unwrap();
+        Event::CoinShareReceived(state.epoch, round, id, share.sign(&state.coin_message(round)))
+    }
+
+    /// Anchor `round`'s coin, from the shares of the first validators.
+    fn reveal_coin(state: &mut ConsensusState, round: u64) {
+        for id in 0..state.committee.coin.threshold() as ValidatorId {
+            let share = coin_share(state, id, round);
+            state.on_event(share);
+        }
+    }
+
+    fn certify_all(state: &mut ConsensusState, hashes: &[Hash]) {
+        for h in hashes {
+            certify(state, *h);
+        }
+    }
+
+    #[test]
+    fn test_single_certificate_does_not_commit() {
+        let mut state = node(0);
+        let r1 = add_round(&mut state, 1, &[0, 1, 2, 3]);
+        certify_all(&mut state, &r1);
+        assert_eq!(state.dag.committed_round, 0);
+        assert!(state.committed_log.is_empty());
+    }
+
+    #[test]
+    fn test_commit_does_not_wait_for_author_votes() {
+        let mut state = node(0);
+        // Certificates from the other three, never with the author's own vote
+        for round in 1..=3 {
+            for (author, h) in add_round(&mut state, round, &[0, 1, 2, 3]).into_iter().enumerate() {
+                let signers = (0..4).filter(|&id| id != author as ValidatorId);
+                state.certify_vertex(AggregatedCoA { epoch: 0, batch_hash: h, aggregated_signature: vec![], signer_bitmap: SignerBitmap::from_ids(4, signers).unwrap() });
+            }
+        }
+        assert_eq!(state.dag.certs.len(), 12);
+        assert_eq!(state.take_pending_coin_shares(), vec![2, 4]);
+        reveal_coin(&mut state, 2);
+        assert_eq!(state.dag.committed_round, 2);
+    }
+
+    fn aggregate(keys: &[BlsSecretKey], signers: &[ValidatorId], h: &Hash) -> AggregatedCoA {
+        let sigs: Vec<_> = signers.iter().map(|&id| (id, keys[id as usize].sign(&vote_message(h)))).collect();
+        let (aggregated_signature, signer_bitmap, _) = crate::bls_crypto::aggregate_signatures_with_metrics(&sigs, &test_committee(4), 0).unwrap();
+        AggregatedCoA { epoch: 0, batch_hash: *h, aggregated_signature, signer_bitmap }
+    }
+
+    #[test]
+    fn test_received_aggregate_is_verified() {
+        let mut state = node(0);
+        let keys = test_keys(4);
+        let h = add_round(&mut state, 1, &[1])[0];
+
+        // Below quorum, or signed over a different message: rejected
+        state.on_event(Event::AggregatedCoAReceived(aggregate(&keys, &[1, 3], &h)));
+        let mut forged = aggregate(&keys, &[0, 1, 3], &[9u8; 32]);
+        forged.batch_hash = h;
+        state.on_event(Event::AggregatedCoAReceived(forged));
+        assert!(state.dag.certs.is_empty());
+
+        let agg = aggregate(&keys, &[0, 1, 3], &h);
+        state.on_event(Event::AggregatedCoAReceived(agg.clone()));
+        assert_eq!(state.dag.certs[&h].signer_bitmap, agg.signer_bitmap);
+    }
+
+    #[test]
+    fn test_quorums_are_weighted_by_stake() {
+        // Validator 0 holds 5 of 8: it and any other form a quorum, the other three do not
+        let mut validators = test_committee(4).validators;
+        for (v, stake) in validators.iter_mut().zip([5, 1, 1, 1]) {
+            v.voting_power = stake;
+        }
+        let mut state = ConsensusState::new(1, Arc::new(Committee::new(LOCALHOST_CHAIN_ID, validators.clone(), test_coin(&validators)).unwrap()));
+        let keys = test_keys(4);
+
+        let r1 = add_round(&mut state, 1, &[0, 1, 2, 3]);
+        state.on_event(Event::AggregatedCoAReceived(aggregate(&keys, &[1, 2, 3], &r1[1])));
+        assert!(state.dag.certs.is_empty());
+        // Validator 0 plus the author is enough
+        let signers: [&[ValidatorId]; 4] = [&[0, 2], &[0, 1, 2], &[0, 2], &[0, 3]];
+        for (signers, h) in signers.iter().zip(&r1) {
+            state.on_event(Event::AggregatedCoAReceived(aggregate(&keys, signers, h)));
+        }
+        assert_eq!(state.dag.certs.len(), 4);
+
+        let light = Vertex { epoch: 0, round: 2, author: 1, batch_hash: [1u8; 32], parents: r1[1..].to_vec(), reconfiguration: None };
+        let heavy = Vertex { epoch: 0, round: 2, author: 1, batch_hash: [1u8; 32], parents: r1[..2].to_vec(), reconfiguration: None };
+        assert!(!state.dag.validate_vertex(&light));
+        assert!(state.dag.validate_vertex(&heavy));
+
+        reveal_coin(&mut state, 2);
+        let anchor = state.anchor_for_round(2).unwrap();
+        for voter in 1..4 {
+            state.on_event(skip_vote(&keys, voter, 2, anchor));
+        }
+        assert!(state.skip_certs.is_empty());
+        state.on_event(skip_vote(&keys, 0, 2, anchor));
+        assert!(state.skip_certs.contains_key(&2));
+    }
+
+    #[test]
+    fn test_aggregate_before_vertex_is_held_back() {
+        let mut state = node(0);
+        let keys = test_keys(4);
+        let v = Vertex { epoch: 0, round: 1, author: 2, batch_hash: [2u8; 32], parents: vec![], reconfiguration: None };
+        let h = crate::crypto::hash_vertex(&v);
+
+        state.on_event(Event::AggregatedCoAReceived(aggregate(&keys, &[1, 2, 3], &h)));
+        assert!(state.dag.certs.is_empty());
+        state.on_event(Event::VertexReceived(v));
+        assert!(state.dag.certs.contains_key(&h));
+        assert!(state.pending_aggregates.is_empty());
+    }
+
+    #[test]
+    fn test_vertex_waits_for_parent_certificates() {
+        let mut state = node(0);
+        let r1 = add_round(&mut state, 1, &[0, 1, 2, 3]);
+        assert_eq!(state.take_accepted_vertices().len(), 4);
+        certify_all(&mut state, &r1[..2]);
+
+        let h = add_vertex(&mut state, 2, 0, r1[..3].to_vec());
+        assert!(state.pending_vertices.contains_key(&h));
+        assert!(!state.dag.vertices.contains_key(&h));
+        assert!(!state.try_vote(h, &state.pending_vertices[&h].clone()));
+
+        certify(&mut state, r1[2]);
+        assert!(state.pending_vertices.is_empty());
+        let accepted = state.take_accepted_vertices();
+        assert_eq!(accepted.len(), 1);
+        assert_eq!(accepted[0].0, h);
+        assert!(state.try_vote(h, &accepted[0].1));
+    }
+
+    #[test]
+    fn test_answer_holds_only_certified_vertices() {
+        let mut state = node(0);
+        let r1 = add_round(&mut state, 1, &[0, 1, 2, 3]);
+        certify_all(&mut state, &r1[..2]);
+
+        let Response::Certified(found) = state.answer(&Request::Certified(vec![r1[0], r1[2], [9; 32], r1[1]]));
+        let hashes: Vec<_> = found.iter().map(|c| crate::crypto::hash_vertex(&c.vertex)).collect();
+        assert_eq!(hashes, vec![r1[0], r1[1]]);
+        assert!(found.iter().all(|c| c.agg_coa.batch_hash == crate::crypto::hash_vertex(&c.vertex)));
+    }
+
+    #[test]
+    fn test_stalled_round_fetches_missing_parents() {
+        let keys = test_keys(4);
+        // A peer holds all of round 1 certified; we missed validator 3's vertex and certificate
+        let mut peer = node(1);
+        let r1 = add_round(&mut peer, 1, &[0, 1, 2, 3]);
+        let mut state = node(0);
+        for (author, h) in r1.iter().enumerate() {
+            peer.on_event(Event::AggregatedCoAReceived(aggregate(&keys, &[0, 1, 2], h)));
+            if author < 3 {
+                add_vertex(&mut state, 1, author as ValidatorId, vec![]);
+                state.on_event(Event::AggregatedCoAReceived(aggregate(&keys, &[0, 1, 2], h)));
+            }
+        }
+        let mut parents = r1.clone();
+        parents.sort();
+        let h = add_vertex(&mut state, 2, 1, parents);
+        assert!(state.pending_vertices.contains_key(&h));
+
+        // Only once the round stalls is the missing parent asked of the author that built on it
+        assert!(state.take_missing_parents().is_empty());
+        state.on_event(Event::Timeout(2));
+        assert_eq!(state.take_missing_parents(), vec![(1, vec![r1[3]])]);
+
+        // The answer goes through the usual checks: a forged certificate is dropped
+        let Response::Certified(found) = peer.answer(&Request::Certified(vec![r1[3]]));
+        let forged = AggregatedCoA { aggregated_signature: aggregate(&keys, &[0, 1, 2], &[9; 32]).aggregated_signature, ..found[0].agg_coa.clone() };
+        state.on_event(Event::AggregatedCoAReceived(forged));
+        state.on_event(Event::VertexReceived(found[0].vertex.clone()));
+        assert!(state.pending_vertices.contains_key(&h));
+        state.on_event(Event::AggregatedCoAReceived(found[0].agg_coa.clone()));
+        assert!(state.dag.vertices.contains_key(&h));
+        assert!(state.pending_vertices.is_empty());
+    }
+
+    #[test]
+    fn test_invalid_vertices_are_rejected() {
+        let mut state = node(0);
+        let r1 = add_round(&mut state, 1, &[0, 1, 2, 3]);
+        certify_all(&mut state, &r1);
+        state.take_accepted_vertices();
+
+        let too_few = add_vertex(&mut state, 2, 0, r1[..2].to_vec());
+        let repeated = add_vertex(&mut state, 2, 1, vec![r1[0], r1[0], r1[1]]);
+        let wrong_round = add_vertex(&mut state, 3, 2, r1[..3].to_vec());
+        let genesis_with_parents = add_vertex(&mut state, 1, 0, vec![r1[0]]);
+        let mut proposals = Vec::new();
+        for (epoch, validators) in [(2, test_committee(3).validators), (1, vec![])] {
+            let coin = test_coin(&validators);
+            let reconfiguration = Some(ReconfigurationProposal { epoch, validators, coin: Box::new(coin) });
+            let v = Vertex { epoch: 0, round: 2, author: 3, batch_hash: [3u8; 32], parents: r1[..3].to_vec(), reconfiguration };
+            proposals.push(hash_vertex(&v));
+            state.on_event(Event::VertexReceived(v));
+        }
+        for h in [too_few, repeated, wrong_round, genesis_with_parents, proposals[0], proposals[1]] {
+            assert!(!state.dag.vertices.contains_key(&h));
+            assert!(!state.pending_vertices.contains_key(&h));
+        }
+        assert!(state.take_accepted_vertices().is_empty());
+    }
+
+    /// Like `add_round`, with `proposers` proposing `validators` for epoch 1.
+    fn add_round_proposing(state: &mut ConsensusState, round: u64, authors: &[ValidatorId], proposers: &[ValidatorId], validators: &[ValidatorInfo]) -> Vec<Hash> {
+        let mut parents = if round > state.dag.first_round { state.dag.round_to_vertices[&(round - 1)].clone() } else { vec![] };
+        parents.sort();
+        authors.iter().map(|&author| {
+            let reconfiguration = proposers.contains(&author).then(|| ReconfigurationProposal { epoch: 1, validators: validators.to_vec(), coin: Box::new(test_coin(validators)) });
+            let v = Vertex { epoch: state.epoch, round, author, batch_hash: [author as u8; 32], parents: parents.clone(), reconfiguration };
+            let h = hash_vertex(&v);
+            state.on_event(Event::VertexReceived(v));
+            h
+        }).collect()
+    }
+
+    #[test]
+    fn test_reconfiguration_takes_effect_after_its_commit() {
+        let mut state = node(0);
+        (state.max_pending_per_author, state.max_pending_per_round, state.max_future_events) = (5, 6, 7);
+        // Three of four validators propose an epoch without validator 3
+        for round in 1..=2 {
+            let hashes = add_round(&mut state, round, &[0, 1, 2, 3]);
+            certify_all(&mut state, &hashes);
+        }
+        let r3 = add_round_proposing(&mut state, 3, &[0, 1, 2, 3], &[0, 1, 2], &test_committee(3).validators);
+        certify_all(&mut state, &r3);
+        // The round-2 anchor commits without any proposal in its history
+        assert_eq!((state.epoch, state.dag.committed_round), (0, 2));
+
+        // A genesis vertex of epoch 1 that arrives before the boundary is held back
+        let early = Vertex { epoch: 1, round: 5, author: 1, batch_hash: [1u8; 32], parents: vec![], reconfiguration: None };
+        let early_hash = hash_vertex(&early);
+        state.on_event(Event::VertexReceived(early));
+        assert_eq!(state.future_events.len(), 1);
+        // A vote for it is kept as well
+        let context = SigningContext { chain_id: LOCALHOST_CHAIN_ID.into(), epoch: 1 };
+        let signature = test_keys(4)[2].sign(&context.message(&SigningIntent::VertexCertificate(early_hash)));
+        state.on_event(Event::CoAReceived(CoA { batch_hash: early_hash, signatures: vec![(2, signature)] }));
+
+        // The round-4 anchor's history holds every proposal
+        let r4 = add_round(&mut state, 4, &[0, 1, 2, 3]);
+        certify_all(&mut state, &r4);
+        let r5 = add_round(&mut state, 5, &[0, 1, 2, 3]);
+        for h in &r5 {
+            if state.epoch == 1 {
+                break;
+            }
+            certify(&mut state, *h);
+        }
+        assert_eq!((state.epoch, state.committee.size()), (1, 3));
+        assert_eq!((state.dag.first_round, state.dag.committed_round, state.round), (5, 4, 5));
+        assert_eq!(state.take_epoch_changes().len(), 1);
+        assert_eq!((state.max_pending_per_author, state.max_pending_per_round, state.max_future_events), (5, 6, 7));
+        let committed = state.take_committed();
+        assert_eq!(committed.len(), 2);
+        assert_eq!((committed[1].epoch, committed[1].round), (0, 4));
+        assert!(state.dag.vertices.contains_key(&early_hash));
+        assert!(state.future_events.is_empty());
+        assert_eq!(state.coa_collectors.len(), 1);
+        assert!(state.verify_partial(&early_hash, 2));
+
+        // Late epoch-0 vertices are rejected
+        let late = Vertex { epoch: 0, round: 5, author: 2, batch_hash: [7u8; 32], parents: r4.clone(), reconfiguration: None };
+        state.on_event(Event::VertexReceived(late));
+        assert_eq!(state.dag.vertices.len(), 1);
+
+        // The new committee keeps committing, and the commit stream runs on
+        let mut e1 = add_round(&mut state, 5, &[0, 2]);
+        e1.push(early_hash);
+        certify_all(&mut state, &e1);
+        for round in 6..=7 {
+            let hashes = add_round(&mut state, round, &[0, 1, 2]);
+            certify_all(&mut state, &hashes);
+        }
+        let committed = state.take_committed();
+        assert_eq!(committed.len(), 1);
+        assert_eq!((committed[0].index, committed[0].epoch, committed[0].round), (2, 1, 6));
+    }
+
+    #[test]
+    fn test_single_proposer_cannot_replace_committee() {
+        let mut state = node(0);
+        // Validator 1 proposes, every round, a committee holding only its own key
+        let mut own = vec![test_committee(4).validators[1].clone()];
+        own[0].id = 0;
+        for round in 1..=6 {
+            let hashes = add_round_proposing(&mut state, round, &[0, 1, 2, 3], &[1], &own);
+            certify_all(&mut state, &hashes);
+        }
+        assert!(state.dag.committed_round >= 4);
+        assert_eq!((state.epoch, state.committee.size()), (0, 4));
+        assert!(state.take_epoch_changes().is_empty());
+    }
+
+    #[test]
+    fn test_pending_buffer_is_bounded() {
+        let mut state = node(0);
+        state.max_pending_per_author = 2;
+        state.max_pending_per_round = 2;
+        let unknown: Vec<Hash> = (0..3).map(|i| [i; 32]).collect();
+        for round in 5..10 {
+            add_vertex(&mut state, round, 1, unknown.clone());
+        }
+        assert_eq!(state.pending_vertices.len(), 2);
+
+        for author in [0, 2, 3] {
+            add_vertex(&mut state, 20, author, unknown.clone());
+        }
+        assert_eq!(state.pending_vertices.values().filter(|v| v.round == 20).count(), 2);
+        assert_eq!(state.pending_vertices.len(), 4);
+    }
+
+    #[test]
+    fn test_equivocation_is_refused_and_proven() {
+        let mut state = node(0);
+        let keys = test_keys(4);
+        let v1 = Vertex { epoch: 0, round: 1, author: 1, batch_hash: [1u8; 32], parents: vec![], reconfiguration: None };
+        let v2 = crate::fault_injector::FaultInjector::new(vec![1], 1.0).create_equivocation(&v1);
+        let (h1, h2) = (hash_vertex(&v1), hash_vertex(&v2));
+
+        state.on_event(Event::VertexReceived(v1.clone()));
+        state.on_event(Event::VertexReceived(v2.clone()));
+        assert!(state.try_vote(h1, &v1));
+        assert!(!state.try_vote(h2, &v2));
+
+        // Evidence needs the author's signature on both vertices
+        state.on_event(vote(&keys, 1, h1));
+        assert!(state.equivocations.is_empty());
+        state.on_event(vote(&keys, 1, h2));
+        let evidence = state.take_new_evidence();
+        assert_eq!(evidence.len(), 1);
+        assert!(state.equivocations.contains_key(&(1, 1)));
+
+        // Another node accepts the evidence, but not one signed by someone else
+        let mut peer = node(2);
+        let mut forged = evidence[0].clone();
+        forged.second.signature = keys[3].sign(&vote_message(&h2));
+        peer.on_event(Event::EquivocationReceived(forged));
+        assert!(peer.equivocations.is_empty());
+        peer.on_event(Event::EquivocationReceived(evidence[0].clone()));
+        assert!(peer.equivocations.contains_key(&(1, 1)));
+        assert!(peer.take_new_evidence().is_empty());
+    }
+
+    #[test]
+    fn test_forged_author_vote_does_not_frame_author() {
+        let mut state = node(0);
+        let keys = test_keys(4);
+        let v1 = Vertex { epoch: 0, round: 1, author: 1, batch_hash: [1u8; 32], parents: vec![], reconfiguration: None };
+        let v2 = crate::fault_injector::FaultInjector::new(vec![3], 1.0).create_equivocation(&v1);
+        let (h1, h2) = (hash_vertex(&v1), hash_vertex(&v2));
+        state.on_event(Event::VertexReceived(v1));
+        state.on_event(Event::VertexReceived(v2));
+        state.on_event(vote(&keys, 1, h1));
+        // Validator 3 injects a "vote from 1" that it signed itself
+        state.on_event(Event::CoAReceived(CoA { batch_hash: h2, signatures: vec![(1, keys[3].sign(&vote_message(&h2)))] }));
+        assert!(state.equivocations.is_empty());
+        assert!(state.take_new_evidence().is_empty());
+    }
+
+    #[test]
+    fn test_bad_partials_are_dropped_and_reported() {
+        let mut state = node(0);
+        let keys = test_keys(4);
+        let h = add_round(&mut state, 1, &[1])[0];
+        state.on_event(vote(&keys, 0, h));
+        state.on_event(vote(&keys, 1, h));
+        state.on_event(Event::CoAReceived(CoA { batch_hash: h, signatures: vec![(2, keys[3].sign(&vote_message(&h))), (3, vec![0u8; 48])] }));
+        // A later partial for the same signer does not replace the first, checked or not
+        state.on_event(Event::CoAReceived(CoA { batch_hash: h, signatures: vec![(1, vec![0u8; 48])] }));
+        assert_eq!(state.get_pending_quorums().len(), 1);
+
+        let mut bad = state.verify_partials(&h);
+        bad.sort();
+        assert_eq!(bad, vec![2, 3]);
+        assert!(state.get_pending_quorums().is_empty());
+        assert_eq!(state.take_bad_partials().len(), 2);
+
+        // A verified partial cannot be replaced; a valid one restores the quorum
+        state.on_event(Event::CoAReceived(CoA { batch_hash: h, signatures: vec![(0, vec![0u8; 48])] }));
+        state.on_event(vote(&keys, 2, h));
+        assert!(state.verify_partials(&h).is_empty());
+        assert_eq!(state.get_pending_quorums().len(), 1);
+    }
+
+    #[test]
+    fn test_forged_coin_share_is_ignored() {
+        let mut state = node(0);
+        let keys = test_keys(4);
+        // Validator 2 sends a share signed with its own key as validator 1's
+        let forged = keys[2].sign(&state.coin_message(2));
+        state.on_event(Event::CoinShareReceived(0, 2, 1, forged));
+        let share = coin_share(&state, 3, 2);
+        state.on_event(share);
+        assert_eq!(state.anchor_seed(2), None);
+
+        // Any valid threshold gives the same coin
+        let share = coin_share(&state, 1, 2);
+        state.on_event(share);
+        let mut other = node(1);
+        reveal_coin(&mut other, 2);
+        assert!(state.anchor_seed(2).is_some());
+        assert_eq!(state.anchor_seed(2), other.anchor_seed(2));
+    }
+
+    #[test]
+    fn test_anchor_commits_with_next_round_support() {
+        let mut state = node(0);
+        let r1 = add_round(&mut state, 1, &[0, 1, 2, 3]);
+        certify_all(&mut state, &r1);
+        // Odd rounds elect no anchor
+        assert_eq!(state.anchor_for_round(1), None);
+        let r2 = add_round(&mut state, 2, &[0, 1, 2, 3]);
+        certify_all(&mut state, &r2);
+        let r3 = add_round(&mut state, 3, &[0, 1, 2, 3]);
+
+        // f+1 = 2 certified children are needed for the round-2 anchor
+        certify(&mut state, r3[0]);
+        assert_eq!(state.dag.committed_round, 0);
+        certify(&mut state, r3[1]);
+        assert_eq!(state.dag.committed_round, 2);
+        assert_eq!(state.committed_anchors, vec![r2[state.anchor_for_round(2).unwrap() as usize]]);
+        // Only the anchor's causal history is ordered
+        assert_eq!(state.committed_log.len(), 5);
+    }
+
+    #[test]
+    fn test_unsupported_anchor_committed_through_later_anchor() {
+        let mut state = node(0);
+        let r1 = add_round(&mut state, 1, &[0, 1, 2, 3]);
+        certify_all(&mut state, &r1);
+        let r2 = add_round(&mut state, 2, &[0, 1, 2, 3]);
+        certify_all(&mut state, &r2);
+
+        // Only one round-3 vertex links to the round-2 anchor, so it lacks direct support
+        let a2_author = state.anchor_for_round(2).unwrap();
+        let r3: Vec<Hash> = (0..4).map(|author| {
+            let parents = (0..4).filter(|&p| author == 0 || p != a2_author).map(|p| r2[p as usize]).collect();
+            add_vertex(&mut state, 3, author, parents)
+        }).collect();
+        certify_all(&mut state, &r3);
+        assert_eq!(state.dag.committed_round, 0);
+
+        // The round-4 anchor reaches it through that vertex
+        let r4 = add_round(&mut state, 4, &[0, 1, 2, 3]);
+        certify_all(&mut state, &r4);
+        let r5 = add_round(&mut state, 5, &[0, 1, 2, 3]);
+        certify_all(&mut state, &r5[..2]);
+        assert_eq!(state.dag.committed_round, 4);
+        assert_eq!(state.committed_anchors[0], r2[a2_author as usize]);
+
+        // Every certified vertex below the last anchor appears exactly once
+        let unique: HashSet<_> = state.committed_log.iter().collect();
+        assert_eq!(unique.len(), state.committed_log.len());
+        assert!(r1.iter().chain(&r2).chain(&r3).all(|h| unique.contains(h)));
+    }
+
+    /// Two nodes see different quorums of the round after an anchor: one holds
+    /// f+1 supporters and commits it directly, the other only one supporter.
+    /// The next anchor's history still holds it, so both commit the same log.
+    #[test]
+    fn test_nodes_with_different_support_agree() {
+        let (mut x, mut y) = (node(0), node(3));
+        let mut r2 = Vec::new();
+        for state in [&mut x, &mut y] {
+            let r1 = add_round(state, 1, &[0, 1, 2, 3]);
+            certify_all(state, &r1);
+            r2 = add_round(state, 2, &[0, 1, 2, 3]);
+            certify_all(state, &r2);
+        }
+        let a2 = r2[x.anchor_for_round(2).unwrap() as usize];
+        assert_eq!(y.anchor_for_round(2), x.anchor_for_round(2));
+
+        // Validators 0 and 1 vote for the anchor in round 3; 2 and 3 do not
+        let mut without_anchor: Vec<Hash> = r2.iter().copied().filter(|h| *h != a2).collect();
+        without_anchor.sort();
+        let r3_parents = |author: ValidatorId| if author < 2 { r2.clone() } else { without_anchor.clone() };
+        let mut r3 = Vec::new();
+        for author in 0..3 {
+            r3.push(add_vertex(&mut x, 3, author, r3_parents(author)));
+        }
+        certify_all(&mut x, &r3);
+        assert_eq!(x.committed_anchors, vec![a2]);
+        for author in 1..4 {
+            let h = add_vertex(&mut y, 3, author, r3_parents(author));
+            certify(&mut y, h);
+        }
+        assert!(y.committed_anchors.is_empty());
+        // x hears from validator 3 late
+        let late = add_vertex(&mut x, 3, 3, r3_parents(3));
+        certify(&mut x, late);
+
+        // Round 4 builds on the vertices y saw, which include one supporter
+        let mut r4_parents = vec![r3[1], r3[2], late];
+        r4_parents.sort();
+        for state in [&mut x, &mut y] {
+            let r4: Vec<Hash> = (0..4).map(|author| add_vertex(state, 4, author, r4_parents.clone())).collect();
+            certify_all(state, &r4);
+            let r5 = add_round(state, 5, &[0, 1, 2, 3]);
+            certify_all(state, &r5);
+        }
+        assert_eq!(y.dag.committed_round, 4);
+        assert_eq!(x.committed_anchors, y.committed_anchors);
+        assert_eq!(x.committed_anchors[0], a2);
+        assert_eq!(x.committed_log, y.committed_log);
+    }
+
+    #[test]
+    fn test_committed_sub_dags_deliver_each_vertex_once() {
+        let mut state = node(0);
+        for round in 1..=6 {
+            let hashes = add_round(&mut state, round, &[0, 1, 2, 3]);
+            certify_all(&mut state, &hashes);
+        }
+        let sub_dags = state.take_committed();
+        assert!(!sub_dags.is_empty());
+        assert!(state.take_committed().is_empty());
+
+        let mut delivered = Vec::new();
+        for (i, sub_dag) in sub_dags.iter().enumerate() {
+            assert_eq!(sub_dag.index, i as u64);
+            assert_eq!(sub_dag.anchor, state.committed_anchors[i]);
+            assert_eq!(crate::crypto::hash_vertex(sub_dag.vertices.last().unwrap()), sub_dag.anchor);
+            for (v, cert) in sub_dag.vertices.iter().zip(&sub_dag.certificates) {
+                assert_eq!(crate::crypto::hash_vertex(v), cert.batch_hash);
+                delivered.push(cert.batch_hash);
+            }
+        }
+        assert_eq!(delivered, state.committed_log);
+        let unique: HashSet<_> = delivered.iter().collect();
+        assert_eq!(unique.len(), delivered.len());
+    }
+
+    #[test]
+    fn test_timeout_signs_skip_vote_once() {
+        let mut state = node(0);
+        let keys = test_keys(4);
+        reveal_coin(&mut state, 2);
+        let anchor = state.anchor_for_round(2).unwrap();
+        state.on_event(Event::Timeout(2));
+        assert_eq!(state.take_pending_skip_votes(), vec![(2, anchor)]);
+        // Not signed yet: asked for again
+        state.on_event(Event::Timeout(2));
+        assert_eq!(state.take_pending_skip_votes(), vec![(2, anchor)]);
+
+        // Signed: a later timeout re-sends the same vote instead
+        let Event::SkipVoteReceived(.., sig) = skip_vote(&keys, 0, 2, anchor) else { unreachable!() };
+        state.on_event(skip_vote(&keys, 0, 2, anchor));
+        state.on_event(Event::Timeout(2));
+        assert!(state.take_pending_skip_votes().is_empty());
+        assert_eq!(state.take_resent_skip_votes(), vec![(2, anchor, sig)]);
+    }
+
+    #[test]
+    fn test_skip_cert_forms_at_quorum() {
+        let mut state = node(0);
+        let keys = test_keys(4);
+        reveal_coin(&mut state, 2);
+        let anchor = state.anchor_for_round(2).unwrap();
+        for voter in 0..2 {
+            state.on_event(skip_vote(&keys, voter, 2, anchor));
+        }
+        // A vote that does not verify is not counted
+        state.on_event(Event::SkipVoteReceived(0, 2, anchor, 3, vec![3u8; 48]));
+        assert!(state.skip_certs.is_empty());
+        state.on_event(skip_vote(&keys, 2, 2, anchor));
+
+        let certs = state.take_new_skip_certs();
+        assert_eq!(certs.len(), 1);
+        assert_eq!(certs[0].signatures.len(), 3);
+        assert_eq!(state.fallback_depth, 1);
+        assert_eq!(state.waiting_anchor_round(), None);
+
+        // A peer that receives the certificate reaches the same state
+        let mut peer = node(1);
+        reveal_coin(&mut peer, 2);
+        peer.on_event(Event::SkipCertReceived(certs[0].clone()));
+        assert!(peer.skip_certs.contains_key(&2));
+        assert!(peer.take_new_skip_certs().is_empty());
+    }
+
+    #[test]
+    fn test_skip_vote_for_another_epoch_or_chain_is_rejected() {
+        let mut state = node(0);
+        let keys = test_keys(4);
+        reveal_coin(&mut state, 2);
+        let anchor = state.anchor_for_round(2).unwrap();
+        let intent = SigningIntent::SkipVote { round: 2, anchor };
+        let contexts = [
+            SigningContext { chain_id: LOCALHOST_CHAIN_ID.into(), epoch: 1 },
+            SigningContext { chain_id: "other-chain".into(), epoch: 0 },
+        ];
+        for context in &contexts {
+            for voter in 0..4 {
+                state.on_event(Event::SkipVoteReceived(0, 2, anchor, voter, keys[voter as usize].sign(&context.message(&intent))));
+            }
+        }
+        assert!(state.skip_certs.is_empty());
+        assert!(state.verified_skip_votes.is_empty());
+    }
+
+    #[test]
+    fn test_forged_skip_cert_is_rejected() {
+        let mut state = node(0);
+        let keys = test_keys(4);
+        reveal_coin(&mut state, 2);
+        let anchor = state.anchor_for_round(2).unwrap();
+        let junk = SkipCert { epoch: 0, round: 2, anchor_index: anchor, signatures: (0..4).map(|id| (id, vec![id as u8; 48])).collect() };
+        state.on_event(Event::SkipCertReceived(junk));
+        // Two real votes and one signed by someone else
+        let mut signatures: Vec<_> = (0..2).map(|voter| match skip_vote(&keys, voter, 2, anchor) {
+            Event::SkipVoteReceived(.., sig) => (voter, sig),
+            _ => unreachable!(),
+        }).collect();
+        let Event::SkipVoteReceived(.., stolen) = skip_vote(&keys, 3, 2, anchor) else { unreachable!() };
+        signatures.push((2, stolen));
+        state.on_event(Event::SkipCertReceived(SkipCert { epoch: 0, round: 2, anchor_index: anchor, signatures }));
+        assert!(state.skip_certs.is_empty());
+        assert_eq!(state.fallback_depth, 0);
+        state.round = 3;
+        assert_eq!(state.waiting_anchor_round(), Some(2));
+    }
+
+    #[test]
+    fn test_skip_cert_does_not_block_commit() {
+        let mut state = node(0);
+        let keys = test_keys(4);
+        reveal_coin(&mut state, 2);
+        let anchor = state.anchor_for_round(2).unwrap();
+        for voter in 0..3 {
+            state.on_event(skip_vote(&keys, voter, 2, anchor));
+        }
+        // The anchor was late, not dead: it still commits once supported
+        let mut rounds = Vec::new();
+        for round in 1..=3 {
+            let hashes = add_round(&mut state, round, &[0, 1, 2, 3]);
+            certify_all(&mut state, &hashes);
+            rounds.push(hashes);
+        }
+        assert_eq!(state.committed_anchors, vec![rounds[1][anchor as usize]]);
+        assert_eq!(state.fallback_depth, 0);
+    }
+
+    #[test]
+    fn test_coin_is_released_after_the_previous_round() {
+        let mut state = node(0);
+        let r1 = add_round(&mut state, 1, &[0, 1, 2, 3]);
+        for h in &r1[..2] {
+            state.certify_vertex(AggregatedCoA { epoch: 0, batch_hash: *h, aggregated_signature: vec![], signer_bitmap: SignerBitmap::from_ids(4, 0..4).unwrap() });
+        }
+        assert!(state.take_pending_coin_shares().is_empty());
+        state.certify_vertex(AggregatedCoA { epoch: 0, batch_hash: r1[2], aggregated_signature: vec![], signer_bitmap: SignerBitmap::from_ids(4, 0..4).unwrap() });
+        assert_eq!(state.take_pending_coin_shares(), vec![2]);
+        assert!(state.released_coins.contains(&2));
+        state.certify_vertex(AggregatedCoA { epoch: 0, batch_hash: r1[3], aggregated_signature: vec![], signer_bitmap: SignerBitmap::from_ids(4, 0..4).unwrap() });
+
+        // Round 2 is supported, but nothing commits before its coin
+        for round in 2..=3 {
+            for h in add_round(&mut state, round, &[0, 1, 2, 3]) {
+                state.certify_vertex(AggregatedCoA { epoch: 0, batch_hash: h, aggregated_signature: vec![], signer_bitmap: SignerBitmap::from_ids(4, 0..4).unwrap() });
+            }
+        }
+        assert_eq!((state.anchor_for_round(2), state.dag.committed_round), (None, 0));
+        // A late coin sends our share again rather than a skip vote
+        assert_eq!(state.take_pending_coin_shares(), vec![4]);
+        state.on_event(Event::Timeout(2));
+        assert_eq!(state.take_pending_coin_shares(), vec![2]);
+        assert!(state.take_pending_skip_votes().is_empty());
+
+        let shares: Vec<_> = (0..2).map(|id| match coin_share(&state, id, 2) {
+            Event::CoinShareReceived(_, _, id, sig) => (id, sig),
+            _ => unreachable!(),
+        }).collect();
+        let coin = state.committee.coin.combine(&state.coin_message(2), &shares).unwrap();
+        reveal_coin(&mut state, 2);
+        assert_eq!(state.anchor_seed(2), Some(derive_vrf_seed(vec![coin], 2)));
+        assert_eq!(state.dag.committed_round, 2);
+    }
+
+    #[test]
+    fn test_anchor_election_agrees_across_nodes() {
+        let mut a = node(0);
+        let mut b = node(3);
+        // Same vertices, delivered and certified in a different order
+        for round in 1..=5 {
+            let ra = add_round(&mut a, round, &[0, 1, 2, 3]);
+            let rb = add_round(&mut b, round, &[3, 2, 1, 0]);
+            certify_all(&mut a, &ra);
+            certify_all(&mut b, &rb);
+        }
+
+        assert_eq!(a.dag.committed_round, 4);
+        assert_eq!(a.committed_anchors, b.committed_anchors);
+        assert_eq!(a.committed_log, b.committed_log);
+        for r in 1..=8 {
+            assert_eq!(a.anchor_for_round(r), b.anchor_for_round(r));
+        }
+    }
+}
@@ -1,6 +1,8 @@
 use ed25519_dalek::{Signature, VerifyingKey, Verifier};
 use blake3::Hasher;
-use crate::types::{Hash, Signature as SigType};
+use crate::types::{BlsPublicKey, Hash, Signature as SigType, ValidatorId};
+use crate::coin::CoinDealing;
+use crate::committee::ValidatorInfo;
 
 pub fn hash(data: &[u8]) -> Hash {
     let mut hasher = Hasher::new();
@@ -15,13 +17,136 @@ pub fn verify_signature(msg: &[u8], sig: &[u8], public_key: &VerifyingKey) -> bo
     false
 }
 
-/// seed_r = Hash(sorted(CoA_signatures || round_number))
+/// H(epoch || round || author || batch_hash || parents || reconfiguration), over
+/// the fields themselves rather than an encoding, so every node derives the same
+/// hash for a vertex.
 pub fn hash_vertex(vertex: &crate::types::Vertex) -> Hash {
-    use rkyv::ser::serializers::AllocSerializer;
-    use rkyv::ser::Serializer;
-    let mut ser = AllocSerializer::<1024>::default();
-    ser.serialize_value(vertex).unwrap();
-    hash(&ser.into_serializer().into_inner())
+    let mut hasher = Hasher::new();
+    hasher.update(b"vertex");
+    hasher.update(&vertex.epoch.to_le_bytes());
+    hasher.update(&vertex.round.to_le_bytes());
+    hasher.update(&vertex.author.to_le_bytes());
+    hasher.update(&vertex.batch_hash);
+    hasher.update(&(vertex.parents.len() as u32).to_le_bytes());
+    for parent in &vertex.parents {
+        hasher.update(parent);
+    }
+    match &vertex.reconfiguration {
+        None => { hasher.update(&[0]); }
+        Some(proposal) => {
+            hasher.update(&[1]);
+            hasher.update(&hash_committee(proposal.epoch, &proposal.validators, &proposal.coin));
+        }
+    }
+    *hasher.finalize().as_bytes()
+}
+
+/// H(epoch || validators || coin), over every field of every validator and of
+/// the coin dealing.
+pub fn hash_committee(epoch: u64, validators: &[ValidatorInfo], coin: &CoinDealing) -> Hash {
+    let mut hasher = Hasher::new();
+    hasher.update(b"committee");
+    hasher.update(&epoch.to_le_bytes());
+    hasher.update(&(validators.len() as u32).to_le_bytes());
+    for v in validators {
+        hasher.update(&v.id.to_le_bytes());
+        for field in [&v.bls_public_key[..], &v.bls_proof_of_possession, &v.ed25519_public_key, v.address.as_bytes()] {
+            hasher.update(&(field.len() as u32).to_le_bytes());
+            hasher.update(field);
+        }
+        hasher.update(&v.voting_power.to_le_bytes());
+    }
+    let shares = coin.shares.iter().flat_map(|s| [&s.ephemeral, &s.ciphertext]);
+    hasher.update(&(coin.commitments.len() as u32).to_le_bytes());
+    for field in coin.commitments.iter().chain(shares) {
+        hasher.update(&(field.len() as u32).to_le_bytes());
+        hasher.update(field);
+    }
+    *hasher.finalize().as_bytes()
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// Where a signature counts: one epoch of one chain.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SigningContext {
+    pub chain_id: String,
+    pub epoch: u64,
+}
+
+/// What a signature is for. BLS keys only ever sign `SigningContext::message`
+/// of one of these, so a signature made for one purpose, chain or epoch is
+/// worthless for any other.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SigningIntent {
+    /// Vote for a vertex, by `hash_vertex`; votes aggregate into its certificate.
+    VertexCertificate(Hash),
+    /// Vote to skip the anchor of `round`, held by `anchor`.
+    SkipVote { round: u64, anchor: ValidatorId },
+    /// Commitment to the state committed up to `round`.
+    Checkpoint { round: u64, digest: Hash },
+    /// Proof of possession of a BLS public key.
+    ProofOfPossession(BlsPublicKey),
+    /// Share of the coin that elects the anchor of `round`.
+    AnchorCoin { round: u64 },
+}
+
+/// Bytes under a BLS signature. Only `SigningContext::message` builds one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SigningMessage(Vec<u8>);
+
+impl SigningMessage {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl SigningContext {
+    /// "sublyne" || intent tag || chain id || epoch || intent fields, with
+    /// variable-length fields length-prefixed and integers little-endian, so
+    /// distinct (context, intent) pairs never encode alike.
+    pub fn message(&self, intent: &SigningIntent) -> SigningMessage {
+        let tag: u8 = match intent {
+            SigningIntent::VertexCertificate(_) => 0,
+            SigningIntent::SkipVote { .. } => 1,
+            SigningIntent::Checkpoint { .. } => 2,
+            SigningIntent::ProofOfPossession(_) => 3,
+            SigningIntent::AnchorCoin { .. } => 4,
+        };
+        let mut out = b"sublyne".to_vec();
+        out.push(tag);
+        out.extend((self.chain_id.len() as u32).to_le_bytes());
+        out.extend(self.chain_id.as_bytes());
+        out.extend(self.epoch.to_le_bytes());
+        match intent {
+            SigningIntent::VertexCertificate(vertex) => out.extend(vertex),
+            SigningIntent::SkipVote { round, anchor } => {
+                out.extend(round.to_le_bytes());
+                out.extend(anchor.to_le_bytes());
+            }
+            SigningIntent::Checkpoint { round, digest } => {
+                out.extend(round.to_le_bytes());
+                out.extend(digest);
+            }
+            SigningIntent::ProofOfPossession(public_key) => {
+                out.extend((public_key.len() as u32).to_le_bytes());
+                out.extend(public_key);
+            }
+            SigningIntent::AnchorCoin { round } => out.extend(round.to_le_bytes()),
+        }
+        SigningMessage(out)
+    }
 }
 
 pub fn derive_vrf_seed(mut signatures: Vec<SigType>, round: u64) -> Hash {
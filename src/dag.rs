@@ -1,37 +1,69 @@
 use std::collections::{HashMap, HashSet};
-use crate::types::{Vertex, Hash, ValidatorId, CoA, CertifiedVertex};
-use crate::crypto::{hash, vrf_sort_key};
+use std::sync::Arc;
+use crate::committee::Committee;
+use crate::types::{Vertex, Hash, AggregatedCoA, AggregatedCertifiedVertex};
+use crate::crypto::vrf_sort_key;
 
 pub struct Dag {
     pub vertices: HashMap<Hash, Vertex>,
-    pub certs: HashMap<Hash, CoA>,
+    /// Canonical certificate per vertex: the O(1) BLS aggregate.
+    pub certs: HashMap<Hash, AggregatedCoA>,
     pub round_to_vertices: HashMap<u64, Vec<Hash>>,
+    /// Round of the last committed anchor. Everything at or below it that is
+    /// reachable from a committed anchor has a fixed position in the total order.
     pub committed_round: u64,
-    pub n: usize,
-    pub f: usize,
+    /// Vertices already emitted by `aether_sort` for a committed anchor.
+    pub ordered: HashSet<Hash>,
+    pub max_certified_round: u64,
+    /// Genesis round of the committee's epoch: 1, or the round after the last
+    /// epoch's boundary.
+    pub first_round: u64,
+    pub committee: Arc<Committee>,
 }
 
 impl Dag {
-    pub fn new(n: usize, f: usize) -> Self {
+    pub fn new(committee: Arc<Committee>) -> Self {
+        Self::starting_at(committee, 1)
+    }
+
+    /// Empty DAG for an epoch whose vertices start at `first_round`.
+    pub fn starting_at(committee: Arc<Committee>, first_round: u64) -> Self {
         Self {
             vertices: HashMap::new(),
             certs: HashMap::new(),
             round_to_vertices: HashMap::new(),
-            committed_round: 0,
-            n,
-            f,
+            committed_round: first_round - 1,
+            ordered: HashSet::new(),
+            max_certified_round: first_round - 1,
+            first_round,
+            committee,
         }
     }
 
-    pub fn validate_vertex(&self, vertex: &Vertex, parent_hashes: &[Hash]) -> bool {
-        // 1. parents.len() == n - f
-        if parent_hashes.len() != (self.n - self.f) {
+    pub fn validate_vertex(&self, vertex: &Vertex) -> bool {
+        // 0. Built in this epoch; a proposed committee must be usable and for the next one
+        if vertex.epoch != self.committee.epoch || vertex.round < self.first_round {
+            return false;
+        }
+        if let Some(proposal) = &vertex.reconfiguration {
+            if proposal.epoch != vertex.epoch + 1 || self.committee.next(proposal.epoch, proposal.validators.clone(), (*proposal.coin).clone()).is_err() {
+                return false;
+            }
+        }
+
+        // Genesis round has no parents
+        if vertex.round == self.first_round {
+            return vertex.parents.is_empty();
+        }
+
+        // 1. No more parents than validators
+        if vertex.parents.len() > self.committee.size() {
             return false;
         }
 
         // 2. All authors distinct and all parents exist and are certified
         let mut authors = HashSet::new();
-        for p_hash in parent_hashes {
+        for p_hash in &vertex.parents {
             if let Some(p_vertex) = self.vertices.get(p_hash) {
                 // 3. All parents from round r-1
                 if p_vertex.round != vertex.round - 1 {
@@ -48,16 +80,56 @@ impl Dag {
                 return false; // Parent missing
             }
         }
-        true
+        // 5. Parent authors hold a stake quorum
+        self.committee.stake_of(authors) >= self.committee.quorum_threshold()
+    }
+
+    pub fn insert_certified(&mut self, cv: AggregatedCertifiedVertex, v_hash: Hash) {
+        if self.vertices.insert(v_hash, cv.vertex.clone()).is_none() {
+            self.round_to_vertices.entry(cv.vertex.round).or_default().push(v_hash);
+        }
+        self.certs.insert(v_hash, cv.agg_coa);
+        self.max_certified_round = self.max_certified_round.max(cv.vertex.round);
     }
 
-    pub fn insert_certified(&mut self, cv: CertifiedVertex, v_hash: Hash) {
-        self.vertices.insert(v_hash, cv.vertex.clone());
-        self.certs.insert(v_hash, cv.coa);
-        self.round_to_vertices.entry(cv.vertex.round).or_default().push(v_hash);
+    /// True once every parent of `vertex` is certified, i.e. `validate_vertex`
+    /// can give a final answer.
+    pub fn parents_ready(&self, vertex: &Vertex) -> bool {
+        vertex.parents.iter().all(|h| self.certs.contains_key(h))
+    }
+
+    /// Hashes of every certified vertex of `round`, as parents for a proposal in round+1.
+    pub fn certified_in_round(&self, round: u64) -> Vec<Hash> {
+        self.round_to_vertices.get(&round)
+            .map(|hashes| hashes.iter().filter(|h| self.certs.contains_key(*h)).copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// True if `to` is in the causal history of `from` (or is `from` itself).
+    pub fn has_path(&self, from: &Hash, to: &Hash) -> bool {
+        let Some(target_round) = self.vertices.get(to).map(|v| v.round) else {
+            return false;
+        };
+        let mut stack = vec![*from];
+        let mut visited = HashSet::new();
+        while let Some(current) = stack.pop() {
+            if current == *to {
+                return true;
+            }
+            if !visited.insert(current) {
+                continue;
+            }
+            if let Some(vertex) = self.vertices.get(&current) {
+                // Parents only ever point one round down, so stop once we pass the target.
+                if vertex.round > target_round {
+                    stack.extend(vertex.parents.iter().copied());
+                }
+            }
+        }
+        false
     }
 
-    pub fn aether_sort(&self, anchor_hash: &Hash, seed: &Hash, dag_lookup: &HashMap<u64, Vec<Hash>>) -> Vec<Hash> {
+    pub fn aether_sort(&self, anchor_hash: &Hash, seed: &Hash) -> Vec<Hash> {
         let mut reachable = Vec::new();
         let mut stack = vec![*anchor_hash];
         let mut visited = HashSet::new();
@@ -65,15 +137,10 @@ impl Dag {
         while let Some(current) = stack.pop() {
             if visited.insert(current) {
                 if let Some(vertex) = self.vertices.get(&current) {
-                    if vertex.round > self.committed_round {
+                    // Causal histories of earlier anchors are already in the log.
+                    if !self.ordered.contains(&current) {
                         reachable.push(current);
-                        if vertex.round > 0 {
-                            for &idx in &vertex.parent_indices {
-                                if let Some(p_hash) = dag_lookup.get(&(vertex.round - 1)).and_then(|list| list.get(idx as usize)) {
-                                    stack.push(*p_hash);
-                                }
-                            }
-                        }
+                        stack.extend(vertex.parents.iter().copied());
                     }
                 }
             }
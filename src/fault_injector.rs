@@ -65,7 +65,7 @@ impl FaultInjector {
         let mut conflicting = original.clone();
         // Modify the batch_hash to create a conflicting vertex
         let mut new_hash = original.batch_hash;
-        new_hash[0] = rand::thread_rng().gen::<u8>(); // Flip first byte
+        new_hash[0] = new_hash[0].wrapping_add(rand::thread_rng().gen_range(1..=255)); // Flip first byte
         conflicting.batch_hash = new_hash;
         conflicting
     }
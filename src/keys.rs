@@ -0,0 +1,146 @@
+// Validator key files: what `sublyne keygen` writes and `sublyne node` reads
+// A key directory holds the two secret keys in encrypted keystores plus public.json for the genesis author
+
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use ed25519_dalek::SigningKey;
+use zeroize::Zeroizing;
+use crate::bls_crypto::BlsSecretKey;
+use crate::keystore::{KeyKind, Keystore};
+use crate::types::{BlsPublicKey, BlsSignature};
+
+const BLS_KEYSTORE_FILE: &str = "bls.keystore.json";
+const ED25519_KEYSTORE_FILE: &str = "ed25519.keystore.json";
+const PUBLIC_KEYS_FILE: &str = "public.json";
+
+/// Unlocked secret keys. Both zeroize their key material on drop.
+pub struct ValidatorKeys {
+    pub bls: BlsSecretKey,
+    pub ed25519: SigningKey,
+}
+
+/// The half of a key directory that is shared: `sublyne genesis` reads these.
+/// The proof of possession only holds on `chain_id`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PublicKeys {
+    pub chain_id: String,
+    #[serde(with = "crate::committee::hex_bytes")]
+    pub bls_public_key: BlsPublicKey,
+    #[serde(with = "crate::committee::hex_bytes")]
+    pub bls_proof_of_possession: BlsSignature,
+    #[serde(with = "crate::committee::hex_bytes")]
+    pub ed25519_public_key: Vec<u8>,
+}
+
+impl ValidatorKeys {
+    pub fn generate<R: rand::RngCore>(rng: &mut R) -> Self {
+        let mut seed = [0u8; 32];
+        rng.fill_bytes(&mut seed);
+        Self { bls: BlsSecretKey::generate(rng), ed25519: SigningKey::from_bytes(&seed) }
+    }
+
+    pub fn public(&self, chain_id: &str) -> PublicKeys {
+        PublicKeys {
+            chain_id: chain_id.to_string(),
+            bls_public_key: self.bls.public_key(),
+            bls_proof_of_possession: self.bls.prove_possession(chain_id),
+            ed25519_public_key: self.ed25519.verifying_key().to_bytes().to_vec(),
+        }
+    }
+
+    /// Writes the key directory for joining `chain_id`, creating it if needed,
+    /// with both secret keys encrypted under `password`. Never overwrites
+    /// existing keys.
+    pub fn save(&self, dir: impl AsRef<Path>, chain_id: &str, password: &[u8]) -> Result<(), String> {
+        let dir = dir.as_ref();
+        let public = self.public(chain_id);
+        let bls_secret = Zeroizing::new(self.bls.to_bytes());
+        let ed25519_secret = Zeroizing::new(self.ed25519.to_bytes());
+        let bls = Keystore::encrypt(KeyKind::Bls, bls_secret.as_ref(), public.bls_public_key.clone(), password, &mut rand::rngs::OsRng)?;
+        let ed25519 = Keystore::encrypt(KeyKind::Ed25519, ed25519_secret.as_ref(), public.ed25519_public_key.clone(), password, &mut rand::rngs::OsRng)?;
+        std::fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+        bls.save(dir.join(BLS_KEYSTORE_FILE))?;
+        ed25519.save(dir.join(ED25519_KEYSTORE_FILE))?;
+        let path = dir.join(PUBLIC_KEYS_FILE);
+        std::fs::write(&path, serde_json::to_string_pretty(&public).unwrap())
+            .map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// Decrypts both keystores of a key directory, checking each secret
+    /// against the public key stored beside it.
+    pub fn unlock(dir: impl AsRef<Path>, password: &[u8]) -> Result<Self, String> {
+        let dir = dir.as_ref();
+        Ok(Self { bls: unlock_bls(dir, password)?, ed25519: unlock_ed25519(dir, password)? })
+    }
+}
+
+/// The consensus signing key alone, for a signer process.
+pub fn unlock_bls(dir: impl AsRef<Path>, password: &[u8]) -> Result<BlsSecretKey, String> {
+    let path = dir.as_ref().join(BLS_KEYSTORE_FILE);
+    let keystore = open(&path, KeyKind::Bls)?;
+    let secret = keystore.decrypt(password).map_err(|e| format!("{}: {}", path.display(), e))?;
+    BlsSecretKey::from_bytes(&secret)
+        .filter(|sk| sk.public_key() == keystore.public_key)
+        .ok_or_else(|| format!("{}: secret does not match the public key", path.display()))
+}
+
+/// The network identity key alone, for a node whose BLS key is held by a signer.
+pub fn unlock_ed25519(dir: impl AsRef<Path>, password: &[u8]) -> Result<SigningKey, String> {
+    let path = dir.as_ref().join(ED25519_KEYSTORE_FILE);
+    let keystore = open(&path, KeyKind::Ed25519)?;
+    let secret = keystore.decrypt(password).map_err(|e| format!("{}: {}", path.display(), e))?;
+    <&[u8; 32]>::try_from(secret.as_slice()).ok()
+        .map(SigningKey::from_bytes)
+        .filter(|sk| sk.verifying_key().as_bytes()[..] == keystore.public_key[..])
+        .ok_or_else(|| format!("{}: secret does not match the public key", path.display()))
+}
+
+fn open(path: &Path, kind: KeyKind) -> Result<Keystore, String> {
+    let keystore = Keystore::load(path)?;
+    if keystore.kind != kind {
+        return Err(format!("{}: expected a {:?} keystore, found {:?}", path.display(), kind, keystore.kind));
+    }
+    Ok(keystore)
+}
+
+impl PublicKeys {
+    /// Reads `public.json`, given either the file or the key directory holding it.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let path = if path.is_dir() { path.join(PUBLIC_KEYS_FILE) } else { path.to_path_buf() };
+        let json = std::fs::read_to_string(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        serde_json::from_str(&json).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::OsRng;
+    use crate::committee::LOCALHOST_CHAIN_ID;
+
+    #[test]
+    fn test_key_directory_round_trip() {
+        let dir = std::env::temp_dir().join(format!("sublyne-keys-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let keys = ValidatorKeys::generate(&mut OsRng);
+        keys.save(&dir, LOCALHOST_CHAIN_ID, b"pw").unwrap();
+
+        let loaded = ValidatorKeys::unlock(&dir, b"pw").unwrap();
+        assert_eq!(loaded.public(LOCALHOST_CHAIN_ID), keys.public(LOCALHOST_CHAIN_ID));
+        assert_eq!(loaded.ed25519.to_bytes(), keys.ed25519.to_bytes());
+        assert_eq!(PublicKeys::load(&dir).unwrap(), keys.public(LOCALHOST_CHAIN_ID));
+        assert!(ValidatorKeys::unlock(&dir, b"not pw").is_err());
+
+        // No secret is on disk in the clear
+        let secret = crate::crypto::to_hex(&keys.bls.to_bytes());
+        for entry in std::fs::read_dir(&dir).unwrap() {
+            assert!(!std::fs::read_to_string(entry.unwrap().path()).unwrap().contains(&secret));
+        }
+
+        // Existing keys are never replaced
+        assert!(ValidatorKeys::generate(&mut OsRng).save(&dir, LOCALHOST_CHAIN_ID, b"pw").is_err());
+        assert_eq!(ValidatorKeys::unlock(&dir, b"pw").unwrap().public(LOCALHOST_CHAIN_ID), keys.public(LOCALHOST_CHAIN_ID));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
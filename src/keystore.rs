@@ -0,0 +1,173 @@
+// Encrypted keystores for validator secret keys
+// Versioned JSON in the spirit of EIP-2335: argon2id turns the password into a key, XChaCha20-Poly1305 seals the secret
+
+use std::path::Path;
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
+use crate::committee::hex_bytes;
+
+pub const KEYSTORE_VERSION: u32 = 1;
+
+const KDF_FUNCTION: &str = "argon2id";
+const CIPHER_FUNCTION: &str = "xchacha20-poly1305";
+// Memory in KiB; tests use a cheap setting so they stay fast in debug builds
+const M_COST: u32 = if cfg!(test) { 256 } else { 64 * 1024 };
+const T_COST: u32 = 3;
+const P_COST: u32 = 1;
+const SALT_LEN: usize = 32;
+const NONCE_LEN: usize = 24;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyKind {
+    Bls,
+    Ed25519,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct KdfParams {
+    pub function: String,
+    /// Memory in KiB.
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+    #[serde(with = "hex_bytes")]
+    pub salt: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CipherParams {
+    pub function: String,
+    #[serde(with = "hex_bytes")]
+    pub nonce: Vec<u8>,
+    /// Secret key followed by the 16-byte tag. The tag also covers version,
+    /// kind and public key, so none of them can be swapped.
+    #[serde(with = "hex_bytes")]
+    pub ciphertext: Vec<u8>,
+}
+
+/// One secret key, encrypted under a password.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Keystore {
+    pub version: u32,
+    pub kind: KeyKind,
+    #[serde(with = "hex_bytes")]
+    pub public_key: Vec<u8>,
+    pub kdf: KdfParams,
+    pub cipher: CipherParams,
+}
+
+impl Keystore {
+    pub fn encrypt<R: RngCore>(kind: KeyKind, secret: &[u8], public_key: Vec<u8>, password: &[u8], rng: &mut R) -> Result<Self, String> {
+        let mut salt = vec![0u8; SALT_LEN];
+        let mut nonce = vec![0u8; NONCE_LEN];
+        rng.fill_bytes(&mut salt);
+        rng.fill_bytes(&mut nonce);
+        let kdf = KdfParams { function: KDF_FUNCTION.into(), m_cost: M_COST, t_cost: T_COST, p_cost: P_COST, salt };
+        let mut keystore = Self {
+            version: KEYSTORE_VERSION,
+            kind,
+            public_key,
+            kdf,
+            cipher: CipherParams { function: CIPHER_FUNCTION.into(), nonce, ciphertext: Vec::new() },
+        };
+        let cipher = keystore.cipher(password)?;
+        let payload = Payload { msg: secret, aad: &keystore.header() };
+        keystore.cipher.ciphertext = cipher.encrypt(XNonce::from_slice(&keystore.cipher.nonce), payload)
+            .map_err(|_| "encryption failed".to_string())?;
+        Ok(keystore)
+    }
+
+    /// The secret key, if `password` is right and nothing was tampered with.
+    pub fn decrypt(&self, password: &[u8]) -> Result<Zeroizing<Vec<u8>>, String> {
+        if self.version != KEYSTORE_VERSION {
+            return Err(format!("unsupported keystore version {}", self.version));
+        }
+        if self.kdf.function != KDF_FUNCTION || self.cipher.function != CIPHER_FUNCTION {
+            return Err(format!("unsupported keystore functions {} / {}", self.kdf.function, self.cipher.function));
+        }
+        if self.cipher.nonce.len() != NONCE_LEN {
+            return Err("keystore nonce has the wrong length".into());
+        }
+        let cipher = self.cipher(password)?;
+        let payload = Payload { msg: &self.cipher.ciphertext, aad: &self.header() };
+        cipher.decrypt(XNonce::from_slice(&self.cipher.nonce), payload)
+            .map(Zeroizing::new)
+            .map_err(|_| "wrong password or corrupted keystore".to_string())
+    }
+
+    /// Cipher keyed by argon2id(password, salt).
+    fn cipher(&self, password: &[u8]) -> Result<XChaCha20Poly1305, String> {
+        let params = Params::new(self.kdf.m_cost, self.kdf.t_cost, self.kdf.p_cost, Some(32))
+            .map_err(|e| format!("invalid keystore kdf parameters: {}", e))?;
+        let mut key = Zeroizing::new([0u8; 32]);
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(password, &self.kdf.salt, key.as_mut())
+            .map_err(|e| format!("key derivation failed: {}", e))?;
+        Ok(XChaCha20Poly1305::new(key.as_ref().into()))
+    }
+
+    /// Associated data: everything in the clear that says what the secret is.
+    fn header(&self) -> Vec<u8> {
+        let mut header = b"sublyne-keystore".to_vec();
+        header.extend(self.version.to_le_bytes());
+        header.push(self.kind as u8);
+        header.extend((self.public_key.len() as u32).to_le_bytes());
+        header.extend(&self.public_key);
+        header
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let json = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        serde_json::from_str(&json).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// Owner-only on unix; fails if the file already exists.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), String> {
+        use std::io::Write;
+        let path = path.as_ref();
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options.open(path)
+            .and_then(|mut file| file.write_all(serde_json::to_string_pretty(self).unwrap().as_bytes()))
+            .map_err(|e| format!("{}: {}", path.display(), e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::OsRng;
+
+    #[test]
+    fn test_keystore_round_trip_and_tampering() {
+        let secret = [7u8; 32];
+        let keystore = Keystore::encrypt(KeyKind::Ed25519, &secret, vec![1; 32], b"hunter2", &mut OsRng).unwrap();
+        assert!(!keystore.cipher.ciphertext.windows(32).any(|w| w == secret));
+
+        let json = serde_json::to_string(&keystore).unwrap();
+        let loaded: Keystore = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded.decrypt(b"hunter2").unwrap().as_slice(), &secret);
+        assert!(loaded.decrypt(b"hunter3").is_err());
+
+        // The clear fields are authenticated along with the secret
+        let tampered = [
+            Keystore { public_key: vec![2; 32], ..keystore.clone() },
+            Keystore { kind: KeyKind::Bls, ..keystore.clone() },
+            Keystore { version: 2, ..keystore.clone() },
+        ];
+        for keystore in tampered {
+            assert!(keystore.decrypt(b"hunter2").is_err());
+        }
+        let mut flipped = keystore.clone();
+        flipped.cipher.ciphertext[0] ^= 1;
+        assert!(flipped.decrypt(b"hunter2").is_err());
+    }
+}
@@ -1,11 +1,23 @@
 pub mod types;
+pub mod bitset;
+pub mod committee;
 pub mod crypto;
 pub mod bls_crypto;  // Phase E.4: BLS12-381 Signature Aggregation
+pub mod coin;
 pub mod dag;
 pub mod net;
 pub mod consensus;
+pub mod pacemaker;
+pub mod commit_log;
+pub mod keys;
+pub mod keystore;
+pub mod signer;
+pub mod slashing;
+pub mod quic;
+pub mod transport;
 
 // SciFest Feature Additions
 pub mod geo_latency;     // Multi-Region Geo-Latency Simulation
+pub mod simulator;       // Deterministic network simulation over the geo-latency matrix
 pub mod fault_injector;  // Byzantine Fault Injection & Resilience Testing
 pub mod ml_predictor;    // ML-Based Adaptive Batching
@@ -1,12 +1,20 @@
 mod consensus;
 mod types;
+mod bitset;
 mod net;
 mod dag;
 mod crypto;
+mod bls_crypto;
+mod coin;
+mod committee;
+#[cfg(test)]
+mod fault_injector;  // consensus tests inject equivocations
 
 use crate::consensus::ConsensusState;
 use crate::types::{Event, Vertex, CoA, Message, Hash};
 use crate::net::{TcpNetwork, NetworkHandle};
+use crate::committee::Committee;
+use crate::bls_crypto::BlsSecretKey;
 use std::time::{Instant, Duration};
 use std::env;
 use std::collections::HashMap;
@@ -68,23 +76,27 @@ async fn run_surface_test(label: &str, batch_window: usize, n: usize, port_offse
         csprng.fill_bytes(&mut bytes);
         keys.push(SigningKey::from_bytes(&bytes));
     }
+    let bls_pks = (0..n).map(|_| {
+        let sk = BlsSecretKey::generate(&mut csprng);
+        (sk.public_key(), sk.prove_possession(crate::committee::LOCALHOST_CHAIN_ID))
+    }).collect();
+    let ed_pks = keys.iter().map(|k| k.verifying_key().to_bytes()).collect();
+    let committee = Arc::new(Committee::localhost(bls_pks, ed_pks, port_offset).expect("invalid committee"));
 
     let latencies = Arc::new(Mutex::new(Vec::<u128>::new()));
     let mut futures = Vec::new();
 
     for i in 0..n {
         let node_id = i as u32;
-        let mut peer_addrs = HashMap::new();
-        for j in 0..n { if i != j { peer_addrs.insert(j as u32, format!("127.0.0.1:{}", port_offset + j as u16)); } }
-        let listen_addr = format!("127.0.0.1:{}", port_offset + i as u16);
+        let committee = committee.clone();
         let sk = SigningKey::from_bytes(&keys[i].to_bytes());
         let latencies_clone = latencies.clone();
 
         futures.push(tokio::spawn(async move {
             let (event_tx, mut event_rx) = mpsc::channel(1_000_000);
-            let network = TcpNetwork::new(node_id, listen_addr, peer_addrs);
+            let network = TcpNetwork::new(node_id, &committee, SigningKey::from_bytes(&sk.to_bytes()));
             let net_handle: Arc<NetworkHandle> = network.start(event_tx).await;
-            let mut state = ConsensusState::new(node_id, n);
+            let mut state = ConsensusState::new(node_id, committee.clone());
 
             tokio::time::sleep(Duration::from_secs(2)).await;
 
@@ -119,10 +131,12 @@ async fn run_surface_test(label: &str, batch_window: usize, n: usize, port_offse
                     // Phase D.1: Increase proposal density (6 vertices per round)
                     for _ in 0..6 {
                         let v = Vertex { 
+                            epoch: state.epoch,
                             round: state.round, 
                             author: node_id, 
                             batch_hash: [0u8; 32], 
-                            parent_indices: if state.round > 1 { (0..(n as u32)).collect() } else { vec![] }
+                            parents: if state.round > 1 { state.dag.certified_in_round(state.round - 1) } else { vec![] },
+                            reconfiguration: None,
                         };
                         if node_id == 0 { round_starts.insert(v.round, Instant::now()); }
                         
@@ -1,27 +1,56 @@
 // Phase F.2: Robust BLS Batch Verification using ConsensusState
 mod consensus;
 mod types;
+mod bitset;
 mod net;
 mod dag;
 mod crypto;
 mod bls_crypto;
+mod coin;
+mod pacemaker;
+mod commit_log;
+mod committee;
+mod keys;
+mod keystore;
+mod signer;
+mod slashing;
+mod quic;
+mod transport;
+#[cfg(test)]
+mod fault_injector;  // consensus tests inject equivocations
 
 use crate::consensus::ConsensusState;
-use crate::types::{Event, Vertex, CoA, Message, Hash, ValidatorId};
-use crate::net::{TcpNetwork, NetworkHandle};
-use crate::bls_crypto::{BlsSecretKey, aggregate_signatures_with_metrics, verify_aggregated_batch_with_metrics};
+use crate::types::{Event, Vertex, CoA, AggregatedCoA, Message, Hash, ValidatorId, ReconfigurationProposal, Request, Response};
+use crate::net::TcpNetwork;
+use crate::quic::QuicNetwork;
+use crate::transport::{ChannelNetwork, Transport, TransportHandle};
+use crate::pacemaker::{Pacemaker, TokioClock};
+use crate::coin::CoinDealing;
+use crate::commit_log::{CommitLog, CommitStream};
+use crate::committee::Committee;
+use crate::committee::{ValidatorInfo, LOCALHOST_CHAIN_ID};
+use crate::bls_crypto::{aggregate_signatures_with_metrics, verify_aggregated_batch_with_metrics};
+use crate::keys::{unlock_bls, unlock_ed25519, PublicKeys, ValidatorKeys};
+use crate::signer::{ConsensusSigner, LocalSigner, RemoteSigner};
+use crate::slashing::{SlashingDb, SlashingInterchange, SLASHING_DB_FILE};
 use std::time::{Instant, Duration};
 use std::env;
 use std::collections::HashMap;
 use rand::rngs::OsRng;
 use tokio::sync::mpsc;
+use zeroize::Zeroizing;
+use ed25519_dalek::SigningKey;
 use std::sync::Arc;
 use parking_lot::Mutex;
-use rkyv::ser::serializers::AllocSerializer;
-use rkyv::ser::Serializer;
 
 const MAX_ROUND_DRIFT: u64 = 50;
 const VERIFICATION_WINDOW: usize = 200; // Allow more in-flight to sustain throughput on i9
+const ANCHOR_TIMEOUT: Duration = Duration::from_millis(500);
+const MAX_ANCHOR_TIMEOUT: Duration = Duration::from_secs(8);
+/// Longest to wait for a peer to send the certified parents we asked for.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(2);
+/// Longest an idle node sleeps when no anchor timer is armed.
+const IDLE_WAIT: Duration = Duration::from_millis(100);
 
 #[derive(Debug, Clone, Default)]
 struct CryptoMetrics {
@@ -55,6 +84,21 @@ impl DriftMetrics {
     }
 }
 
+/// Shared by the validators of one process; only the reporting one reads it.
+#[derive(Clone, Default)]
+struct Stats {
+    latencies: Arc<Mutex<Vec<u128>>>,
+    crypto: Arc<Mutex<CryptoMetrics>>,
+    drift: Arc<Mutex<DriftMetrics>>,
+}
+
+/// What a validator acts with: its consensus signer, which may be remote, and
+/// the network it reaches its peers over, not yet started.
+struct Validator<T: Transport> {
+    signer: Arc<dyn ConsensusSigner>,
+    network: T,
+}
+
 impl CryptoMetrics {
     fn report(&self, total_dur_micros: u64) -> String {
         let avg_v = if self.cert_count > 0 { self.bls_verify_micros as f64 / self.cert_count as f64 } else { 0.0 };
@@ -67,8 +111,259 @@ impl CryptoMetrics {
 
 fn main() {
     let args: Vec<String> = env::args().collect();
+    let result = match args.get(1).map(String::as_str) {
+        Some("keygen") => keygen(&args[2..]),
+        Some("genesis") => genesis(&args[2..]),
+        Some("node") => node(&args[2..]),
+        Some("signer") => signer(&args[2..]),
+        Some("commits") => commits(&args[2..]),
+        Some("slashing") => slashing(&args[2..]),
+        _ => local_testnet(&args),
+    };
+    if let Err(e) = result {
+        eprintln!("sublyne: {}", e);
+        std::process::exit(1);
+    }
+}
+
+/// Keystore password, read from the file named by `SUBLYNE_PASSWORD_FILE`.
+fn read_password() -> Result<Zeroizing<Vec<u8>>, String> {
+    let path = env::var("SUBLYNE_PASSWORD_FILE")
+        .map_err(|_| "set SUBLYNE_PASSWORD_FILE to a file holding the keystore password".to_string())?;
+    let mut password = Zeroizing::new(std::fs::read(&path).map_err(|e| format!("{}: {}", path, e))?);
+    // A trailing newline belongs to the file, not the password
+    if password.ends_with(b"\n") {
+        password.pop();
+        if password.ends_with(b"\r") {
+            password.pop();
+        }
+    }
+    if password.is_empty() {
+        return Err(format!("{}: password is empty", path));
+    }
+    Ok(password)
+}
+
+/// `sublyne keygen <key_dir> <chain_id>`: new BLS and ed25519 keys for one
+/// validator of `chain_id`, encrypted under the keystore password.
+fn keygen(args: &[String]) -> Result<(), String> {
+    let [dir, chain_id] = args else { return Err("usage: sublyne keygen <key_dir> <chain_id>".into()) };
+    let password = read_password()?;
+    let keys = ValidatorKeys::generate(&mut OsRng);
+    keys.save(dir, chain_id, &password)?;
+    println!("wrote keys to {} (share {}/public.json with the genesis author)", dir, dir);
+    Ok(())
+}
+
+/// `sublyne genesis <genesis.json> <chain_id> (<public.json> <address> <stake>)...`:
+/// the epoch-0 committee, ids in argument order. Prints the genesis hash every
+/// node must be started with.
+fn genesis(args: &[String]) -> Result<(), String> {
+    let usage = || "usage: sublyne genesis <genesis.json> <chain_id> (<public.json|key_dir> <host:port> <stake>)...".to_string();
+    let [out, chain_id, members @ ..] = args else { return Err(usage()) };
+    if members.is_empty() || !members.len().is_multiple_of(3) {
+        return Err(usage());
+    }
+    let mut validators = Vec::new();
+    for (id, member) in members.chunks(3).enumerate() {
+        let keys = PublicKeys::load(&member[0])?;
+        if keys.chain_id != *chain_id {
+            return Err(format!("{}: keys were published for chain {:?}", member[0], keys.chain_id));
+        }
+        let voting_power = member[2].parse().map_err(|_| format!("invalid stake {:?}", member[2]))?;
+        validators.push(ValidatorInfo {
+            id: id as ValidatorId,
+            bls_public_key: keys.bls_public_key,
+            bls_proof_of_possession: keys.bls_proof_of_possession,
+            ed25519_public_key: keys.ed25519_public_key,
+            address: member[1].clone(),
+            voting_power,
+        });
+    }
+    let recipients: Vec<_> = validators.iter().map(|v| v.bls_public_key.clone()).collect();
+    let committee = Committee::new(chain_id, validators, CoinDealing::deal(&mut OsRng, &recipients)?)?;
+    let genesis_hash = committee.save_genesis(out).map_err(|e| format!("{}: {}", out, e))?;
+    println!("wrote {} with {} validators", out, committee.size());
+    println!("genesis hash: {}", crate::crypto::to_hex(&genesis_hash));
+    Ok(())
+}
+
+/// Networks a validator can run on, chosen with `SUBLYNE_TRANSPORT`.
+enum TransportKind {
+    Tcp,
+    Quic,
+    /// In-process channels; only joins validators of one local testnet.
+    Memory,
+}
+
+fn transport_kind() -> Result<TransportKind, String> {
+    match env::var("SUBLYNE_TRANSPORT").as_deref() {
+        Err(_) | Ok("tcp") => Ok(TransportKind::Tcp),
+        Ok("quic") => Ok(TransportKind::Quic),
+        Ok("memory") => Ok(TransportKind::Memory),
+        Ok(other) => Err(format!("unknown SUBLYNE_TRANSPORT {:?} (expected tcp, quic or memory)", other)),
+    }
+}
+
+/// Slashing protection database of a key directory, created on first use.
+fn open_slashing_db(key_dir: &str) -> Result<SlashingDb, String> {
+    let path = std::path::Path::new(key_dir).join(SLASHING_DB_FILE);
+    SlashingDb::open(&path).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// `sublyne signer <socket> <key_dir>`: holds a validator's BLS key and signs
+/// for a node started with `<socket>`, refusing to sign conflicting votes.
+fn signer(args: &[String]) -> Result<(), String> {
+    let [socket, key_dir] = args else { return Err("usage: sublyne signer <socket> <key_dir>".into()) };
+    let chain_id = PublicKeys::load(key_dir)?.chain_id;
+    let key = unlock_bls(key_dir, &read_password()?)?;
+    let signer = Arc::new(LocalSigner::new(key, &chain_id, open_slashing_db(key_dir)?));
+
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+    runtime.block_on(async move {
+        let listener = tokio::net::UnixListener::bind(socket).map_err(|e| format!("{}: {}", socket, e))?;
+        println!("=== sublyne signer for {} on {} ===", chain_id, socket);
+        crate::signer::serve(listener, signer).await.map_err(|e| format!("{}: {}", socket, e))
+    })
+}
+
+/// `sublyne commits <socket> [from_index]`: follow the commit stream a node
+/// serves on `<socket>`, one line per committed sub-DAG.
+fn commits(args: &[String]) -> Result<(), String> {
+    let usage = || "usage: sublyne commits <socket> [from_index]".to_string();
+    let (socket, from_index) = match args {
+        [socket] => (socket, 0),
+        [socket, from] => (socket, from.parse().map_err(|_| usage())?),
+        _ => return Err(usage()),
+    };
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+    runtime.block_on(async move {
+        let mut stream = CommitStream::connect(socket, from_index).await.map_err(|e| format!("{}: {}", socket, e))?;
+        loop {
+            let sub_dag = stream.next().await.map_err(|e| format!("{}: {}", socket, e))?;
+            println!("{} epoch {} round {} anchor {} vertices {}",
+                sub_dag.index, sub_dag.epoch, sub_dag.round, crate::crypto::to_hex(&sub_dag.anchor), sub_dag.vertices.len());
+        }
+    })
+}
+
+/// `sublyne slashing (export|import) <key_dir> <interchange.json>`: move a
+/// validator's slashing protection records to or from another machine. Only
+/// run it while no node or signer is using the key directory.
+fn slashing(args: &[String]) -> Result<(), String> {
+    let usage = || "usage: sublyne slashing (export|import) <key_dir> <interchange.json>".to_string();
+    let [command, key_dir, file] = args else { return Err(usage()) };
+    let public = PublicKeys::load(key_dir)?;
+    let mut db = open_slashing_db(key_dir)?;
+    match command.as_str() {
+        "export" => {
+            let interchange = db.export(&public.chain_id, &public.bls_public_key);
+            std::fs::write(file, serde_json::to_string_pretty(&interchange).unwrap()).map_err(|e| format!("{}: {}", file, e))?;
+            println!("exported {} records to {}", interchange.records.len(), file);
+        }
+        "import" => {
+            let json = std::fs::read_to_string(file).map_err(|e| format!("{}: {}", file, e))?;
+            let interchange: SlashingInterchange = serde_json::from_str(&json).map_err(|e| format!("{}: {}", file, e))?;
+            let added = db.import(&interchange, &public.chain_id, &public.bls_public_key).map_err(|e| format!("{}: {}", file, e))?;
+            println!("imported {} new records into {} ({} in total)", added, key_dir, db.len());
+        }
+        _ => return Err(usage()),
+    }
+    Ok(())
+}
+
+/// `sublyne node <genesis.json> <genesis_hash> <key_dir> [commit_log|-] [signer_socket|-] [commit_socket]`:
+/// one validator of a network set up with `keygen` and `genesis`. Its keys are
+/// unlocked with the keystore password. With `signer_socket` the BLS key is
+/// held by a `sublyne signer` instead, and `key_dir` needs only the ed25519
+/// keystore that identifies the node to its peers. Peers are reached over TCP,
+/// or QUIC with `SUBLYNE_TRANSPORT=quic`. With `commit_socket` the node serves
+/// its commit stream there (see `sublyne commits`). A restarted node replays
+/// `commit_log` to its subscribers, but its consensus starts over at round 1,
+/// so it only rejoins a network that restarts with it. Its slashing protection
+/// database is kept, so up to the last round it signed in it votes only for
+/// vertices identical to the ones it voted for before. Round 1's, which have
+/// no parents, always are; a later round's depend on which parents were
+/// certified in time, and a restarted network stalls at the first round that
+/// comes out differently. To start such a network over, issue a new genesis.
+fn node(args: &[String]) -> Result<(), String> {
+    let usage = || "usage: sublyne node <genesis.json> <genesis_hash> <key_dir> [commit_log|-] [signer_socket|-] [commit_socket]".to_string();
+    let (genesis_path, expected, key_dir) = match args {
+        [genesis, hash, keys, ..] if args.len() <= 6 => (genesis, hash, keys),
+        _ => return Err(usage()),
+    };
+    let expected: Hash = crate::crypto::from_hex(expected).and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| format!("invalid genesis hash {:?}", expected))?;
+    let committee = Arc::new(Committee::load_genesis(genesis_path, &expected)?);
+    let transport = transport_kind()?;
+    if let TransportKind::Memory = transport {
+        return Err("the memory transport only joins the validators of a local testnet".into());
+    }
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(8)
+        .enable_all()
+        .build()
+        .unwrap();
+    let password = read_password()?;
+    let identity = unlock_ed25519(key_dir, &password)?;
+    let signer: Arc<dyn ConsensusSigner> = match args.get(4).filter(|socket| *socket != "-") {
+        Some(socket) => Arc::new(runtime.block_on(RemoteSigner::connect(socket))?),
+        None => {
+            let slashing_db = open_slashing_db(key_dir)?;
+            if let Some(round) = slashing_db.last_round(committee.epoch) {
+                eprintln!("{}: this key signed up to round {} before; it votes again only for the vertices it voted for then", key_dir, round);
+            }
+            Arc::new(LocalSigner::new(unlock_bls(key_dir, &password)?, &committee.chain_id, slashing_db))
+        }
+    };
+    let node_id = committee.id_of(&signer.public_key())
+        .ok_or_else(|| format!("{}: BLS key is not in the genesis committee", key_dir))?;
+    if committee.validators[node_id as usize].ed25519_public_key != identity.verifying_key().to_bytes() {
+        return Err(format!("{}: ed25519 key does not match validator {} in the genesis committee", key_dir, node_id));
+    }
+    let commit_log = match args.get(3).filter(|path| *path != "-") {
+        Some(path) => CommitLog::open(path).map_err(|e| format!("{}: {}", path, e))?,
+        None => CommitLog::in_memory(),
+    };
+    let commit_log = Arc::new(Mutex::new(commit_log));
+    if let Some(socket) = args.get(5) {
+        let listener = runtime.block_on(async { tokio::net::UnixListener::bind(socket) }).map_err(|e| format!("{}: {}", socket, e))?;
+        runtime.spawn(serve_commits(listener, commit_log.clone(), socket.clone()));
+    }
+
+    println!("=== sublyne validator {} of {} ({}) ===", node_id, committee.size(), committee.validators[node_id as usize].address);
+    match transport {
+        TransportKind::Quic => {
+            let validator = Validator { signer, network: QuicNetwork::new(node_id, &committee, identity) };
+            runtime.block_on(run_validator(node_id, validator, committee, commit_log, None, Stats::default(), true));
+        }
+        _ => {
+            let validator = Validator { signer, network: TcpNetwork::new(node_id, &committee, identity) };
+            runtime.block_on(run_validator(node_id, validator, committee, commit_log, None, Stats::default(), true));
+        }
+    }
+    Ok(())
+}
+
+/// Serve a node's commit stream until the socket fails.
+async fn serve_commits(listener: tokio::net::UnixListener, commit_log: Arc<Mutex<CommitLog>>, socket: String) {
+    if let Err(e) = crate::commit_log::serve(listener, commit_log).await {
+        eprintln!("commit stream {}: {}", socket, e);
+    }
+}
+
+/// Every validator in one process on localhost, with throwaway keys, joined
+/// by the transport `SUBLYNE_TRANSPORT` names.
+fn local_testnet(args: &[String]) -> Result<(), String> {
+    let transport = transport_kind()?;
     let n: usize = args.get(2).and_then(|s| s.parse().ok()).unwrap_or(4);
     let port_offset: u16 = args.get(3).and_then(|s| s.parse().ok()).unwrap_or(10000);
+    // Optional directory for per-node commit logs ("-" or absent: in memory),
+    // each served on a socket beside it. Keys are generated per run, so a log
+    // only matches the run that wrote it.
+    let commit_log_dir: Option<String> = args.get(4).filter(|dir| *dir != "-").cloned();
+    // Optional round at which every validator proposes an epoch without the last one
+    let leave_round: Option<u64> = args.get(5).and_then(|s| s.parse().ok());
     
     // Phase G.2: Dedicated worker pools
     // 3 crypto, 3 validator, 2 network = 8 total
@@ -79,192 +374,384 @@ fn main() {
         .unwrap();
     runtime.block_on(async move {
         println!("=== Aether-V2 Phase F.2: BLS Batch Verification (n={}) ===", n);
-        let mut bls_keys = Vec::new();
-        let mut bls_pks = Vec::new();
-        let mut rng = OsRng;
-        for _ in 0..n {
-            let sk = BlsSecretKey::generate(&mut rng);
-            bls_pks.push(sk.public_key());
-            bls_keys.push(sk);
+        let keys: Vec<_> = (0..n).map(|_| ValidatorKeys::generate(&mut OsRng)).collect();
+        let bls_pks = keys.iter().map(|k| (k.bls.public_key(), k.bls.prove_possession(LOCALHOST_CHAIN_ID))).collect();
+        let ed_pks = keys.iter().map(|k| k.ed25519.verifying_key().to_bytes()).collect();
+        let committee = Arc::new(Committee::localhost(bls_pks, ed_pks, port_offset).expect("invalid committee"));
+
+        let stats = Stats::default();
+        // Every validator proposes the same smaller committee, so its coin is dealt once here
+        let leave = match leave_round {
+            Some(round) => {
+                let validators = committee.validators[..n - 1].to_vec();
+                let recipients: Vec<_> = validators.iter().map(|v| v.bls_public_key.clone()).collect();
+                let coin = CoinDealing::deal(&mut OsRng, &recipients).expect("deal the next epoch's coin");
+                Some((round, ReconfigurationProposal { epoch: 1, validators, coin: Box::new(coin) }))
+            }
+            None => None,
+        };
+        let commit_logs = (0..n).map(|node_id| match &commit_log_dir {
+            Some(dir) => {
+                let log = Arc::new(Mutex::new(CommitLog::open(format!("{}/node-{}.log", dir, node_id)).expect("open commit log")));
+                let socket = format!("{}/node-{}.sock", dir, node_id);
+                let _ = std::fs::remove_file(&socket);
+                let listener = tokio::net::UnixListener::bind(&socket).expect("bind commit socket");
+                tokio::spawn(serve_commits(listener, log.clone(), socket));
+                log
+            }
+            None => Arc::new(Mutex::new(CommitLog::in_memory())),
+        }).collect();
+        let (signers, identities): (Vec<Arc<dyn ConsensusSigner>>, Vec<SigningKey>) = keys.into_iter()
+            .map(|keys| (Arc::new(LocalSigner::new(keys.bls, LOCALHOST_CHAIN_ID, SlashingDb::in_memory())) as _, keys.ed25519))
+            .unzip();
+
+        let tasks = match transport {
+            TransportKind::Tcp => {
+                let networks = identities.into_iter().enumerate().map(|(i, key)| TcpNetwork::new(i as ValidatorId, &committee, key)).collect();
+                spawn_validators(networks, signers, &committee, commit_logs, leave, &stats)
+            }
+            TransportKind::Quic => {
+                let networks = identities.into_iter().enumerate().map(|(i, key)| QuicNetwork::new(i as ValidatorId, &committee, key)).collect();
+                spawn_validators(networks, signers, &committee, commit_logs, leave, &stats)
+            }
+            TransportKind::Memory => spawn_validators(ChannelNetwork::mesh(&committee), signers, &committee, commit_logs, leave, &stats),
+        };
+        futures::future::join_all(tasks).await;
+    });
+    Ok(())
+}
+
+/// One task per validator of the local testnet, in id order; node 0 reports.
+fn spawn_validators<T: Transport>(
+    networks: Vec<T>,
+    signers: Vec<Arc<dyn ConsensusSigner>>,
+    committee: &Arc<Committee>,
+    commit_logs: Vec<Arc<Mutex<CommitLog>>>,
+    leave: Option<(u64, ReconfigurationProposal)>,
+    stats: &Stats,
+) -> Vec<tokio::task::JoinHandle<()>> {
+    let validators = networks.into_iter().zip(signers).map(|(network, signer)| Validator { signer, network });
+    validators.zip(commit_logs).enumerate().map(|(i, (validator, commit_log))| {
+        let node_id = i as ValidatorId;
+        tokio::spawn(run_validator(node_id, validator, committee.clone(), commit_log, leave.clone(), stats.clone(), node_id == 0))
+    }).collect()
+}
+
+/// Drives one validator until it leaves the committee. `report` makes it
+/// print RESULT lines and sample commit latency; `leave` is the local
+/// testnet's reconfiguration demo, with the round to propose it in.
+async fn run_validator<T: Transport>(
+    mut node_id: ValidatorId,
+    validator: Validator<T>,
+    committee: Arc<Committee>,
+    commit_log: Arc<Mutex<CommitLog>>,
+    leave: Option<(u64, ReconfigurationProposal)>,
+    stats: Stats,
+    report: bool,
+) {
+    let Validator { signer, network } = validator;
+    let bls_pk = signer.public_key();
+    let (tx, mut rx) = mpsc::channel(1_000_000);
+    let fetched = tx.clone();
+    let handle = network.start(tx).await;
+    let mut state = ConsensusState::new(node_id, committee.clone());
+    tokio::time::sleep(Duration::from_secs(2)).await;
+
+    let start = Instant::now();
+    let mut last_report = Instant::now();
+    let mut round_starts = HashMap::new();
+    let mut in_flight = std::collections::HashSet::new();
+    let mut pacemaker = Pacemaker::new(TokioClock::new(), ANCHOR_TIMEOUT, MAX_ANCHOR_TIMEOUT);
+    let mut idle = false;
+
+    loop {
+        let committee = state.committee.clone();
+        let drift = state.round.saturating_sub(state.dag.committed_round);
+        stats.drift.lock().record(drift);
+
+        if report && last_report.elapsed() > Duration::from_secs(2) {
+            let dur = start.elapsed().as_secs_f64();
+            let total_micros = start.elapsed().as_micros() as u64;
+            let mut l = stats.latencies.lock();
+            l.sort();
+            let p99 = if !l.is_empty() { l[(l.len() * 99) / 100] } else { 0 };
+            
+            let net_m = handle.metrics();
+            let tx_count = state.dag.committed_round * committee.size() as u64;
+            let b_tx = if tx_count > 0 { net_m.bytes_sent as f64 / tx_count as f64 } else { 0.0 };
+
+            let dm = stats.drift.lock();
+            println!("RESULT: VPS={:.2}, P99={}ms, B/Tx={:.1}, Drift={:.1}/{}, R={}/CR={}", 
+                tx_count as f64 / dur, p99, b_tx, dm.mean_drift(), dm.max_drift, state.round, state.dag.committed_round);
+            println!("DEBUG_METRICS: {}", stats.crypto.lock().report(total_micros));
+            last_report = Instant::now();
+        }
+
+        // 1. Propose Vertex (with backpressure), on top of every certified vertex of the previous round
+        let first_round = state.dag.first_round;
+        let parents = if state.round > first_round { state.dag.certified_in_round(state.round - 1) } else { vec![] };
+        let has_parents = state.round == first_round
+            || committee.stake_of(parents.iter().map(|h| state.dag.vertices[h].author)) >= committee.quorum_threshold();
+        let can_propose = has_parents && drift < MAX_ROUND_DRIFT && in_flight.len() < VERIFICATION_WINDOW;
+        if can_propose {
+            // Every validator proposes the smaller committee; it takes effect
+            // once proposals from a stake quorum have committed
+            let reconfiguration = leave.as_ref()
+                .filter(|(round, _)| state.epoch == 0 && state.round == *round)
+                .map(|(_, proposal)| proposal.clone());
+            let v = Vertex { 
+                epoch: state.epoch,
+                round: state.round, 
+                author: node_id, 
+                batch_hash: [0u8; 32], 
+                parents,
+                reconfiguration,
+            };
+            if report { round_starts.insert(v.round, Instant::now()); }
+            
+            // Broadcast Vertex
+            handle.broadcast_message(&Message::Vertex(v.clone())).await;
+            
+            // Own vertex is signed with the other accepted vertices below
+            state.on_event(Event::VertexReceived(v));
+            state.round += 1;
+        }
+
+        // 2. Process incoming events. With nothing left to do, sleep until the
+        // next one arrives or the anchor timer runs out
+        let mut next = None;
+        if idle {
+            let wait = pacemaker.time_to_deadline().unwrap_or(IDLE_WAIT);
+            tokio::select! {
+                event = rx.recv() => match event {
+                    Some(event) => next = Some(event),
+                    None => break,
+                },
+                _ = tokio::time::sleep(wait) => {}
+            }
+        }
+        let mut event_count = 0;
+        while let Some(event) = next.take().or_else(|| rx.try_recv().ok()) {
+            match event {
+                Event::RequestReceived(peer, id, request) => handle.respond(peer, id, state.answer(&request)).await,
+                event => state.on_event(event),
+            }
+            event_count += 1;
+            if event_count > 1000 { break; }
+        }
+
+        // 2a. Sign vertices that passed validation (including ones released from the pending buffer)
+        for (h, v) in state.take_accepted_vertices() {
+            if !state.try_vote(h, &v) { continue; }
+
+            let sig = match signer.sign_vote(&v).await {
+                Ok(sig) => sig,
+                Err(e) => {
+                    eprintln!("node {}: not voting for round {} vertex of {}: {}", node_id, v.round, v.author, e);
+                    continue;
+                }
+            };
+            stats.crypto.lock().bls_sign_count += 1;
+
+            let coa = CoA { batch_hash: h, signatures: vec![(node_id, sig)] };
+            state.on_event(Event::CoAReceived(coa.clone()));
+
+            handle.broadcast_message(&Message::CoA(coa)).await;
+        }
+
+        // 2b. Pacemaker: time out rounds whose anchor is late
+        pacemaker.update(state.waiting_anchor_round(), state.fallback_depth);
+        if let Some(timeout) = pacemaker.poll() {
+            state.on_event(timeout);
+        }
+
+        // 2c. Skip path: sign skip votes the state agreed to, forward new SkipCerts and equivocation evidence
+        for (round, anchor) in state.take_pending_skip_votes() {
+            let epoch = state.epoch;
+            let sig = match signer.sign_skip_vote(epoch, round, anchor).await {
+                Ok(sig) => sig,
+                Err(e) => {
+                    eprintln!("node {}: not voting to skip round {}: {}", node_id, round, e);
+                    continue;
+                }
+            };
+            stats.crypto.lock().bls_sign_count += 1;
+            state.on_event(Event::SkipVoteReceived(epoch, round, anchor, node_id, sig.clone()));
+
+            handle.broadcast_message(&Message::SkipVote(epoch, round, anchor, node_id, sig)).await;
+        }
+        // The round is still stuck: some peers may have missed our vote
+        for (round, anchor, sig) in state.take_resent_skip_votes() {
+            handle.broadcast_message(&Message::SkipVote(state.epoch, round, anchor, node_id, sig)).await;
+        }
+        // 2d. Coin shares for anchor rounds whose previous round is certified, or
+        // whose coin is late
+        for round in state.take_pending_coin_shares() {
+            let epoch = state.epoch;
+            let sig = match signer.sign_coin_share(epoch, round, &state.committee.coin, node_id).await {
+                Ok(sig) => sig,
+                Err(e) => {
+                    eprintln!("node {}: no coin share for round {}: {}", node_id, round, e);
+                    continue;
+                }
+            };
+            stats.crypto.lock().bls_sign_count += 1;
+            state.on_event(Event::CoinShareReceived(epoch, round, node_id, sig.clone()));
+
+            handle.broadcast_message(&Message::CoinShare(epoch, round, node_id, sig)).await;
+        }
+        // 2e. Certified parents a stalled round waits on, asked of the author
+        // that built on them. They come back as events, verified like any other
+        for (peer, parents) in state.take_missing_parents() {
+            let (handle, fetched, id) = (handle.clone(), fetched.clone(), node_id);
+            tokio::spawn(async move {
+                match handle.request(peer, Request::Certified(parents), FETCH_TIMEOUT).await {
+                    Ok(Response::Certified(found)) => for cv in found {
+                        let _ = fetched.send(Event::AggregatedCoAReceived(cv.agg_coa)).await;
+                        let _ = fetched.send(Event::VertexReceived(cv.vertex)).await;
+                    },
+                    Err(e) => eprintln!("node {}: fetching parents: {}", id, e),
+                }
+            });
+        }
+        for cert in state.take_new_skip_certs() {
+            handle.broadcast_message(&Message::SkipCert(cert)).await;
         }
+        for evidence in state.take_new_evidence() {
+            handle.broadcast_message(&Message::Equivocation(evidence)).await;
+        }
+
+        // 3. Batch Verification
+        let pending = state.get_pending_quorums();
+        let mut batch_items = Vec::new();
+
+        for (h, _vertex, signatures) in pending {
+            if in_flight.contains(&h) { continue; }
+            let q = committee.quorum_threshold();
+            if let Ok((agg, bitmap, _)) = aggregate_signatures_with_metrics(&signatures, &committee, q) {
+                batch_items.push((h, agg, bitmap, q, state.vote_message(&h)));
+            }
+        }
+
+        // Adaptive batch trigger based on drift
+        let batch_trigger = if drift > 10 { 
+            4  // Aggressive: verify smaller batches to reduce latency
+        } else { 
+            8  // Normal: balance batching efficiency
+        };
         
-        // Precompute uncompressed public keys for the entire lifetime
-        let pks_shared: Vec<(u32, Vec<u8>)> = (0..n).map(|idx| (idx as u32, bls_pks[idx].clone())).collect();
-        let pks_shared = Arc::new(pks_shared);
-
-        let latencies = Arc::new(Mutex::new(Vec::new()));
-        let crypto_metrics = Arc::new(Mutex::new(CryptoMetrics::default()));
-        let drift_metrics = Arc::new(Mutex::new(DriftMetrics::default()));
-        let mut tasks = Vec::new();
-
-        for i in 0..n {
-            let node_id = i as u32;
-            let bls_sk = bls_keys[i].clone();
-            let _all_pks = bls_pks.clone();
-            let pks_node = pks_shared.clone();
-            let latencies = latencies.clone();
-            let metrics = crypto_metrics.clone();
-            let drift_m = drift_metrics.clone();
-            let mut peer_addrs = HashMap::new();
-            for j in 0..n { if i != j { peer_addrs.insert(j as u32, format!("127.0.0.1:{}", port_offset + j as u16)); } }
-            let listen_addr = format!("127.0.0.1:{}", port_offset + i as u16);
-
-            tasks.push(tokio::spawn(async move {
-                let (tx, mut rx) = mpsc::channel(1_000_000);
-                let network = TcpNetwork::new(node_id, listen_addr, peer_addrs);
-                let handle = network.start(tx).await;
-                let mut state = ConsensusState::new(node_id, n);
-                tokio::time::sleep(Duration::from_secs(2)).await;
-
-                let start = Instant::now();
-                let mut last_report = Instant::now();
-                let mut round_starts = HashMap::new();
-                let mut in_flight = std::collections::HashSet::new();
-
-                loop {
-                    let drift = state.round.saturating_sub(state.dag.committed_round);
-                    drift_m.lock().record(drift);
-
-                    if node_id == 0 && last_report.elapsed() > Duration::from_secs(2) {
-                        let dur = start.elapsed().as_secs_f64();
-                        let total_micros = start.elapsed().as_micros() as u64;
-                        let mut l = latencies.lock();
-                        l.sort();
-                        let p99 = if !l.is_empty() { l[(l.len() * 99) / 100] } else { 0 };
-                        
-                        let net_m = handle.get_metrics();
-                        let tx_count = state.dag.committed_round * n as u64;
-                        let b_tx = if tx_count > 0 { net_m.bytes_sent as f64 / tx_count as f64 } else { 0.0 };
-
-                        let dm = drift_m.lock();
-                        println!("RESULT: VPS={:.2}, P99={}ms, B/Tx={:.1}, Drift={:.1}/{}, R={}/CR={}", 
-                            tx_count as f64 / dur, p99, b_tx, dm.mean_drift(), dm.max_drift, state.round, state.dag.committed_round);
-                        println!("DEBUG_METRICS: {}", metrics.lock().report(total_micros));
-                        last_report = Instant::now();
-                    }
+        // Flush early when the next proposal is waiting on these certificates
+        let flush = !batch_items.is_empty() && (batch_items.len() >= batch_trigger || drift > 15 || !has_parents);
+        if flush {
+            let mut batch_input = Vec::new();
+            for (h, agg, bitmap, q, msg) in batch_items.iter() {
+                in_flight.insert(*h);
+                batch_input.push((msg, agg, committee.as_ref(), bitmap, *q));
+            }
 
-                    // 1. Propose Vertex (with backpressure)
-                    let can_propose = drift < MAX_ROUND_DRIFT && in_flight.len() < VERIFICATION_WINDOW;
-                    if can_propose {
-                        let v = Vertex { 
-                            round: state.round, 
-                            author: node_id, 
-                            batch_hash: [0u8; 32], 
-                            parent_indices: if state.round > 1 { (0..(n as u32)).collect() } else { vec![] } 
-                        };
-                        if node_id == 0 { round_starts.insert(v.round, Instant::now()); }
-                        
-                        // Broadcast Vertex
-                        let mut ser = AllocSerializer::<1024>::default();
-                        ser.serialize_value(&Message::Vertex(v.clone())).unwrap();
-                        let v_bytes = ser.into_serializer().into_inner().to_vec();
-                        let _ = handle.broadcast_raw(v_bytes).await;
-                        
-                        // Hash Vertex and Sign
-                        let h = crate::crypto::hash_vertex(&v);
-                        state.on_event(Event::VertexReceived(v));
-                        
-                        let sig = bls_sk.sign(&h);
-                        metrics.lock().bls_sign_count += 1;
-                        
-                        let coa = CoA { batch_hash: h, signatures: vec![(node_id, sig)] };
-                        state.on_event(Event::CoAReceived(coa.clone()));
-                        
-                        // Broadcast CoA
-                        let mut ser_coa = AllocSerializer::<1024>::default();
-                        ser_coa.serialize_value(&Message::CoA(coa)).unwrap();
-                        let _ = handle.broadcast_raw(ser_coa.into_serializer().into_inner().to_vec()).await;
-                        
-                        state.round += 1;
-                    }
+            let (valid, v_metrics) = verify_aggregated_batch_with_metrics(batch_input);
+            
+            {
+                let mut m = stats.crypto.lock();
+                m.cert_count += batch_items.len() as u64;
+                m.bls_verify_micros += v_metrics.verify_micros;
+                m.batch_count += 1;
+                m.pairing_count += v_metrics.pairing_count;
+            }
 
-                    // 2. Process incoming events
-                    let mut event_count = 0;
-                    while let Ok(event) = rx.try_recv() {
-                        match event {
-                            Event::VertexReceived(v) => {
-                                let h = crate::crypto::hash_vertex(&v);
-                                state.on_event(Event::VertexReceived(v));
-                                
-                                let sig = bls_sk.sign(&h);
-                                metrics.lock().bls_sign_count += 1;
-                                
-                                let coa = CoA { batch_hash: h, signatures: vec![(node_id, sig)] };
-                                state.on_event(Event::CoAReceived(coa.clone()));
-                                
-                                let mut ser_coa = AllocSerializer::<1024>::default();
-                                ser_coa.serialize_value(&Message::CoA(coa)).unwrap();
-                                let _ = handle.broadcast_raw(ser_coa.into_serializer().into_inner().to_vec()).await;
-                            }
-                            Event::CoAReceived(coa) => {
-                                state.on_event(Event::CoAReceived(coa));
-                            }
-                            _ => {}
+            if valid {
+                let old_cr = state.dag.committed_round;
+                for (h, agg, bitmap, _, _) in batch_items {
+                    let cert = AggregatedCoA { epoch: state.epoch, batch_hash: h, aggregated_signature: agg, signer_bitmap: bitmap };
+                    // The author gossips the canonical certificate for its own vertex
+                    if state.dag.vertices.get(&h).map(|v| v.author) == Some(node_id) {
+                        handle.broadcast_message(&Message::AggregatedCoA(cert.clone())).await;
+                    }
+                    state.certify_vertex(cert);
+                    in_flight.remove(&h); // Release the credit
+                }
+                if report && state.dag.committed_round > old_cr {
+                    for r in (old_cr + 1)..=state.dag.committed_round {
+                        if let Some(s) = round_starts.remove(&r) {
+                            stats.latencies.lock().push(s.elapsed().as_millis());
                         }
-                        event_count += 1;
-                        if event_count > 1000 { break; }
                     }
+                }
+            } else {
+                // Some partial signature is bad: find and drop it, then retry with the rest
+                for (h, _, _, _, _) in &batch_items {
+                    state.verify_partials(h);
+                    in_flight.remove(h);
+                }
+                for (h, signer) in state.take_bad_partials() {
+                    eprintln!("node {}: dropped invalid partial signature from {} on {:02x?}", node_id, signer, &h[..4]);
+                }
+            }
+        }
 
-                    // 3. Batch Verification
-                    let pending = state.get_pending_quorums();
-                    let mut batch_items = Vec::new();
+        // 4. Hand committed sub-DAGs to the commit stream. A write that fails, or
+        // a re-commit after restart that disagrees with the log, stops the node
+        for sub_dag in state.take_committed() {
+            if let Err(e) = commit_log.lock().append(sub_dag) {
+                eprintln!("node {}: commit log: {}; stopping", node_id, e);
+                return;
+            }
+        }
 
-                    for (h, _vertex, signatures) in pending {
-                        if in_flight.contains(&h) { continue; }
-                        let q = n - (n - 1) / 3;
-                        if let Ok((agg, bitmap, _)) = aggregate_signatures_with_metrics(&signatures, q) {
-                            batch_items.push((h, agg, bitmap, q, signatures));
-                            in_flight.insert(h);
-                        }
-                    }
+        // 5. Epoch boundary: ids may have moved, and a validator that left stops
+        // here. The transport follows, so joiners are reached and leavers shut out
+        if let Some(next) = state.take_epoch_changes().pop() {
+            in_flight.clear();
+            println!("node {}: epoch {} starts at round {} with {} validators", node_id, next.epoch, state.dag.first_round, next.size());
+            match next.id_of(&bls_pk) {
+                Some(id) => node_id = id,
+                None => break,
+            }
+            handle.reconfigure(node_id, &next);
+        }
 
-                    // Adaptive batch trigger based on drift
-                    let batch_trigger = if drift > 10 { 
-                        4  // Aggressive: verify smaller batches to reduce latency
-                    } else { 
-                        8  // Normal: balance batching efficiency
-                    };
-                    
-                    if !batch_items.is_empty() && (batch_items.len() >= batch_trigger || drift > 15) {
-                        let mut batch_input = Vec::new();
-                        for (h, agg, bitmap, q, _) in batch_items.iter() {
-                            batch_input.push((h.as_slice(), agg, pks_node.as_slice(), *bitmap, *q));
-                        }
+        if state.dag.committed_round >= 25000 { break; }
+        idle = event_count == 0 && !can_propose && !flush;
+        tokio::task::yield_now().await;
+    }
+}
 
-                        let (valid, v_metrics) = verify_aggregated_batch_with_metrics(batch_input);
-                        
-                        let mut m = metrics.lock();
-                        m.cert_count += batch_items.len() as u64;
-                        m.bls_verify_micros += v_metrics.verify_micros;
-                        m.batch_count += 1;
-                        m.pairing_count += v_metrics.pairing_count;
-                        drop(m);
-
-                        if valid {
-                            let old_cr = state.dag.committed_round;
-                            for (h, _, _, _, sigs) in batch_items {
-                                state.certify_vertex(h, sigs);
-                                in_flight.remove(&h); // Release the credit
-                            }
-                            if node_id == 0 && state.dag.committed_round > old_cr {
-                                for r in (old_cr + 1)..=state.dag.committed_round {
-                                    if let Some(s) = round_starts.remove(&r) {
-                                        latencies.lock().push(s.elapsed().as_millis());
-                                    }
-                                }
-                            }
-                        } else {
-                            for (h, _, _, _, _) in &batch_items {
-                                in_flight.remove(h);
-                            }
-                        }
-                    }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-                    if state.dag.committed_round >= 25000 { break; }
-                    tokio::task::yield_now().await;
-                }
-            }));
+    async fn next(stream: &mut CommitStream) -> crate::types::CommittedSubDag {
+        tokio::time::timeout(Duration::from_secs(30), stream.next()).await.unwrap().unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_running_node_serves_its_commit_stream() {
+        let keys: Vec<_> = (0..4).map(|_| ValidatorKeys::generate(&mut OsRng)).collect();
+        let bls_pks = keys.iter().map(|k| (k.bls.public_key(), k.bls.prove_possession(LOCALHOST_CHAIN_ID))).collect();
+        let ed_pks = keys.iter().map(|k| k.ed25519.verifying_key().to_bytes()).collect();
+        let committee = Arc::new(Committee::localhost(bls_pks, ed_pks, 10000).unwrap());
+        let signers = keys.into_iter()
+            .map(|keys| Arc::new(LocalSigner::new(keys.bls, LOCALHOST_CHAIN_ID, SlashingDb::in_memory())) as Arc<dyn ConsensusSigner>)
+            .collect();
+        let commit_logs: Vec<_> = (0..4).map(|_| Arc::new(Mutex::new(CommitLog::in_memory()))).collect();
+
+        let socket = std::env::temp_dir().join(format!("sublyne-commits-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&socket);
+        let listener = tokio::net::UnixListener::bind(&socket).unwrap();
+        tokio::spawn(serve_commits(listener, commit_logs[0].clone(), socket.display().to_string()));
+        let _validators = spawn_validators(ChannelNetwork::mesh(&committee), signers, &committee, commit_logs.clone(), None, &Stats::default());
+
+        let mut stream = CommitStream::connect(&socket, 0).await.unwrap();
+        for index in 0..3 {
+            let sub_dag = next(&mut stream).await;
+            assert_eq!(sub_dag.index, index);
+            assert_eq!(Some(&sub_dag), commit_logs[0].lock().get(index));
         }
-        futures::future::join_all(tasks).await;
-    });
+
+        // A consumer that processed index 1 resumes from 2, replayed and then live
+        let mut resumed = CommitStream::connect(&socket, 2).await.unwrap();
+        assert_eq!(next(&mut resumed).await.index, 2);
+        assert_eq!(next(&mut resumed).await.index, 3);
+        let _ = std::fs::remove_file(&socket);
+    }
 }
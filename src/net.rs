@@ -1,12 +1,29 @@
 use tokio::net::{TcpListener, TcpStream};
-use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
 use std::collections::HashMap;
+use std::io;
 use std::sync::Arc;
-use tokio::sync::mpsc;
-use crate::types::{Message, Event, ValidatorId, ArchivedMessage};
-use std::time::Instant;
-use parking_lot::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::{mpsc, oneshot};
+use crate::types::{Message, Event, Hash, ValidatorId, ArchivedMessage, Response};
+use crate::committee::Committee;
+use std::time::{Duration, Instant};
+use parking_lot::{Mutex, RwLock};
 use rkyv::{check_archived_root, Deserialize};
+use ed25519_dalek::{Signer, SigningKey, VerifyingKey};
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use rand::RngCore;
+use zeroize::Zeroizing;
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 32;
+const SIGNATURE_LEN: usize = 64;
+/// What each side contributes afresh to a handshake: a nonce and an ephemeral X25519 key.
+const FRESH_LEN: usize = NONCE_LEN + KEY_LEN;
+const MAC_LEN: usize = 32;
+/// Largest message frame accepted from a peer, checked before allocating for it.
+pub const MAX_FRAME: usize = 16 << 20;
 
 #[derive(Clone, Debug)]
 pub struct NetMetrics {
@@ -16,19 +33,333 @@ pub struct NetMetrics {
     pub bytes_recv: u64,
 }
 
+/// Requests sent and not yet answered, by correlation id, with the peer asked.
+#[derive(Default)]
+pub struct PendingRequests {
+    next_id: AtomicU64,
+    pub waiting: Mutex<HashMap<u64, (ValidatorId, oneshot::Sender<Response>)>>,
+}
+
+impl PendingRequests {
+    /// Correlation id for a new request to `peer`, and where its answer arrives.
+    pub fn register(&self, peer: ValidatorId) -> (u64, oneshot::Receiver<Response>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        self.waiting.lock().insert(id, (peer, tx));
+        (id, rx)
+    }
+
+    /// Give up on a request, so a late answer is dropped.
+    pub fn forget(&self, id: u64) {
+        self.waiting.lock().remove(&id);
+    }
+
+    /// Hand `response` to the request it answers, if `peer` is the one asked.
+    fn resolve(&self, peer: ValidatorId, id: u64, response: Response) {
+        let mut waiting = self.waiting.lock();
+        if waiting.get(&id).is_some_and(|(asked, _)| *asked == peer) {
+            let (_, tx) = waiting.remove(&id).unwrap();
+            let _ = tx.send(response);
+        }
+    }
+}
+
+/// An ed25519 public key. Connections are bound to one, whatever id its
+/// validator has in the current epoch.
+pub type PeerKey = [u8; 32];
+
+/// The committee as a transport sees it: whose connections to let in, where
+/// each peer listens, and the id each has in the current epoch. Messages look
+/// their sender's id up as they arrive, so `reconfigure` applies to open
+/// connections as well.
+pub struct PeerSet {
+    pub chain_id: String,
+    members: RwLock<Members>,
+}
+
+struct Members {
+    id: ValidatorId,
+    /// Every other validator, with its id and address.
+    peers: HashMap<PeerKey, (ValidatorId, String)>,
+}
+
+impl Members {
+    fn new(id: ValidatorId, committee: &Committee) -> Self {
+        let peers = committee.validators.iter()
+            .filter(|v| v.id != id)
+            .filter_map(|v| Some((PeerKey::try_from(v.ed25519_public_key.as_slice()).ok()?, (v.id, v.address.clone()))))
+            .collect();
+        Self { id, peers }
+    }
+}
+
+impl PeerSet {
+    pub fn new(id: ValidatorId, committee: &Committee) -> Self {
+        Self { chain_id: committee.chain_id.clone(), members: RwLock::new(Members::new(id, committee)) }
+    }
+
+    /// Switch to `committee`, in which we are `id`.
+    pub fn reconfigure(&self, id: ValidatorId, committee: &Committee) {
+        *self.members.write() = Members::new(id, committee);
+    }
+
+    /// Our own id in the current committee.
+    pub fn id(&self) -> ValidatorId {
+        self.members.read().id
+    }
+
+    /// Current id of the peer holding `key`; None if it is not in the committee.
+    pub fn id_of(&self, key: &PeerKey) -> Option<ValidatorId> {
+        self.members.read().peers.get(key).map(|(id, _)| *id)
+    }
+
+    pub fn key_of(&self, peer: ValidatorId) -> Option<PeerKey> {
+        self.members.read().peers.iter().find(|(_, (id, _))| *id == peer).map(|(key, _)| *key)
+    }
+
+    /// Every peer's key and address.
+    pub fn peers(&self) -> Vec<(PeerKey, String)> {
+        self.members.read().peers.iter().map(|(key, (_, addr))| (*key, addr.clone())).collect()
+    }
+}
+
+/// Mutual authentication when a connection opens: each side signs a transcript
+/// of both ed25519 keys, fresh nonces and ephemeral X25519 keys. The dialer
+/// learns it reached the key it meant to; the acceptor learns which key
+/// dialed, and binds the session to it. Either side refuses a key outside its
+/// current committee. The ephemeral keys agree on a `FrameMac` for the frames
+/// that follow.
+pub struct Handshake {
+    key: SigningKey,
+    public_key: PeerKey,
+    pub peers: Arc<PeerSet>,
+}
+
+impl Handshake {
+    pub fn new(peers: Arc<PeerSet>, key: SigningKey) -> Self {
+        Self { public_key: key.verifying_key().to_bytes(), key, peers }
+    }
+
+    /// H(chain_id || role || dialer || acceptor || fresh values); the role
+    /// keeps one side's signature from being replayed as the other's.
+    fn transcript(&self, role: &[u8], dialer: &PeerKey, acceptor: &PeerKey, dialer_fresh: &[u8], acceptor_fresh: &[u8]) -> Hash {
+        let chain_id = &self.peers.chain_id;
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(b"sublyne-handshake");
+        hasher.update(&(chain_id.len() as u32).to_le_bytes());
+        hasher.update(chain_id.as_bytes());
+        hasher.update(role);
+        hasher.update(dialer);
+        hasher.update(acceptor);
+        hasher.update(dialer_fresh);
+        hasher.update(acceptor_fresh);
+        *hasher.finalize().as_bytes()
+    }
+
+    fn verify(&self, peer: &PeerKey, transcript: &Hash, sig: &[u8]) -> io::Result<()> {
+        if self.peers.id_of(peer).is_none() {
+            return Err(not_a_peer(peer));
+        }
+        let key = VerifyingKey::from_bytes(peer).map_err(|_| not_a_peer(peer))?;
+        if !crate::crypto::verify_signature(transcript, sig, &key) {
+            return Err(refused(format!("key {} failed to prove its identity", crate::crypto::to_hex(peer))));
+        }
+        Ok(())
+    }
+
+    /// Dialer side: hello, then check the acceptor holds `expected` before
+    /// proving ourselves. Tags what we send next.
+    pub async fn dial<S: AsyncRead + AsyncWrite + Unpin>(&self, stream: &mut S, expected: &PeerKey) -> io::Result<FrameMac> {
+        let (secret, fresh) = fresh();
+        let mut hello = self.public_key.to_vec();
+        hello.extend(fresh);
+        stream.write_all(&hello).await?;
+
+        let mut reply = [0u8; KEY_LEN + FRESH_LEN + SIGNATURE_LEN];
+        stream.read_exact(&mut reply).await?;
+        let acceptor: PeerKey = reply[..KEY_LEN].try_into().unwrap();
+        if acceptor != *expected {
+            return Err(refused(format!("reached key {} instead of {}", crate::crypto::to_hex(&acceptor), crate::crypto::to_hex(expected))));
+        }
+        let acceptor_fresh = &reply[KEY_LEN..KEY_LEN + FRESH_LEN];
+        self.verify(&acceptor, &self.transcript(b"accept", &self.public_key, &acceptor, &fresh, acceptor_fresh), &reply[KEY_LEN + FRESH_LEN..])?;
+
+        let transcript = self.transcript(b"dial", &self.public_key, &acceptor, &fresh, acceptor_fresh);
+        stream.write_all(&self.key.sign(&transcript).to_bytes()).await?;
+        stream.flush().await?;
+        FrameMac::agree(&secret, acceptor_fresh, &transcript)
+    }
+
+    /// Acceptor side: the authenticated key of the validator that dialed, and
+    /// the MAC its frames carry.
+    pub async fn accept<S: AsyncRead + AsyncWrite + Unpin>(&self, stream: &mut S) -> io::Result<(PeerKey, FrameMac)> {
+        let mut hello = [0u8; KEY_LEN + FRESH_LEN];
+        stream.read_exact(&mut hello).await?;
+        let dialer: PeerKey = hello[..KEY_LEN].try_into().unwrap();
+        if self.peers.id_of(&dialer).is_none() {
+            return Err(not_a_peer(&dialer));
+        }
+        let dialer_fresh = &hello[KEY_LEN..];
+        let (secret, fresh) = fresh();
+        let transcript = self.transcript(b"accept", &dialer, &self.public_key, dialer_fresh, &fresh);
+        let mut reply = self.public_key.to_vec();
+        reply.extend(fresh);
+        reply.extend(self.key.sign(&transcript).to_bytes());
+        stream.write_all(&reply).await?;
+        stream.flush().await?;
+
+        let mut sig = [0u8; SIGNATURE_LEN];
+        stream.read_exact(&mut sig).await?;
+        let transcript = self.transcript(b"dial", &dialer, &self.public_key, dialer_fresh, &fresh);
+        self.verify(&dialer, &transcript, &sig)?;
+        Ok((dialer, FrameMac::agree(&secret, dialer_fresh, &transcript)?))
+    }
+}
+
+/// A nonce and an ephemeral X25519 key, with the key's secret.
+fn fresh() -> (Zeroizing<[u8; 32]>, [u8; FRESH_LEN]) {
+    let mut secret = Zeroizing::new([0u8; 32]);
+    let mut fresh = [0u8; FRESH_LEN];
+    rand::rngs::OsRng.fill_bytes(secret.as_mut());
+    rand::rngs::OsRng.fill_bytes(&mut fresh[..NONCE_LEN]);
+    fresh[NONCE_LEN..].copy_from_slice(MontgomeryPoint::mul_base_clamped(*secret).as_bytes());
+    (secret, fresh)
+}
+
+/// Authenticates the frames a dialer sends after the handshake, under a key
+/// only the two ends of the connection know. Each tag also covers the frame's
+/// position, so the acceptor notices a frame dropped, replayed or reordered
+/// as well as one altered.
+pub struct FrameMac {
+    key: Zeroizing<[u8; 32]>,
+    frames: u64,
+}
+
+impl FrameMac {
+    /// Key from our ephemeral `secret` and the peer's fresh values, bound to
+    /// the handshake `transcript` both sides signed.
+    fn agree(secret: &[u8; 32], peer_fresh: &[u8], transcript: &Hash) -> io::Result<Self> {
+        let peer_ephemeral = MontgomeryPoint(peer_fresh[NONCE_LEN..].try_into().unwrap());
+        let shared = Zeroizing::new(peer_ephemeral.mul_clamped(*secret).to_bytes());
+        // A low-order ephemeral key would fix the shared secret
+        if *shared == [0u8; 32] {
+            return Err(refused("peer sent a degenerate ephemeral key".to_string()));
+        }
+        let mut hasher = blake3::Hasher::new_derive_key("sublyne tcp frame mac");
+        hasher.update(shared.as_ref());
+        hasher.update(transcript);
+        Ok(Self { key: Zeroizing::new(*hasher.finalize().as_bytes()), frames: 0 })
+    }
+
+    /// Tag of the next frame.
+    fn tag(&mut self, frame: &[u8]) -> blake3::Hash {
+        let tag = blake3::Hasher::new_keyed(&self.key).update(&self.frames.to_le_bytes()).update(frame).finalize();
+        self.frames += 1;
+        tag
+    }
+
+    /// Whether `tag` is the next frame's; the comparison is constant-time.
+    fn check(&mut self, frame: &[u8], tag: &[u8; MAC_LEN]) -> bool {
+        self.tag(frame) == *tag
+    }
+}
+
+fn not_a_peer(key: &PeerKey) -> io::Error {
+    refused(format!("key {} is not in the committee", crate::crypto::to_hex(key)))
+}
+
+fn refused(reason: String) -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, reason)
+}
+
+/// Whether `peer` may send `msg` itself. Vertices and votes only ever come
+/// from their author; certificates and evidence carry their own signatures
+/// and may be relayed by anyone.
+pub fn sent_by_author(msg: &Message, peer: ValidatorId) -> bool {
+    match msg {
+        Message::Vertex(v) => v.author == peer,
+        Message::CoA(coa) => coa.signatures.iter().all(|(voter, _)| *voter == peer),
+        Message::SkipVote(_, _, _, voter, _) | Message::CoinShare(_, _, voter, _) => *voter == peer,
+        Message::AggregatedCoA(_) | Message::SkipCert(_) | Message::Equivocation(_) => true,
+        // Answered to, or matched against a request to, the connection's own peer
+        Message::Request(..) | Message::Response(..) => true,
+    }
+}
+
+/// Read length-prefixed messages from the peer authenticated as `key` into
+/// `tx` (or, for responses, `pending`) until the stream ends or carries
+/// something malformed. Each is attributed to the id the key has when it
+/// arrives; while the key is outside the committee, its messages are dropped.
+/// On a stream that does not authenticate its bytes itself, as TCP does not,
+/// every frame is followed by a tag that `mac` must accept.
+pub(crate) async fn receive<R: AsyncRead + Unpin>(mut reader: R, peers: Arc<PeerSet>, key: PeerKey, mut mac: Option<FrameMac>, tx: mpsc::Sender<Event>, pending: Arc<PendingRequests>, metrics: Arc<Mutex<NetMetrics>>) {
+    let mut len_buf = [0u8; 4];
+    let mut tag = [0u8; MAC_LEN];
+    loop {
+        if reader.read_exact(&mut len_buf).await.is_err() { break; }
+        let len = u32::from_le_bytes(len_buf) as usize;
+        if len > MAX_FRAME {
+            eprintln!("node {}: {} sent a {} byte frame, closing", peers.id(), crate::crypto::to_hex(&key), len);
+            break;
+        }
+        let mut msg_buf = vec![0u8; len];
+        if reader.read_exact(&mut msg_buf).await.is_err() { break; }
+        if let Some(mac) = &mut mac {
+            if reader.read_exact(&mut tag).await.is_err() { break; }
+            if !mac.check(&msg_buf, &tag) {
+                eprintln!("node {}: frame from {} failed authentication, closing", peers.id(), crate::crypto::to_hex(&key));
+                break;
+            }
+        }
+        let Some(peer) = peers.id_of(&key) else { continue };
+        if !deliver(&msg_buf, peers.id(), peer, &tx, &pending, &metrics).await { break; }
+    }
+}
+
+/// Decode one message from `peer` and pass it on as an event, or as the answer
+/// to a pending request. False if it is malformed; a message the peer did not
+/// author is dropped.
+pub(crate) async fn deliver(msg_buf: &[u8], id: ValidatorId, peer: ValidatorId, tx: &mpsc::Sender<Event>, pending: &PendingRequests, metrics: &Mutex<NetMetrics>) -> bool {
+    let start_deser = Instant::now();
+    let Ok(archived) = check_archived_root::<Message>(msg_buf) else { return false };
+    let msg: Message = archived.deserialize(&mut rkyv::Infallible).unwrap();
+    let deser_elapsed = start_deser.elapsed().as_micros() as u64;
+    if !sent_by_author(&msg, peer) {
+        eprintln!("node {}: dropped a message from {} that it did not author", id, peer);
+        return true;
+    }
+
+    {
+        let mut m = metrics.lock();
+        m.deser_micros += deser_elapsed;
+        m.bytes_recv += msg_buf.len() as u64;
+    }
+
+    match msg {
+        Message::Response(request_id, response) => pending.resolve(peer, request_id, response),
+        msg => {
+            if let Some(event) = msg.into_event(peer) {
+                let _ = tx.send(event).await;
+            }
+        }
+    }
+    true
+}
+
 pub struct TcpNetwork {
-    pub id: ValidatorId,
     pub listen_addr: String,
-    pub peer_addrs: HashMap<ValidatorId, String>,
+    pub handshake: Arc<Handshake>,
+    pub pending: Arc<PendingRequests>,
     pub metrics: Arc<Mutex<NetMetrics>>,
 }
 
 impl TcpNetwork {
-    pub fn new(id: ValidatorId, listen_addr: String, peer_addrs: HashMap<ValidatorId, String>) -> Self {
+    /// `identity` is this validator's ed25519 key, as registered in `committee`.
+    pub fn new(id: ValidatorId, committee: &Committee, identity: SigningKey) -> Self {
         Self {
-            id,
-            listen_addr,
-            peer_addrs,
+            listen_addr: committee.address(id).expect("validator not in committee").to_string(),
+            handshake: Arc::new(Handshake::new(Arc::new(PeerSet::new(id, committee)), identity)),
+            pending: Arc::new(PendingRequests::default()),
             metrics: Arc::new(Mutex::new(NetMetrics {
                 ser_micros: 0, deser_micros: 0, bytes_sent: 0, bytes_recv: 0
             })),
@@ -36,101 +367,300 @@ impl TcpNetwork {
     }
 
     pub async fn start(self, event_tx: mpsc::Sender<Event>) -> Arc<NetworkHandle> {
-        let mut peer_senders = HashMap::new();
-        let metrics = self.metrics.clone();
-
-        for (peer_id, addr) in self.peer_addrs {
-            let (tx, mut rx) = mpsc::channel::<Vec<u8>>(100_000);
-            peer_senders.insert(peer_id, tx);
-            let metrics_in = metrics.clone();
-            
-            tokio::spawn(async move {
-                loop {
-                    if let Ok(stream) = TcpStream::connect(&addr).await {
-                        let _ = stream.set_nodelay(true);
-                        let mut writer = BufWriter::new(stream);
-                        while let Some(msg_bytes) = rx.recv().await {
-                            let len = (msg_bytes.len() as u32).to_le_bytes();
-                            if writer.write_all(&len).await.is_err() { break; }
-                            if writer.write_all(&msg_bytes).await.is_err() { break; }
-                            
-                            // Only flush if no more messages are immediately available
-                            if rx.is_empty() {
-                                if writer.flush().await.is_err() { break; }
-                            }
-                            
-                            {
-                                let mut m = metrics_in.lock();
-                                m.bytes_sent += msg_bytes.len() as u64;
-                            }
-                        }
-                    }
-                    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
-                }
-            });
-        }
+        let handle = Arc::new(NetworkHandle {
+            handshake: self.handshake.clone(),
+            peer_senders: Mutex::new(HashMap::new()),
+            pending: self.pending.clone(),
+            metrics: self.metrics.clone(),
+        });
+        handle.connect_peers();
 
         let listener = TcpListener::bind(&self.listen_addr).await.unwrap();
-        let event_tx_in = event_tx.clone();
-        let metrics_in = metrics.clone();
+        let (handshake, pending, metrics) = (self.handshake, self.pending, self.metrics);
         tokio::spawn(async move {
-            while let Ok((stream, _)) = listener.accept().await {
+            while let Ok((mut stream, addr)) = listener.accept().await {
                 let _ = stream.set_nodelay(true);
-                let tx = event_tx_in.clone();
-                let m_in = metrics_in.clone();
+                let tx = event_tx.clone();
+                let m_in = metrics.clone();
+                let handshake = handshake.clone();
+                let pending = pending.clone();
                 tokio::spawn(async move {
-                    let mut reader = BufReader::new(stream);
-                    let mut len_buf = [0u8; 4];
-                    loop {
-                        if reader.read_exact(&mut len_buf).await.is_err() { break; }
-                        let len = u32::from_le_bytes(len_buf) as usize;
-                        let mut msg_buf = vec![0u8; len];
-                        if reader.read_exact(&mut msg_buf).await.is_err() { break; }
-                        
-                        let start_deser = Instant::now();
-                        let archived = check_archived_root::<Message>(&msg_buf).unwrap();
-                        let msg: Message = archived.deserialize(&mut rkyv::Infallible).unwrap();
-                        let deser_elapsed = start_deser.elapsed().as_micros() as u64;
-
-                        {
-                            let mut m = m_in.lock();
-                            m.deser_micros += deser_elapsed;
-                            m.bytes_recv += len as u64;
-                        }
-
-                        match msg {
-                            Message::Vertex(v) => { let _ = tx.send(Event::VertexReceived(v)).await; }
-                            Message::CoA(coa) => { let _ = tx.send(Event::CoAReceived(coa)).await; }
-                            _ => {}
+                    let accepted = tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake.accept(&mut stream)).await
+                        .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "handshake timed out")));
+                    let (key, mac) = match accepted {
+                        Ok(session) => session,
+                        Err(e) => {
+                            eprintln!("node {}: rejected connection from {}: {}", handshake.peers.id(), addr, e);
+                            return;
                         }
-                    }
+                    };
+                    receive(BufReader::new(stream), handshake.peers.clone(), key, Some(mac), tx, pending, m_in).await;
                 });
             }
         });
+        handle
+    }
+}
+
+/// Keep a connection to the peer holding `key` at `addr` and write out what
+/// `rx` queues, until the peer leaves the committee or the handle stops
+/// sending to it.
+async fn dial_peer(handshake: Arc<Handshake>, key: PeerKey, addr: String, mut rx: mpsc::Receiver<Vec<u8>>, metrics: Arc<Mutex<NetMetrics>>) {
+    while handshake.peers.id_of(&key).is_some() {
+        if let Ok(mut stream) = TcpStream::connect(&addr).await {
+            let _ = stream.set_nodelay(true);
+            let dialed = tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake.dial(&mut stream, &key)).await
+                .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "handshake timed out")));
+            let mut mac = match dialed {
+                Ok(mac) => mac,
+                Err(e) => {
+                    eprintln!("node {}: handshake with {} failed: {}", handshake.peers.id(), addr, e);
+                    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+                    continue;
+                }
+            };
+            let mut writer = BufWriter::new(stream);
+            loop {
+                let Some(msg_bytes) = rx.recv().await else { return };
+                let len = (msg_bytes.len() as u32).to_le_bytes();
+                if writer.write_all(&len).await.is_err() { break; }
+                if writer.write_all(&msg_bytes).await.is_err() { break; }
+                if writer.write_all(mac.tag(&msg_bytes).as_bytes()).await.is_err() { break; }
 
-        Arc::new(NetworkHandle {
-            id: self.id,
-            peer_senders,
-            metrics,
-        })
+                // Only flush if no more messages are immediately available
+                if rx.is_empty() {
+                    if writer.flush().await.is_err() { break; }
+                }
+
+                {
+                    let mut m = metrics.lock();
+                    m.bytes_sent += msg_bytes.len() as u64;
+                }
+            }
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
     }
 }
 
 pub struct NetworkHandle {
-    pub id: ValidatorId,
-    pub peer_senders: HashMap<ValidatorId, mpsc::Sender<Vec<u8>>>,
+    pub handshake: Arc<Handshake>,
+    /// Outgoing queue of every peer, by key.
+    peer_senders: Mutex<HashMap<PeerKey, mpsc::Sender<Vec<u8>>>>,
+    pub pending: Arc<PendingRequests>,
     pub metrics: Arc<Mutex<NetMetrics>>,
 }
 
 impl NetworkHandle {
+    /// Switch to `committee`, in which we are `id`: dial the validators that
+    /// joined, stop sending to the ones that left, and let in only its keys.
+    pub fn reconfigure(&self, id: ValidatorId, committee: &Committee) {
+        self.handshake.peers.reconfigure(id, committee);
+        self.connect_peers();
+    }
+
+    /// A dialer per current peer; dropping a queue ends its dialer.
+    fn connect_peers(&self) {
+        let peers = self.handshake.peers.peers();
+        let mut senders = self.peer_senders.lock();
+        senders.retain(|key, _| peers.iter().any(|(peer, _)| peer == key));
+        for (key, addr) in peers {
+            senders.entry(key).or_insert_with(|| {
+                let (tx, rx) = mpsc::channel::<Vec<u8>>(100_000);
+                tokio::spawn(dial_peer(self.handshake.clone(), key, addr, rx, self.metrics.clone()));
+                tx
+            });
+        }
+    }
+
     pub async fn broadcast_raw(&self, msg_bytes: Vec<u8>) {
         // Fire-and-forget: send to the MPMC channel, the spawned task handles the TCP write.
-        for sender in self.peer_senders.values() {
+        let senders: Vec<_> = self.peer_senders.lock().values().cloned().collect();
+        for sender in senders {
             let _ = sender.send(msg_bytes.clone()).await;
         }
     }
+
+    /// Fire-and-forget to one peer; ignored if `peer` is not in the committee.
+    pub async fn send_to(&self, peer: ValidatorId, msg_bytes: Vec<u8>) {
+        let sender = self.handshake.peers.key_of(peer).and_then(|key| self.peer_senders.lock().get(&key).cloned());
+        if let Some(sender) = sender {
+            let _ = sender.send(msg_bytes).await;
+        }
+    }
     
     pub fn get_metrics(&self) -> NetMetrics {
         self.metrics.lock().clone()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::committee::{test_coin, test_committee, ValidatorInfo};
+    use crate::types::{CoA, Vertex};
+    use rkyv::ser::serializers::AllocSerializer;
+    use rkyv::ser::Serializer;
+
+    /// test_committee gives validator i the ed25519 key seeded with i + 1.
+    fn key(seed: u8) -> PeerKey {
+        SigningKey::from_bytes(&[seed; 32]).verifying_key().to_bytes()
+    }
+
+    fn handshake(id: ValidatorId, key_seed: u8) -> Handshake {
+        Handshake::new(Arc::new(PeerSet::new(id, &test_committee(4))), SigningKey::from_bytes(&[key_seed; 32]))
+    }
+
+    async fn connect(dialer: &Handshake, acceptor: &Handshake, expected: PeerKey) -> (io::Result<FrameMac>, io::Result<(PeerKey, FrameMac)>) {
+        // Each side hangs up when it is done, as a dropped connection would
+        let (mut a, mut b) = tokio::io::duplex(1024);
+        tokio::join!(
+            async move { dialer.dial(&mut a, &expected).await },
+            async move { acceptor.accept(&mut b).await },
+        )
+    }
+
+    #[tokio::test]
+    async fn test_handshake_authenticates_both_sides() {
+        let (dialed, accepted) = connect(&handshake(1, 2), &handshake(2, 3), key(3)).await;
+        assert!(dialed.is_ok());
+        assert_eq!(accepted.unwrap().0, key(2));
+
+        // A key outside the committee
+        let (_, accepted) = connect(&handshake(1, 9), &handshake(2, 3), key(3)).await;
+        assert!(accepted.is_err());
+        // Answering for a validator without its key, or being the wrong one
+        let (dialed, _) = connect(&handshake(1, 2), &handshake(2, 9), key(3)).await;
+        assert!(dialed.is_err());
+        let (dialed, _) = connect(&handshake(1, 2), &handshake(3, 4), key(3)).await;
+        assert!(dialed.is_err());
+
+        // Once the committee drops validator 1, its key is refused
+        let acceptor = handshake(2, 3);
+        let committee = test_committee(4);
+        let validators: Vec<_> = committee.validators[2..].iter().enumerate().map(|(i, v)| ValidatorInfo { id: i as ValidatorId, ..v.clone() }).collect();
+        acceptor.peers.reconfigure(0, &committee.next(1, validators.clone(), test_coin(&validators)).unwrap());
+        let (_, accepted) = connect(&handshake(1, 2), &acceptor, key(3)).await;
+        assert!(accepted.is_err());
+        let (_, accepted) = connect(&handshake(3, 4), &acceptor, key(3)).await;
+        assert_eq!(accepted.unwrap().0, key(4));
+    }
+
+    /// Validator 1 dials validator 2, whose reader is left running: the
+    /// dialer's end of the stream, its MAC, the reader, and what it delivers.
+    async fn session() -> (tokio::io::DuplexStream, FrameMac, tokio::task::JoinHandle<()>, mpsc::Receiver<Event>) {
+        let (dialer, acceptor) = (handshake(1, 2), handshake(2, 3));
+        let (mut a, mut b) = tokio::io::duplex(4096);
+        let expected = key(3);
+        let (dialed, accepted) = tokio::join!(dialer.dial(&mut a, &expected), acceptor.accept(&mut b));
+        let (tx, rx) = mpsc::channel(10);
+        let metrics = Arc::new(Mutex::new(NetMetrics { ser_micros: 0, deser_micros: 0, bytes_sent: 0, bytes_recv: 0 }));
+        let read = tokio::spawn(receive(b, acceptor.peers.clone(), key(2), Some(accepted.unwrap().1), tx, Arc::default(), metrics));
+        (a, dialed.unwrap(), read, rx)
+    }
+
+    fn frame(mac: &mut FrameMac, msg_bytes: &[u8]) -> Vec<u8> {
+        let tag = mac.tag(msg_bytes);
+        [&(msg_bytes.len() as u32).to_le_bytes()[..], msg_bytes, tag.as_bytes()].concat()
+    }
+
+    #[tokio::test]
+    async fn test_tcp_frames_are_authenticated_in_order() {
+        let (mut stream, mut mac, read, mut events) = session().await;
+        let first = frame(&mut mac, &vertex(1));
+        stream.write_all(&first).await.unwrap();
+        assert!(matches!(next(&mut events).await, Event::VertexReceived(v) if v.author == 1));
+        // The same frame again is out of place
+        stream.write_all(&first).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(1), read).await.unwrap().unwrap();
+        assert!(events.try_recv().is_err());
+
+        // So is a tag altered on the way
+        let (mut stream, mut mac, read, mut events) = session().await;
+        let mut altered = frame(&mut mac, &vertex(1));
+        *altered.last_mut().unwrap() ^= 1;
+        stream.write_all(&altered).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(1), read).await.unwrap().unwrap();
+        assert!(events.try_recv().is_err());
+    }
+
+    fn serialize(msg: &Message) -> Vec<u8> {
+        let mut ser = AllocSerializer::<1024>::default();
+        ser.serialize_value(msg).unwrap();
+        ser.into_serializer().into_inner().to_vec()
+    }
+
+    fn vertex(author: ValidatorId) -> Vec<u8> {
+        serialize(&Message::Vertex(Vertex { epoch: 1, round: 1, author, batch_hash: [1; 32], parents: vec![], reconfiguration: None }))
+    }
+
+    async fn next(rx: &mut mpsc::Receiver<Event>) -> Event {
+        tokio::time::timeout(Duration::from_secs(10), rx.recv()).await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_tcp_peers_follow_the_committee_across_epochs() {
+        // test_committee(4) on ports of its own. Genesis is validators 0 to 2;
+        // epoch 1 drops validator 0 and adds validator 3, so 1 and 2 move to ids 0 and 1
+        let mut all = test_committee(4);
+        for (i, v) in all.validators.iter_mut().enumerate() {
+            v.address = format!("127.0.0.1:{}", 21970 + i);
+        }
+        let committee = |epoch: u64, members: &[usize]| {
+            let validators: Vec<_> = members.iter().enumerate().map(|(id, i)| ValidatorInfo { id: id as ValidatorId, ..all.validators[*i].clone() }).collect();
+            all.next(epoch, validators.clone(), test_coin(&validators)).unwrap()
+        };
+        let (genesis, epoch1) = (committee(0, &[0, 1, 2]), committee(1, &[1, 2, 3]));
+
+        let mut handles = Vec::new();
+        let mut receivers = Vec::new();
+        for (id, seed, committee) in [(0, 1, &genesis), (1, 2, &genesis), (2, 3, &genesis), (2, 4, &epoch1)] {
+            let (tx, rx) = mpsc::channel(100);
+            handles.push(TcpNetwork::new(id, committee, SigningKey::from_bytes(&[seed; 32])).start(tx).await);
+            receivers.push(rx);
+        }
+        let (leaver, a, b, joiner) = (&handles[0], &handles[1], &handles[2], &handles[3]);
+        let a_events = &mut receivers[1];
+
+        b.send_to(1, serialize(&Message::SkipVote(0, 3, 0, 2, vec![]))).await;
+        assert!(matches!(next(a_events).await, Event::SkipVoteReceived(_, _, _, 2, _)));
+
+        // The joiner is shut out of the genesis committee
+        joiner.broadcast_raw(vertex(2)).await;
+        tokio::time::sleep(Duration::from_millis(700)).await;
+        assert!(a_events.try_recv().is_err());
+
+        // Until its members move to epoch 1: then its queued vertex gets through,
+        // a stayer's votes carry its new id, and the leaver is no longer heard
+        a.reconfigure(0, &epoch1);
+        b.reconfigure(1, &epoch1);
+        assert_eq!(a.handshake.peers.id(), 0);
+        assert!(matches!(next(a_events).await, Event::VertexReceived(v) if v.author == 2));
+        b.send_to(0, serialize(&Message::SkipVote(1, 3, 0, 1, vec![]))).await;
+        assert!(matches!(next(a_events).await, Event::SkipVoteReceived(_, _, _, 1, _)));
+        leaver.broadcast_raw(vertex(0)).await;
+        joiner.send_to(0, serialize(&Message::SkipVote(1, 3, 0, 2, vec![]))).await;
+        assert!(matches!(next(a_events).await, Event::SkipVoteReceived(_, _, _, 2, _)));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(a_events.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_votes_must_come_from_their_author() {
+        let coa = |voters: &[ValidatorId]| Message::CoA(CoA { batch_hash: [0; 32], signatures: voters.iter().map(|v| (*v, vec![])).collect() });
+        assert!(sent_by_author(&coa(&[1]), 1));
+        assert!(!sent_by_author(&coa(&[1, 2]), 1));
+        assert!(sent_by_author(&Message::SkipVote(0, 3, 0, 1, vec![]), 1));
+        assert!(!sent_by_author(&Message::SkipVote(0, 3, 0, 2, vec![]), 1));
+    }
+
+    #[tokio::test]
+    async fn test_oversized_frame_closes_connection() {
+        let (mut peer, reader) = tokio::io::duplex(64);
+        peer.write_all(&u32::MAX.to_le_bytes()).await.unwrap();
+        let (tx, _rx) = mpsc::channel(1);
+        let metrics = Arc::new(Mutex::new(NetMetrics { ser_micros: 0, deser_micros: 0, bytes_sent: 0, bytes_recv: 0 }));
+        // The peer keeps the stream open; only the length check ends the read
+        let read = receive(reader, Arc::new(PeerSet::new(0, &test_committee(4))), key(2), None, tx, Arc::default(), metrics);
+        assert!(tokio::time::timeout(Duration::from_secs(1), read).await.is_ok());
+    }
+}
@@ -0,0 +1,284 @@
+// Pacemaker: per-round anchor timers that drive the skip path
+// Emits Event::Timeout(round) when a round's anchor is not certified in time,
+// and again with backoff for as long as it stays late
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use crate::types::Event;
+
+/// Monotonic time source, so the same pacemaker runs on tokio or on a virtual clock.
+pub trait Clock {
+    fn now(&self) -> Duration;
+}
+
+/// Wall-clock time from tokio's timer (honours `tokio::time::pause` in tests).
+pub struct TokioClock {
+    start: tokio::time::Instant,
+}
+
+impl TokioClock {
+    pub fn new() -> Self {
+        Self { start: tokio::time::Instant::now() }
+    }
+}
+
+impl Default for TokioClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for TokioClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+/// Manually advanced clock for deterministic tests and simulations.
+/// Clones share the same time.
+#[derive(Clone, Default)]
+pub struct VirtualClock {
+    now_micros: Arc<AtomicU64>,
+}
+
+impl VirtualClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.now_micros.fetch_add(by.as_micros() as u64, Ordering::SeqCst);
+    }
+
+    pub fn set(&self, to: Duration) {
+        self.now_micros.store(to.as_micros() as u64, Ordering::SeqCst);
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> Duration {
+        Duration::from_micros(self.now_micros.load(Ordering::SeqCst))
+    }
+}
+
+pub struct Pacemaker<C: Clock> {
+    clock: C,
+    pub base_timeout: Duration,
+    pub max_timeout: Duration,
+    /// Round being watched, when its timer expires, and how often it has fired.
+    armed: Option<(u64, Duration, u32)>,
+    /// Fallback depth at the last update; repeated timeouts back off from it.
+    fallback_depth: u32,
+}
+
+impl<C: Clock> Pacemaker<C> {
+    pub fn new(clock: C, base_timeout: Duration, max_timeout: Duration) -> Self {
+        Self {
+            clock,
+            base_timeout,
+            max_timeout,
+            armed: None,
+            fallback_depth: 0,
+        }
+    }
+
+    /// Timeout doubles for every consecutive skipped anchor, capped at `max_timeout`.
+    pub fn timeout_for(&self, fallback_depth: u32) -> Duration {
+        let factor = 1u32.checked_shl(fallback_depth.min(16)).unwrap_or(u32::MAX);
+        self.base_timeout.saturating_mul(factor).min(self.max_timeout)
+    }
+
+    /// Point the timer at the round whose anchor we are waiting for
+    /// (`ConsensusState::waiting_anchor_round`). A new round re-arms the timer;
+    /// `None` means nothing is outstanding and disarms it.
+    pub fn update(&mut self, waiting_round: Option<u64>, fallback_depth: u32) {
+        self.fallback_depth = fallback_depth;
+        match waiting_round {
+            None => self.armed = None,
+            Some(round) if self.armed_round() != Some(round) => {
+                let deadline = self.clock.now() + self.timeout_for(fallback_depth);
+                self.armed = Some((round, deadline, 0));
+            }
+            Some(_) => {}
+        }
+    }
+
+    /// Fire the armed timer if it has expired, and re-arm it with a doubled
+    /// timeout: until the round moves on, each firing re-sends its skip vote.
+    pub fn poll(&mut self) -> Option<Event> {
+        let (round, deadline, fired) = self.armed?;
+        let now = self.clock.now();
+        if now < deadline {
+            return None;
+        }
+        let fired = fired.saturating_add(1);
+        let retry = self.timeout_for(self.fallback_depth.saturating_add(fired));
+        self.armed = Some((round, now + retry, fired));
+        Some(Event::Timeout(round))
+    }
+
+    /// Time left until the armed timer fires, for callers that want to sleep on it.
+    pub fn time_to_deadline(&self) -> Option<Duration> {
+        self.armed.map(|(_, deadline, _)| deadline.saturating_sub(self.clock.now()))
+    }
+
+    pub fn armed_round(&self) -> Option<u64> {
+        self.armed.map(|(r, _, _)| r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consensus::{is_anchor_round, ConsensusState};
+    use crate::crypto::hash_vertex;
+    use crate::types::{AggregatedCoA, CoA, Hash, SignerBitmap, SkipCert, Vertex, ValidatorId};
+
+    fn pacemaker(clock: &VirtualClock) -> Pacemaker<VirtualClock> {
+        Pacemaker::new(clock.clone(), Duration::from_millis(100), Duration::from_secs(2))
+    }
+
+    #[test]
+    fn test_fires_after_timeout() {
+        let clock = VirtualClock::new();
+        let mut pm = pacemaker(&clock);
+        pm.update(Some(1), 0);
+        clock.advance(Duration::from_millis(99));
+        assert!(pm.poll().is_none());
+        clock.advance(Duration::from_millis(1));
+        assert!(matches!(pm.poll(), Some(Event::Timeout(1))));
+        assert!(pm.poll().is_none());
+        // While the round is still waiting it fires again, backing off
+        pm.update(Some(1), 0);
+        clock.advance(Duration::from_millis(199));
+        assert!(pm.poll().is_none());
+        clock.advance(Duration::from_millis(1));
+        assert!(matches!(pm.poll(), Some(Event::Timeout(1))));
+        assert_eq!(pm.time_to_deadline(), Some(Duration::from_millis(400)));
+        // Progress resets the backoff
+        pm.update(Some(2), 0);
+        assert_eq!(pm.time_to_deadline(), Some(Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn test_backoff_grows_with_fallback_depth() {
+        let clock = VirtualClock::new();
+        let pm = pacemaker(&clock);
+        assert_eq!(pm.timeout_for(0), Duration::from_millis(100));
+        assert_eq!(pm.timeout_for(2), Duration::from_millis(400));
+        assert_eq!(pm.timeout_for(10), Duration::from_secs(2));
+        assert_eq!(pm.timeout_for(u32::MAX), Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_progress_rearms_timer() {
+        let clock = VirtualClock::new();
+        let mut pm = pacemaker(&clock);
+        pm.update(Some(1), 0);
+        clock.advance(Duration::from_millis(80));
+        pm.update(Some(2), 0);
+        clock.advance(Duration::from_millis(80));
+        assert!(pm.poll().is_none());
+        assert_eq!(pm.armed_round(), Some(2));
+        pm.update(None, 0);
+        clock.advance(Duration::from_secs(1));
+        assert!(pm.poll().is_none());
+    }
+
+    /// Four nodes, the round-2 anchor crashed. Timeouts produce skip votes, the
+    /// skip certificate forms, and the DAG keeps committing behind it.
+    #[test]
+    fn test_crashed_anchor_is_skipped() {
+        let n = 4;
+        let clock = VirtualClock::new();
+        let keys = crate::bls_crypto::test_keys(n);
+        let committee = Arc::new(crate::committee::test_committee(n));
+        let mut nodes: Vec<ConsensusState> = (0..n as u32).map(|id| ConsensusState::new(id, committee.clone())).collect();
+        let mut pms: Vec<_> = (0..n).map(|_| pacemaker(&clock)).collect();
+        let shares: Vec<_> = (0..n).map(|id| committee.coin.decrypt_share(id as ValidatorId, &keys[id]).unwrap()).collect();
+        // Any threshold of shares reveals the same coin, so learn it from the first ones
+        let mut scratch = ConsensusState::new(0, committee.clone());
+        for id in 0..committee.coin.threshold() {
+            let share = shares[id].sign(&scratch.coin_message(2));
+            scratch.on_event(Event::CoinShareReceived(0, 2, id as ValidatorId, share));
+        }
+        let crashed = scratch.anchor_for_round(2).unwrap();
+        let live: Vec<ValidatorId> = (0..n as u32).filter(|&id| id != crashed).collect();
+
+        let add_round = |nodes: &mut [ConsensusState], round: u64| {
+            let parents = if round > 1 { nodes[live[0] as usize].dag.certified_in_round(round - 1) } else { vec![] };
+            let hashes: Vec<(Hash, ValidatorId)> = live.iter().map(|&author| {
+                let v = Vertex { epoch: 0, round, author, batch_hash: [0u8; 32], parents: parents.clone(), reconfiguration: None };
+                for &id in &live {
+                    nodes[id as usize].on_event(Event::VertexReceived(v.clone()));
+                }
+                (hash_vertex(&v), author)
+            }).collect();
+            for &id in &live {
+                nodes[id as usize].round = round;
+                for (h, author) in &hashes {
+                    let signature = keys[*author as usize].sign(&nodes[id as usize].vote_message(h));
+                    nodes[id as usize].on_event(Event::CoAReceived(CoA { batch_hash: *h, signatures: vec![(*author, signature)] }));
+                    nodes[id as usize].certify_vertex(AggregatedCoA { epoch: 0, batch_hash: *h, aggregated_signature: vec![], signer_bitmap: SignerBitmap::from_ids(n, 0..n as ValidatorId).unwrap() });
+                }
+            }
+        };
+
+        add_round(&mut nodes, 1);
+        let mut round = 1;
+        for step in 0..200 {
+            clock.advance(Duration::from_millis(50));
+            let mut votes = Vec::new();
+            let mut coin_shares = Vec::new();
+            let mut certs: Vec<SkipCert> = Vec::new();
+            for &id in &live {
+                let (state, pm) = (&mut nodes[id as usize], &mut pms[id as usize]);
+                pm.update(state.waiting_anchor_round(), state.fallback_depth);
+                if let Some(ev) = pm.poll() {
+                    state.on_event(ev);
+                }
+                votes.extend(state.take_pending_skip_votes().into_iter().map(|(r, a)| (r, a, id)));
+                coin_shares.extend(state.take_pending_coin_shares().into_iter().map(|r| (r, id)));
+                certs.extend(state.take_new_skip_certs());
+            }
+            for &(r, a, voter) in &votes {
+                for &id in &live {
+                    let signature = keys[voter as usize].sign(&nodes[id as usize].skip_vote_message(r, a));
+                    nodes[id as usize].on_event(Event::SkipVoteReceived(0, r, a, voter, signature));
+                }
+            }
+            for &(r, voter) in &coin_shares {
+                for &id in &live {
+                    let share = shares[voter as usize].sign(&nodes[id as usize].coin_message(r));
+                    nodes[id as usize].on_event(Event::CoinShareReceived(0, r, voter, share));
+                }
+            }
+            for cert in certs {
+                for &id in &live {
+                    nodes[id as usize].on_event(Event::SkipCertReceived(cert.clone()));
+                }
+            }
+            if step % 10 == 9 && round < 10 {
+                round += 1;
+                add_round(&mut nodes, round);
+            }
+        }
+
+        let reference = &nodes[live[0] as usize];
+        assert!(reference.skip_certs.contains_key(&2));
+        // Every live anchor up to the last supported round has committed
+        let last_live = (1..10).rev().filter(|&r| is_anchor_round(r)).find(|&r| reference.anchor_for_round(r) != Some(crashed));
+        assert_eq!(Some(reference.dag.committed_round), last_live);
+        assert!(reference.dag.committed_round >= 5);
+        // Only the crashed validator's rounds were skipped
+        for cert in reference.skip_certs.values() {
+            assert_eq!(cert.anchor_index, crashed);
+        }
+        for &id in &live {
+            let state = &nodes[id as usize];
+            assert_eq!(state.committed_anchors, reference.committed_anchors);
+            assert_eq!(state.committed_log, reference.committed_log);
+        }
+    }
+}
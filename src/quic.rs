@@ -0,0 +1,427 @@
+// QUIC transport: the TcpNetwork handle API over quinn, one connection per peer
+// Vertices and votes/certificates travel on separate streams, so a lost packet on one never stalls the other
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use ed25519_dalek::SigningKey;
+use parking_lot::Mutex;
+use quinn::{Connection, Endpoint};
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::server::{ClientCertVerified, ClientCertVerifier};
+use rustls::{Certificate, CertificateError, DistinguishedName, PrivateKey, ServerName};
+use tokio::sync::mpsc;
+use crate::committee::Committee;
+use crate::net::{receive, NetMetrics, PeerKey, PeerSet, PendingRequests};
+use crate::transport::Lane;
+use crate::types::{Event, ValidatorId};
+
+/// Every certificate names this host; peers are told apart by key, not name.
+const SERVER_NAME: &str = "peer.sublyne";
+const KEEP_ALIVE: Duration = Duration::from_secs(2);
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+const RECONNECT_DELAY: Duration = Duration::from_millis(500);
+
+/// PKCS#8 v1 wrapping of a raw ed25519 seed (RFC 8410).
+const ED25519_PKCS8_PREFIX: [u8; 16] = [0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x22, 0x04, 0x20];
+/// Contents of an ed25519 SubjectPublicKeyInfo, up to the key itself.
+const ED25519_SPKI_PREFIX: [u8; 10] = [0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00];
+
+/// TLS identities: each validator presents a self-signed certificate for its
+/// committee ed25519 key, and accepts exactly the other keys of the current
+/// committee.
+struct CommitteeCerts {
+    peers: Arc<PeerSet>,
+}
+
+impl CommitteeCerts {
+    /// Key of a certificate held by a current peer.
+    fn peer_of_cert(&self, cert: &Certificate) -> Option<PeerKey> {
+        ed25519_key_of(&cert.0).filter(|key| self.peers.id_of(key).is_some())
+    }
+
+    /// The authenticated peer at the other end of `connection`.
+    fn peer_of(&self, connection: &Connection) -> Option<PeerKey> {
+        let certs = connection.peer_identity()?.downcast::<Vec<Certificate>>().ok()?;
+        self.peer_of_cert(certs.first()?)
+    }
+
+    fn check(&self, cert: &Certificate) -> Result<(), rustls::Error> {
+        match self.peer_of_cert(cert) {
+            Some(_) => Ok(()),
+            None => Err(rustls::Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure)),
+        }
+    }
+}
+
+// The TLS 1.3 handshake signature, checked by the default trait methods,
+// proves the peer holds the key its certificate names.
+impl ServerCertVerifier for CommitteeCerts {
+    fn verify_server_cert(&self, end_entity: &Certificate, _: &[Certificate], _: &ServerName, _: &mut dyn Iterator<Item = &[u8]>, _: &[u8], _: SystemTime) -> Result<ServerCertVerified, rustls::Error> {
+        self.check(end_entity).map(|_| ServerCertVerified::assertion())
+    }
+}
+
+impl ClientCertVerifier for CommitteeCerts {
+    fn client_auth_root_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(&self, end_entity: &Certificate, _: &[Certificate], _: SystemTime) -> Result<ClientCertVerified, rustls::Error> {
+        self.check(end_entity).map(|_| ClientCertVerified::assertion())
+    }
+}
+
+/// The ed25519 key of a DER certificate: walks Certificate, TBSCertificate and
+/// the fields before subjectPublicKeyInfo rather than searching for the key.
+fn ed25519_key_of(cert: &[u8]) -> Option<[u8; 32]> {
+    let (0x30, cert, _) = der_element(cert)? else { return None };
+    let (0x30, mut tbs, _) = der_element(cert)? else { return None };
+    if tbs.first() == Some(&0xa0) {
+        tbs = der_element(tbs)?.2; // version
+    }
+    // serialNumber, signature, issuer, validity, subject
+    for _ in 0..5 {
+        tbs = der_element(tbs)?.2;
+    }
+    let (0x30, spki, _) = der_element(tbs)? else { return None };
+    spki.strip_prefix(&ED25519_SPKI_PREFIX[..])?.try_into().ok()
+}
+
+/// (tag, contents, rest) of the DER element at the start of `input`.
+fn der_element(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, input) = input.split_first()?;
+    let (&first, mut input) = input.split_first()?;
+    let len = if first < 0x80 {
+        first as usize
+    } else {
+        let n = (first & 0x7f) as usize;
+        if n == 0 || n > 4 || input.len() < n {
+            return None;
+        }
+        let len = input[..n].iter().fold(0usize, |len, b| (len << 8) | *b as usize);
+        input = &input[n..];
+        len
+    };
+    (input.len() >= len).then(|| (tag, &input[..len], &input[len..]))
+}
+
+/// Self-signed certificate for `identity`; peers only look at its key.
+fn certificate(identity: &SigningKey) -> (Certificate, PrivateKey) {
+    let mut pkcs8 = ED25519_PKCS8_PREFIX.to_vec();
+    pkcs8.extend(identity.to_bytes());
+    let mut params = rcgen::CertificateParams::new(vec![SERVER_NAME.to_string()]);
+    params.alg = &rcgen::PKCS_ED25519;
+    params.key_pair = Some(rcgen::KeyPair::from_der(&pkcs8).expect("ed25519 key pair"));
+    let cert = rcgen::Certificate::from_params(params).expect("ed25519 certificate");
+    (Certificate(cert.serialize_der().expect("ed25519 certificate")), PrivateKey(pkcs8))
+}
+
+fn transport_config() -> Arc<quinn::TransportConfig> {
+    let mut transport = quinn::TransportConfig::default();
+    transport.keep_alive_interval(Some(KEEP_ALIVE));
+    transport.max_idle_timeout(Some(IDLE_TIMEOUT.try_into().unwrap()));
+    Arc::new(transport)
+}
+
+/// Server and client configs for one validator. ALPN carries the chain id,
+/// so nodes of different chains never connect.
+fn endpoint_configs(certs: Arc<CommitteeCerts>, identity: &SigningKey) -> (quinn::ServerConfig, quinn::ClientConfig) {
+    let (cert, key) = certificate(identity);
+    let alpn = vec![format!("sublyne/{}", certs.peers.chain_id).into_bytes()];
+
+    let mut server_crypto = rustls::ServerConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .unwrap()
+        .with_client_cert_verifier(certs.clone())
+        .with_single_cert(vec![cert.clone()], key.clone())
+        .expect("ed25519 server certificate");
+    server_crypto.alpn_protocols = alpn.clone();
+    let mut server = quinn::ServerConfig::with_crypto(Arc::new(server_crypto));
+    server.transport_config(transport_config());
+
+    let mut client_crypto = rustls::ClientConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .unwrap()
+        .with_custom_certificate_verifier(certs)
+        .with_client_auth_cert(vec![cert], key)
+        .expect("ed25519 client certificate");
+    client_crypto.alpn_protocols = alpn;
+    let mut client = quinn::ClientConfig::new(Arc::new(client_crypto));
+    client.transport_config(transport_config());
+    (server, client)
+}
+
+pub struct QuicNetwork {
+    pub listen_addr: String,
+    pub pending: Arc<PendingRequests>,
+    pub metrics: Arc<Mutex<NetMetrics>>,
+    certs: Arc<CommitteeCerts>,
+    server_config: quinn::ServerConfig,
+    client_config: quinn::ClientConfig,
+}
+
+impl QuicNetwork {
+    /// `identity` is this validator's ed25519 key, as registered in `committee`.
+    pub fn new(id: ValidatorId, committee: &Committee, identity: SigningKey) -> Self {
+        let certs = Arc::new(CommitteeCerts { peers: Arc::new(PeerSet::new(id, committee)) });
+        let (server_config, client_config) = endpoint_configs(certs.clone(), &identity);
+        Self {
+            listen_addr: committee.address(id).expect("validator not in committee").to_string(),
+            pending: Arc::new(PendingRequests::default()),
+            metrics: Arc::new(Mutex::new(NetMetrics {
+                ser_micros: 0, deser_micros: 0, bytes_sent: 0, bytes_recv: 0
+            })),
+            certs,
+            server_config,
+            client_config,
+        }
+    }
+
+    pub async fn start(self, event_tx: mpsc::Sender<Event>) -> Arc<QuicHandle> {
+        let listen_addr: SocketAddr = tokio::net::lookup_host(&self.listen_addr).await.ok()
+            .and_then(|mut addrs| addrs.next())
+            .expect("unresolvable listen address");
+        let mut endpoint = Endpoint::server(self.server_config, listen_addr).unwrap();
+        endpoint.set_default_client_config(self.client_config);
+
+        let handle = Arc::new(QuicHandle {
+            endpoint: endpoint.clone(),
+            certs: self.certs.clone(),
+            peer_senders: Mutex::new(HashMap::new()),
+            pending: self.pending.clone(),
+            metrics: self.metrics.clone(),
+        });
+        handle.connect_peers();
+
+        let (certs, pending, metrics) = (self.certs, self.pending, self.metrics);
+        tokio::spawn(async move {
+            while let Some(connecting) = endpoint.accept().await {
+                let (certs, tx, pending, metrics) = (certs.clone(), event_tx.clone(), pending.clone(), metrics.clone());
+                tokio::spawn(async move {
+                    let remote = connecting.remote_address();
+                    let connection = match connecting.await {
+                        Ok(connection) => connection,
+                        Err(e) => {
+                            eprintln!("node {}: rejected connection from {}: {}", certs.peers.id(), remote, e);
+                            return;
+                        }
+                    };
+                    let Some(key) = certs.peer_of(&connection) else { return };
+                    while let Ok(stream) = connection.accept_uni().await {
+                        tokio::spawn(receive(stream, certs.peers.clone(), key, None, tx.clone(), pending.clone(), metrics.clone()));
+                    }
+                });
+            }
+        });
+        handle
+    }
+}
+
+/// One peer's connection, shared by its lanes and dialed again when it drops.
+struct PeerLink {
+    key: PeerKey,
+    addr: String,
+    endpoint: Endpoint,
+    certs: Arc<CommitteeCerts>,
+    connection: tokio::sync::Mutex<Option<Connection>>,
+}
+
+impl PeerLink {
+    /// None once the peer has left the committee.
+    async fn connection(&self) -> Option<Connection> {
+        let mut current = self.connection.lock().await;
+        loop {
+            if let Some(connection) = current.as_ref().filter(|c| c.close_reason().is_none()) {
+                return Some(connection.clone());
+            }
+            self.certs.peers.id_of(&self.key)?;
+            match self.dial().await {
+                Ok(connection) => *current = Some(connection),
+                Err(e) => {
+                    eprintln!("node {}: connecting to {} failed: {}", self.certs.peers.id(), self.addr, e);
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                }
+            }
+        }
+    }
+
+    async fn dial(&self) -> Result<Connection, String> {
+        let addr = tokio::net::lookup_host(&self.addr).await.map_err(|e| e.to_string())?
+            .next().ok_or("unresolvable address")?;
+        let connection = self.endpoint.connect(addr, SERVER_NAME).map_err(|e| e.to_string())?
+            .await.map_err(|e| e.to_string())?;
+        match self.certs.peer_of(&connection) {
+            Some(key) if key == self.key => Ok(connection),
+            _ => {
+                connection.close(0u32.into(), b"unexpected peer");
+                Err("reached a different validator".into())
+            }
+        }
+    }
+}
+
+/// Writes one lane's messages to its own stream, opening a new one (and
+/// reconnecting if needed) when a write fails. The failed message is resent.
+async fn send_lane(link: Arc<PeerLink>, mut rx: mpsc::Receiver<Vec<u8>>, metrics: Arc<Mutex<NetMetrics>>) {
+    let mut pending = None;
+    loop {
+        let Some(connection) = link.connection().await else { return };
+        let Ok(mut stream) = connection.open_uni().await else { continue };
+        loop {
+            let msg_bytes = match pending.take() {
+                Some(msg_bytes) => msg_bytes,
+                None => match rx.recv().await {
+                    Some(msg_bytes) => msg_bytes,
+                    None => return,
+                },
+            };
+            let len = (msg_bytes.len() as u32).to_le_bytes();
+            if stream.write_all(&len).await.is_err() || stream.write_all(&msg_bytes).await.is_err() {
+                pending = Some(msg_bytes);
+                break;
+            }
+            metrics.lock().bytes_sent += msg_bytes.len() as u64;
+        }
+    }
+}
+
+pub struct QuicHandle {
+    endpoint: Endpoint,
+    certs: Arc<CommitteeCerts>,
+    /// Per peer key, one sender per `Lane`.
+    peer_senders: Mutex<HashMap<PeerKey, [mpsc::Sender<Vec<u8>>; 2]>>,
+    pub pending: Arc<PendingRequests>,
+    pub metrics: Arc<Mutex<NetMetrics>>,
+}
+
+impl QuicHandle {
+    /// Switch to `committee`, in which we are `id`: dial the validators that
+    /// joined, stop sending to the ones that left, and accept only its keys.
+    pub fn reconfigure(&self, id: ValidatorId, committee: &Committee) {
+        self.certs.peers.reconfigure(id, committee);
+        self.connect_peers();
+    }
+
+    /// A link per current peer; dropping its lanes' senders ends it.
+    fn connect_peers(&self) {
+        let peers = self.certs.peers.peers();
+        let mut senders = self.peer_senders.lock();
+        senders.retain(|key, _| peers.iter().any(|(peer, _)| peer == key));
+        for (key, addr) in peers {
+            senders.entry(key).or_insert_with(|| {
+                let link = Arc::new(PeerLink {
+                    key,
+                    addr,
+                    endpoint: self.endpoint.clone(),
+                    certs: self.certs.clone(),
+                    connection: tokio::sync::Mutex::new(None),
+                });
+                [Lane::Vertices, Lane::Votes].map(|_| {
+                    let (tx, rx) = mpsc::channel::<Vec<u8>>(100_000);
+                    tokio::spawn(send_lane(link.clone(), rx, self.metrics.clone()));
+                    tx
+                })
+            });
+        }
+    }
+
+    pub async fn broadcast_raw(&self, lane: Lane, msg_bytes: Vec<u8>) {
+        let senders: Vec<_> = self.peer_senders.lock().values().map(|lanes| lanes[lane as usize].clone()).collect();
+        for sender in senders {
+            let _ = sender.send(msg_bytes.clone()).await;
+        }
+    }
+
+    /// Fire-and-forget to one peer; ignored if `peer` is not in the committee.
+    pub async fn send_to(&self, peer: ValidatorId, lane: Lane, msg_bytes: Vec<u8>) {
+        let sender = self.certs.peers.key_of(peer).and_then(|key| Some(self.peer_senders.lock().get(&key)?[lane as usize].clone()));
+        if let Some(sender) = sender {
+            let _ = sender.send(msg_bytes).await;
+        }
+    }
+
+    pub fn get_metrics(&self) -> NetMetrics {
+        self.metrics.lock().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bls_crypto::test_keys;
+    use crate::committee::{test_committee, LOCALHOST_CHAIN_ID};
+    use crate::types::{Message, Vertex};
+    use rkyv::ser::serializers::AllocSerializer;
+    use rkyv::ser::Serializer;
+
+    fn serialize(msg: &Message) -> Vec<u8> {
+        let mut ser = AllocSerializer::<1024>::default();
+        ser.serialize_value(msg).unwrap();
+        ser.into_serializer().into_inner().to_vec()
+    }
+
+    fn vertex(author: ValidatorId) -> Vertex {
+        Vertex { epoch: 0, round: 1, author, batch_hash: [1; 32], parents: vec![[2; 32]; 3], reconfiguration: None }
+    }
+
+    #[test]
+    fn test_certificate_carries_the_validator_key() {
+        let identity = SigningKey::from_bytes(&[2; 32]);
+        let (cert, _) = certificate(&identity);
+        assert_eq!(ed25519_key_of(&cert.0), Some(identity.verifying_key().to_bytes()));
+        let certs = CommitteeCerts { peers: Arc::new(PeerSet::new(0, &test_committee(4))) };
+        assert_eq!(certs.peer_of_cert(&cert), Some(identity.verifying_key().to_bytes()));
+        assert_eq!(certs.peer_of_cert(&certificate(&SigningKey::from_bytes(&[9; 32])).0), None);
+        assert!(ed25519_key_of(&cert.0[..cert.0.len() / 2]).is_none());
+    }
+
+    /// Validator i has the ed25519 key seeded with i + 1, as in test_committee.
+    fn seeded_committee(port_offset: u16, ed25519_seeds: &[u8]) -> Committee {
+        let bls = test_keys(ed25519_seeds.len()).iter().map(|sk| (sk.public_key(), sk.prove_possession(LOCALHOST_CHAIN_ID))).collect();
+        let ed = ed25519_seeds.iter().map(|seed| SigningKey::from_bytes(&[*seed; 32]).verifying_key().to_bytes()).collect();
+        Committee::localhost(bls, ed, port_offset).unwrap()
+    }
+
+    async fn next(rx: &mut mpsc::Receiver<Event>) -> Event {
+        tokio::time::timeout(Duration::from_secs(10), rx.recv()).await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_quic_peers_authenticate_and_deliver() {
+        let committee = seeded_committee(21950, &[1, 2, 3]);
+        let mut receivers = Vec::new();
+        let mut handles = Vec::new();
+        for id in 0..3 {
+            let (tx, rx) = mpsc::channel(100);
+            let identity = SigningKey::from_bytes(&[id as u8 + 1; 32]);
+            handles.push(QuicNetwork::new(id, &committee, identity).start(tx).await);
+            receivers.push(rx);
+        }
+        handles[0].broadcast_raw(Lane::Vertices, serialize(&Message::Vertex(vertex(0)))).await;
+        assert!(matches!(next(&mut receivers[1]).await, Event::VertexReceived(v) if v.author == 0));
+        assert!(matches!(next(&mut receivers[2]).await, Event::VertexReceived(v) if v.author == 0));
+
+        // Relayed votes are dropped; the author's own arrive
+        handles[1].broadcast_raw(Lane::Votes, serialize(&Message::SkipVote(0, 1, 2, 0, vec![0; 48]))).await;
+        handles[1].broadcast_raw(Lane::Votes, serialize(&Message::SkipVote(0, 1, 2, 1, vec![0; 48]))).await;
+        assert!(matches!(next(&mut receivers[0]).await, Event::SkipVoteReceived(_, _, _, 1, _)));
+        assert!(matches!(next(&mut receivers[2]).await, Event::SkipVoteReceived(_, _, _, 1, _)));
+
+        // A key outside the committee cannot connect
+        // (its own committee lists it in validator 0's place, with peers 1 and 2 at their real ports)
+        let (tx, mut rx) = mpsc::channel(100);
+        let mut outsider = seeded_committee(21950, &[9, 2, 3]);
+        outsider.validators[0].address = "127.0.0.1:21959".into();
+        let impostor = QuicNetwork::new(0, &outsider, SigningKey::from_bytes(&[9; 32])).start(tx).await;
+        impostor.broadcast_raw(Lane::Vertices, serialize(&Message::Vertex(vertex(0)))).await;
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(receivers[1].try_recv().is_err());
+        assert!(rx.try_recv().is_err());
+    }
+}
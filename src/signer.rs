@@ -0,0 +1,351 @@
+// Consensus signing behind a trait, so validator keys can live outside the node
+// LocalSigner signs in-process; RemoteSigner asks a `sublyne signer` process over a Unix socket
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use async_trait::async_trait;
+use parking_lot::Mutex;
+use rkyv::ser::serializers::AllocSerializer;
+use rkyv::ser::Serializer;
+use rkyv::{check_archived_root, Archive, Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{UnixListener, UnixStream};
+use crate::bls_crypto::BlsSecretKey;
+use crate::coin::CoinDealing;
+use crate::crypto::{hash_vertex, SigningContext, SigningIntent, SigningMessage};
+use crate::slashing::{SignedKind, SignedRecord, SlashingDb};
+use crate::types::{BlsPublicKey, BlsSignature, ValidatorId, Vertex};
+
+/// Largest request or response frame; a vertex names at most a committee of parents.
+const MAX_FRAME: usize = 4 << 20;
+
+/// Longest a remote signer may take to answer one request, dialing included.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Every signature the consensus loop needs. Implementations refuse to sign
+/// anything that contradicts what they signed before.
+#[async_trait]
+pub trait ConsensusSigner: Send + Sync {
+    /// Key the signatures verify under.
+    fn public_key(&self) -> BlsPublicKey;
+
+    /// Vote for `vertex` in its epoch; the signer hashes it itself.
+    async fn sign_vote(&self, vertex: &Vertex) -> Result<BlsSignature, String>;
+
+    /// Vote to skip the anchor of `round` in `epoch`, held by `anchor`.
+    async fn sign_skip_vote(&self, epoch: u64, round: u64, anchor: ValidatorId) -> Result<BlsSignature, String>;
+
+    /// Share of anchor `round`'s coin in `epoch`, signed with our share of
+    /// `coin` as validator `id`.
+    async fn sign_coin_share(&self, epoch: u64, round: u64, coin: &CoinDealing, id: ValidatorId) -> Result<BlsSignature, String>;
+}
+
+/// Key held in this process. Every signature is first recorded in its
+/// slashing protection database, which refuses conflicting messages; signing
+/// the same thing again is allowed, since BLS signatures are deterministic.
+pub struct LocalSigner {
+    key: BlsSecretKey,
+    chain_id: String,
+    slashing_db: Mutex<SlashingDb>,
+}
+
+impl LocalSigner {
+    pub fn new(key: BlsSecretKey, chain_id: &str, slashing_db: SlashingDb) -> Self {
+        Self { key, chain_id: chain_id.to_string(), slashing_db: Mutex::new(slashing_db) }
+    }
+
+    /// Sign `message` once the database accepts `record` for it.
+    fn sign(&self, message: SigningMessage, kind: SignedKind, epoch: u64, round: u64, author: ValidatorId) -> Result<BlsSignature, String> {
+        self.slashing_db.lock().check_and_record(SignedRecord::new(kind, epoch, round, author, &message))?;
+        Ok(self.key.sign(&message))
+    }
+
+    fn context(&self, epoch: u64) -> SigningContext {
+        SigningContext { chain_id: self.chain_id.clone(), epoch }
+    }
+}
+
+#[async_trait]
+impl ConsensusSigner for LocalSigner {
+    fn public_key(&self) -> BlsPublicKey {
+        self.key.public_key()
+    }
+
+    async fn sign_vote(&self, vertex: &Vertex) -> Result<BlsSignature, String> {
+        let message = self.context(vertex.epoch).message(&SigningIntent::VertexCertificate(hash_vertex(vertex)));
+        self.sign(message, SignedKind::Vote, vertex.epoch, vertex.round, vertex.author)
+    }
+
+    async fn sign_skip_vote(&self, epoch: u64, round: u64, anchor: ValidatorId) -> Result<BlsSignature, String> {
+        let message = self.context(epoch).message(&SigningIntent::SkipVote { round, anchor });
+        self.sign(message, SignedKind::SkipVote, epoch, round, anchor)
+    }
+
+    /// Not recorded: a round has one coin message and one share per
+    /// validator, so there is nothing to contradict.
+    async fn sign_coin_share(&self, epoch: u64, round: u64, coin: &CoinDealing, id: ValidatorId) -> Result<BlsSignature, String> {
+        let message = self.context(epoch).message(&SigningIntent::AnchorCoin { round });
+        Ok(coin.decrypt_share(id, &self.key)?.sign(&message))
+    }
+}
+
+#[derive(Archive, Serialize, Deserialize, Debug)]
+#[archive(check_bytes)]
+pub enum SignerRequest {
+    PublicKey,
+    Vote(Vertex),
+    SkipVote(u64, u64, ValidatorId),
+    CoinShare(u64, u64, CoinDealing, ValidatorId),
+}
+
+#[derive(Archive, Serialize, Deserialize, Debug)]
+#[archive(check_bytes)]
+pub enum SignerResponse {
+    PublicKey(BlsPublicKey),
+    Signature(BlsSignature),
+    Refused(String),
+}
+
+/// Key held by a `sublyne signer` process. Requests are serialized over one
+/// connection; the signer's slashing protection covers every node that uses it.
+/// A request that fails or times out drops the connection, and the next one
+/// dials again, so a restarted signer is picked up.
+pub struct RemoteSigner {
+    path: PathBuf,
+    /// None once a request has failed, timed out or been cancelled.
+    stream: tokio::sync::Mutex<Option<UnixStream>>,
+    timeout: Duration,
+    public_key: BlsPublicKey,
+}
+
+impl RemoteSigner {
+    pub async fn connect(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let stream = UnixStream::connect(path).await.map_err(|e| format!("{}: {}", path.display(), e))?;
+        let mut signer = Self {
+            path: path.to_path_buf(),
+            stream: tokio::sync::Mutex::new(Some(stream)),
+            timeout: REQUEST_TIMEOUT,
+            public_key: Vec::new(),
+        };
+        match signer.request(&SignerRequest::PublicKey).await? {
+            SignerResponse::PublicKey(pk) => signer.public_key = pk,
+            other => return Err(format!("signer answered {:?} to a key request", other)),
+        }
+        Ok(signer)
+    }
+
+    async fn request(&self, request: &SignerRequest) -> Result<SignerResponse, String> {
+        let mut slot = self.stream.lock().await;
+        // The stream stays out of its slot until a full answer is read, so one
+        // left mid-frame by an error or a cancelled request is never reused
+        let stream = slot.take();
+        let exchange = async {
+            let mut stream = match stream {
+                Some(stream) => stream,
+                None => UnixStream::connect(&self.path).await?,
+            };
+            write_frame(&mut stream, request).await?;
+            let response = read_frame(&mut stream).await?;
+            Ok::<_, std::io::Error>((stream, response))
+        };
+        match tokio::time::timeout(self.timeout, exchange).await {
+            Ok(Ok((stream, response))) => {
+                *slot = Some(stream);
+                Ok(response)
+            }
+            Ok(Err(e)) => Err(format!("signer {}: {}", self.path.display(), e)),
+            Err(_) => Err(format!("signer {}: no answer within {:?}", self.path.display(), self.timeout)),
+        }
+    }
+
+    async fn signature(&self, request: SignerRequest) -> Result<BlsSignature, String> {
+        match self.request(&request).await? {
+            SignerResponse::Signature(sig) => Ok(sig),
+            SignerResponse::Refused(reason) => Err(format!("signer refused: {}", reason)),
+            other => Err(format!("signer answered {:?} to a signing request", other)),
+        }
+    }
+}
+
+#[async_trait]
+impl ConsensusSigner for RemoteSigner {
+    fn public_key(&self) -> BlsPublicKey {
+        self.public_key.clone()
+    }
+
+    async fn sign_vote(&self, vertex: &Vertex) -> Result<BlsSignature, String> {
+        self.signature(SignerRequest::Vote(vertex.clone())).await
+    }
+
+    async fn sign_skip_vote(&self, epoch: u64, round: u64, anchor: ValidatorId) -> Result<BlsSignature, String> {
+        self.signature(SignerRequest::SkipVote(epoch, round, anchor)).await
+    }
+
+    async fn sign_coin_share(&self, epoch: u64, round: u64, coin: &CoinDealing, id: ValidatorId) -> Result<BlsSignature, String> {
+        self.signature(SignerRequest::CoinShare(epoch, round, coin.clone(), id)).await
+    }
+}
+
+/// Answer signing requests on `listener` until it fails. A connection that
+/// sends a malformed request is dropped.
+pub async fn serve(listener: UnixListener, signer: Arc<LocalSigner>) -> std::io::Result<()> {
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        let signer = signer.clone();
+        tokio::spawn(async move {
+            while let Ok(request) = read_frame::<SignerRequest>(&mut stream).await {
+                let response = match request {
+                    SignerRequest::PublicKey => SignerResponse::PublicKey(signer.public_key()),
+                    SignerRequest::Vote(vertex) => signer.sign_vote(&vertex).await
+                        .map_or_else(SignerResponse::Refused, SignerResponse::Signature),
+                    SignerRequest::SkipVote(epoch, round, anchor) => signer.sign_skip_vote(epoch, round, anchor).await
+                        .map_or_else(SignerResponse::Refused, SignerResponse::Signature),
+                    SignerRequest::CoinShare(epoch, round, coin, id) => signer.sign_coin_share(epoch, round, &coin, id).await
+                        .map_or_else(SignerResponse::Refused, SignerResponse::Signature),
+                };
+                if write_frame(&mut stream, &response).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+/// Length-prefixed rkyv, as on the consensus network.
+async fn write_frame<T>(stream: &mut UnixStream, value: &T) -> std::io::Result<()>
+where
+    T: Serialize<AllocSerializer<1024>>,
+{
+    let mut ser = AllocSerializer::<1024>::default();
+    ser.serialize_value(value).unwrap();
+    let bytes = ser.into_serializer().into_inner();
+    stream.write_all(&(bytes.len() as u32).to_le_bytes()).await?;
+    stream.write_all(&bytes).await
+}
+
+async fn read_frame<T>(stream: &mut UnixStream) -> std::io::Result<T>
+where
+    T: Archive,
+    T::Archived: for<'a> bytecheck::CheckBytes<rkyv::validation::validators::DefaultValidator<'a>> + Deserialize<T, rkyv::Infallible>,
+{
+    let invalid = |msg: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, msg.to_string());
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len).await?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_FRAME {
+        return Err(invalid("frame too large"));
+    }
+    let mut bytes = rkyv::AlignedVec::with_capacity(len);
+    bytes.resize(len, 0);
+    stream.read_exact(&mut bytes).await?;
+    let archived = check_archived_root::<T>(&bytes).map_err(|_| invalid("malformed frame"))?;
+    Ok(archived.deserialize(&mut rkyv::Infallible).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bls_crypto::{test_keys, verify_signature};
+    use crate::committee::LOCALHOST_CHAIN_ID;
+
+    fn vertex(round: u64, batch: u8) -> Vertex {
+        Vertex { epoch: 0, round, author: 1, batch_hash: [batch; 32], parents: vec![], reconfiguration: None }
+    }
+
+    #[tokio::test]
+    async fn test_remote_signer_enforces_its_own_slashing_protection() {
+        let path = std::env::temp_dir().join(format!("sublyne-signer-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let key = test_keys(2)[1].clone();
+        let local = Arc::new(LocalSigner::new(key.clone(), LOCALHOST_CHAIN_ID, SlashingDb::in_memory()));
+        tokio::spawn(serve(UnixListener::bind(&path).unwrap(), local.clone()));
+
+        let remote = RemoteSigner::connect(&path).await.unwrap();
+        assert_eq!(remote.public_key(), key.public_key());
+        let sig = remote.sign_vote(&vertex(3, 1)).await.unwrap();
+        let context = SigningContext { chain_id: LOCALHOST_CHAIN_ID.into(), epoch: 0 };
+        let msg = context.message(&SigningIntent::VertexCertificate(hash_vertex(&vertex(3, 1))));
+        assert!(verify_signature(&msg, &sig, &key.public_key()));
+        assert_eq!(remote.sign_vote(&vertex(3, 1)).await.unwrap(), sig);
+
+        // An equivocating vertex or a second skip anchor is refused, whichever
+        // client asks
+        assert!(remote.sign_vote(&vertex(3, 2)).await.is_err());
+        assert!(local.sign_vote(&vertex(3, 2)).await.is_err());
+        remote.sign_skip_vote(0, 5, 2).await.unwrap();
+        assert!(remote.sign_skip_vote(0, 5, 3).await.is_err());
+        assert!(remote.sign_skip_vote(1, 5, 3).await.is_ok());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_restarted_signer_votes_again_only_for_the_same_vertices() {
+        let path = std::env::temp_dir().join(format!("sublyne-signer-restart-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let key = test_keys(2)[1].clone();
+        let first = vertex(1, 0);
+        let second = Vertex { round: 2, parents: vec![hash_vertex(&first)], ..vertex(2, 0) };
+        let (first_sig, skip_sig) = {
+            let signer = LocalSigner::new(key.clone(), LOCALHOST_CHAIN_ID, SlashingDb::open(&path).unwrap());
+            signer.sign_vote(&second).await.unwrap();
+            (signer.sign_vote(&first).await.unwrap(), signer.sign_skip_vote(0, 2, 3).await.unwrap())
+        };
+
+        // Consensus starts over at round 1: the parentless vertices of round 1
+        // and the same skip votes are signed again, but a round 2 vertex built
+        // on other parents is not, until consensus is past round 2
+        let db = SlashingDb::open(&path).unwrap();
+        assert_eq!(db.last_round(0), Some(2));
+        let signer = LocalSigner::new(key, LOCALHOST_CHAIN_ID, db);
+        assert_eq!(signer.sign_vote(&first).await.unwrap(), first_sig);
+        assert_eq!(signer.sign_skip_vote(0, 2, 3).await.unwrap(), skip_sig);
+        let rebuilt = Vertex { parents: vec![hash_vertex(&first), [7; 32]], ..second.clone() };
+        assert!(signer.sign_vote(&rebuilt).await.is_err());
+        assert!(signer.sign_vote(&second).await.is_ok());
+        assert!(signer.sign_vote(&Vertex { round: 3, parents: vec![hash_vertex(&rebuilt)], ..vertex(3, 0) }).await.is_ok());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_remote_signer_times_out_and_redials() {
+        let path = std::env::temp_dir().join(format!("sublyne-signer-redial-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let key = test_keys(2)[1].clone();
+        let local = Arc::new(LocalSigner::new(key.clone(), LOCALHOST_CHAIN_ID, SlashingDb::in_memory()));
+        // A signer that answers the key request, then exits
+        let listener = UnixListener::bind(&path).unwrap();
+        let public_key = key.public_key();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            read_frame::<SignerRequest>(&mut stream).await.unwrap();
+            write_frame(&mut stream, &SignerResponse::PublicKey(public_key)).await.unwrap();
+        });
+        let mut remote = RemoteSigner::connect(&path).await.unwrap();
+        remote.timeout = Duration::from_millis(200);
+
+        // It restarts as one that accepts connections but never answers
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let hung = tokio::spawn(async move {
+            let mut held = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                held.push(stream);
+            }
+        });
+        assert!(remote.sign_vote(&vertex(3, 1)).await.is_err());
+        assert!(remote.sign_vote(&vertex(3, 1)).await.unwrap_err().contains("no answer"));
+
+        // Then comes back for real
+        hung.abort();
+        let _ = hung.await;
+        let _ = std::fs::remove_file(&path);
+        tokio::spawn(serve(UnixListener::bind(&path).unwrap(), local));
+        let sig = remote.sign_vote(&vertex(3, 1)).await.unwrap();
+        let context = SigningContext { chain_id: LOCALHOST_CHAIN_ID.into(), epoch: 0 };
+        let msg = context.message(&SigningIntent::VertexCertificate(hash_vertex(&vertex(3, 1))));
+        assert!(verify_signature(&msg, &sig, &key.public_key()));
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,391 @@
+// Deterministic network simulator: every validator's consensus in one thread, on a virtual clock
+// Message delays come from GeoLatencyMatrix plus seeded jitter, so a run is reproducible from its seed
+
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, BinaryHeap};
+use std::sync::Arc;
+use std::time::Duration;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use crate::committee::Committee;
+use crate::consensus::ConsensusState;
+use crate::geo_latency::{GeoLatencyMatrix, Region};
+use crate::pacemaker::{Clock, Pacemaker, VirtualClock};
+use crate::types::{AggregatedCoA, CoA, Event, Hash, Message, SignerBitmap, ValidatorId, Vertex};
+
+pub struct SimConfig {
+    pub seed: u64,
+    /// Region of each validator, by id.
+    pub regions: Vec<Region>,
+    /// Largest delay added to a message on top of its link's latency; drawn
+    /// uniformly, so messages on one link can overtake each other.
+    pub jitter: Duration,
+    pub anchor_timeout: Duration,
+    pub max_anchor_timeout: Duration,
+    /// Validators that never start.
+    pub crashed: Vec<ValidatorId>,
+}
+
+/// A message on its way, ordered by delivery time and then by the order it was sent.
+struct InFlight {
+    at: Duration,
+    seq: u64,
+    from: ValidatorId,
+    to: ValidatorId,
+    msg: Message,
+}
+
+impl PartialEq for InFlight {
+    fn eq(&self, other: &Self) -> bool {
+        (self.at, self.seq) == (other.at, other.seq)
+    }
+}
+
+impl Eq for InFlight {}
+
+impl PartialOrd for InFlight {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for InFlight {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (self.at, self.seq).cmp(&(other.at, other.seq))
+    }
+}
+
+pub struct SimNode {
+    pub state: ConsensusState,
+    pacemaker: Pacemaker<VirtualClock>,
+    pub crashed: bool,
+    /// Vertices whose votes or vertex arrived since the last quorum check.
+    unchecked: BTreeSet<Hash>,
+    /// Virtual time and anchor round of every sub-DAG this node committed.
+    pub commits: Vec<(Duration, u64)>,
+}
+
+/// Validators of one committee driven like `sublyne` drives them, except that
+/// time is virtual and the network is a priority queue. Signatures are modelled
+/// rather than computed: every simulated validator is honest, and BLS would
+/// otherwise take all of the run time. A vote's bytes are a hash of the voter
+/// and its vote message, and the simulator delivers votes as already verified.
+pub struct Simulator {
+    pub nodes: Vec<SimNode>,
+    clock: VirtualClock,
+    matrix: GeoLatencyMatrix,
+    regions: Vec<Region>,
+    jitter: Duration,
+    rng: StdRng,
+    queue: BinaryHeap<Reverse<InFlight>>,
+    sent: u64,
+    pub delivered: u64,
+}
+
+impl Simulator {
+    pub fn new(committee: Arc<Committee>, config: SimConfig) -> Result<Self, String> {
+        if config.regions.len() != committee.size() {
+            return Err(format!("{} regions for a committee of {}", config.regions.len(), committee.size()));
+        }
+        let clock = VirtualClock::new();
+        let nodes = (0..committee.size() as ValidatorId).map(|id| SimNode {
+            state: ConsensusState::new(id, committee.clone()),
+            pacemaker: Pacemaker::new(clock.clone(), config.anchor_timeout, config.max_anchor_timeout),
+            crashed: config.crashed.contains(&id),
+            unchecked: BTreeSet::new(),
+            commits: Vec::new(),
+        }).collect();
+        Ok(Self {
+            nodes,
+            clock,
+            matrix: GeoLatencyMatrix::new(),
+            regions: config.regions,
+            jitter: config.jitter,
+            rng: StdRng::seed_from_u64(config.seed),
+            queue: BinaryHeap::new(),
+            sent: 0,
+            delivered: 0,
+        })
+    }
+
+    pub fn now(&self) -> Duration {
+        self.clock.now()
+    }
+
+    /// Advance virtual time by `duration`, delivering every message and firing
+    /// every timer that falls due on the way.
+    pub fn run_for(&mut self, duration: Duration) {
+        let end = self.now() + duration;
+        let mut ready: BTreeSet<ValidatorId> = (0..self.nodes.len() as ValidatorId).collect();
+        loop {
+            for id in std::mem::take(&mut ready) {
+                self.step(id);
+            }
+            let next_message = self.queue.peek().map(|Reverse(m)| m.at);
+            let next_timer = self.nodes.iter()
+                .filter(|node| !node.crashed)
+                .filter_map(|node| node.pacemaker.time_to_deadline())
+                .min()
+                .map(|left| self.now() + left);
+            let next = match (next_message, next_timer) {
+                (Some(m), Some(t)) => m.min(t),
+                (m, t) => match m.or(t) {
+                    Some(next) => next,
+                    None => break,
+                },
+            };
+            if next > end {
+                break;
+            }
+            self.clock.set(next);
+
+            while self.queue.peek().is_some_and(|Reverse(m)| m.at <= next) {
+                let Reverse(m) = self.queue.pop().unwrap();
+                self.delivered += 1;
+                self.nodes[m.to as usize].receive(m.from, m.msg);
+                ready.insert(m.to);
+            }
+            // Nodes whose timer fired
+            for (id, node) in self.nodes.iter().enumerate() {
+                if !node.crashed && node.pacemaker.time_to_deadline() == Some(Duration::ZERO) {
+                    ready.insert(id as ValidatorId);
+                }
+            }
+        }
+        self.clock.set(end);
+    }
+
+    /// One pass of the node loop for validator `id`, repeated until it has
+    /// nothing more to do at this instant.
+    fn step(&mut self, id: ValidatorId) {
+        if self.nodes[id as usize].crashed {
+            return;
+        }
+        let now = self.now();
+        loop {
+            let mut outbox = Vec::new();
+            let node = &mut self.nodes[id as usize];
+            let committee = node.state.committee.clone();
+            let state = &mut node.state;
+
+            // Propose on top of every certified vertex of the previous round
+            let first_round = state.dag.first_round;
+            let parents = if state.round > first_round { state.dag.certified_in_round(state.round - 1) } else { vec![] };
+            if state.round == first_round
+                || committee.stake_of(parents.iter().map(|h| state.dag.vertices[h].author)) >= committee.quorum_threshold()
+            {
+                let v = Vertex { epoch: state.epoch, round: state.round, author: id, batch_hash: [0u8; 32], parents, reconfiguration: None };
+                outbox.push(Message::Vertex(v.clone()));
+                state.on_event(Event::VertexReceived(v));
+                state.round += 1;
+            }
+
+            // Vote for what was accepted, in a fixed order
+            let mut accepted = state.take_accepted_vertices();
+            accepted.sort_by_key(|(h, v)| (v.round, v.author, *h));
+            for (h, v) in accepted {
+                node.unchecked.insert(h);
+                if !node.state.try_vote(h, &v) { continue; }
+                let coa = CoA { batch_hash: h, signatures: vec![(id, modelled_signature(&node.state, &h, id))] };
+                outbox.push(Message::CoA(coa.clone()));
+                node.receive(id, Message::CoA(coa));
+            }
+
+            let state = &mut node.state;
+            node.pacemaker.update(state.waiting_anchor_round(), state.fallback_depth);
+            if let Some(timeout) = node.pacemaker.poll() {
+                state.on_event(timeout);
+            }
+            for (round, anchor) in state.take_pending_skip_votes() {
+                let message = state.skip_vote_message(round, anchor);
+                let sig = blake3::hash(&[&id.to_le_bytes()[..], message.as_bytes()].concat()).as_bytes().to_vec();
+                state.verified_skip_votes.insert((round, anchor, id));
+                state.on_event(Event::SkipVoteReceived(state.epoch, round, anchor, id, sig.clone()));
+                outbox.push(Message::SkipVote(state.epoch, round, anchor, id, sig));
+            }
+            for (round, anchor, sig) in state.take_resent_skip_votes() {
+                outbox.push(Message::SkipVote(state.epoch, round, anchor, id, sig));
+            }
+            outbox.extend(state.take_new_skip_certs().into_iter().map(Message::SkipCert));
+            for round in state.take_pending_coin_shares() {
+                let message = state.coin_message(round);
+                let sig = blake3::hash(&[&id.to_le_bytes()[..], message.as_bytes()].concat()).as_bytes().to_vec();
+                state.verified_coin_shares.insert((round, id));
+                state.on_event(Event::CoinShareReceived(state.epoch, round, id, sig.clone()));
+                reveal_modelled_coin(state, round);
+                outbox.push(Message::CoinShare(state.epoch, round, id, sig));
+            }
+
+            // Each node aggregates the votes it collected itself. As in
+            // `get_pending_quorums`, but only for vertices whose votes changed:
+            // that scans every vertex ever voted on, once per step.
+            let certs = state.dag.certs.len();
+            for h in std::mem::take(&mut node.unchecked) {
+                let (Some(votes), true) = (state.coa_collectors.get(&h), state.dag.vertices.contains_key(&h)) else { continue };
+                if state.dag.certs.contains_key(&h)
+                    || committee.stake_of(votes.keys().copied()) < committee.quorum_threshold()
+                {
+                    continue;
+                }
+                let signer_bitmap = SignerBitmap::from_ids(committee.size(), votes.keys().copied()).unwrap();
+                state.certify_vertex(AggregatedCoA { epoch: state.epoch, batch_hash: h, aggregated_signature: vec![], signer_bitmap });
+            }
+
+            for sub_dag in state.take_committed() {
+                node.commits.push((now, sub_dag.round));
+            }
+
+            let progressed = !outbox.is_empty() || state.dag.certs.len() > certs;
+            for msg in outbox {
+                self.broadcast(id, msg);
+            }
+            if !progressed {
+                break;
+            }
+        }
+    }
+
+    /// Queue `msg` for every other live validator, delayed by the latency
+    /// between the regions plus jitter.
+    fn broadcast(&mut self, from: ValidatorId, msg: Message) {
+        let now = self.now();
+        for to in 0..self.nodes.len() as ValidatorId {
+            if to == from || self.nodes[to as usize].crashed {
+                continue;
+            }
+            let latency = Duration::from_micros(self.matrix.get_latency_us(self.regions[from as usize], self.regions[to as usize]));
+            let jitter = Duration::from_micros(self.rng.gen_range(0..=self.jitter.as_micros() as u64));
+            self.sent += 1;
+            self.queue.push(Reverse(InFlight { at: now + latency + jitter, seq: self.sent, from, to, msg: msg.clone() }));
+        }
+    }
+}
+
+/// Stand-in for a BLS vote: deterministic, distinct per voter and vertex, and
+/// the same on every node, as the real signature is.
+fn modelled_signature(state: &ConsensusState, h: &Hash, voter: ValidatorId) -> Vec<u8> {
+    let message = state.vote_message(h);
+    blake3::hash(&[&voter.to_le_bytes()[..], message.as_bytes()].concat()).as_bytes().to_vec()
+}
+
+/// Stand-in for combining coin shares: once `round` has a threshold of them,
+/// its coin is a hash of the round's coin message, the same on every node.
+fn reveal_modelled_coin(state: &mut ConsensusState, round: u64) {
+    let shares = state.coin_shares.get(&round).map_or(0, |s| s.len());
+    if shares >= state.committee.coin.threshold() {
+        let coin = blake3::hash(state.coin_message(round).as_bytes()).as_bytes().to_vec();
+        state.apply_coin(round, coin);
+    }
+}
+
+impl SimNode {
+    /// Hand `msg` from `from` to this node. Votes, skip votes and coin shares
+    /// skip signature checks: they are recorded as verified before the
+    /// consensus state sees them.
+    fn receive(&mut self, from: ValidatorId, msg: Message) {
+        if let Message::CoA(coa) = &msg {
+            for (voter, sig) in &coa.signatures {
+                debug_assert_eq!(*voter, from);
+                self.state.coa_collectors.entry(coa.batch_hash).or_default().insert(*voter, sig.clone());
+                self.state.verified_partials.insert((coa.batch_hash, *voter));
+            }
+            self.unchecked.insert(coa.batch_hash);
+        }
+        match &msg {
+            Message::SkipVote(_, round, anchor, voter, _) => {
+                self.state.verified_skip_votes.insert((*round, *anchor, *voter));
+            }
+            Message::SkipCert(cert) => {
+                for (voter, _) in &cert.signatures {
+                    self.state.verified_skip_votes.insert((cert.round, cert.anchor_index, *voter));
+                }
+            }
+            Message::CoinShare(_, round, voter, _) => {
+                self.state.verified_coin_shares.insert((*round, *voter));
+            }
+            _ => {}
+        }
+        let coin_round = match &msg {
+            Message::CoinShare(_, round, ..) => Some(*round),
+            _ => None,
+        };
+        if let Some(event) = msg.into_event(from) {
+            self.state.on_event(event);
+        }
+        if let Some(round) = coin_round {
+            reveal_modelled_coin(&mut self.state, round);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::committee::test_committee;
+
+    fn config(seed: u64, regions: Vec<Region>) -> SimConfig {
+        SimConfig {
+            seed,
+            regions,
+            jitter: Duration::from_millis(20),
+            anchor_timeout: Duration::from_millis(500),
+            max_anchor_timeout: Duration::from_secs(8),
+            crashed: vec![],
+        }
+    }
+
+    fn global() -> Vec<Region> {
+        vec![Region::USEast, Region::EUWest, Region::APNortheast, Region::SAEast]
+    }
+
+    fn run(config: SimConfig, seconds: u64) -> Simulator {
+        let committee = Arc::new(test_committee(config.regions.len()));
+        let mut sim = Simulator::new(committee, config).unwrap();
+        sim.run_for(Duration::from_secs(seconds));
+        sim
+    }
+
+    /// Every live node's commit log is a prefix of the longest one.
+    fn assert_agree(sim: &Simulator) {
+        let live: Vec<_> = sim.nodes.iter().filter(|node| !node.crashed).collect();
+        let longest = live.iter().map(|node| &node.state.committed_log).max_by_key(|log| log.len()).unwrap();
+        for node in live {
+            assert_eq!(node.state.committed_log[..], longest[..node.state.committed_log.len()]);
+        }
+    }
+
+    #[test]
+    fn test_same_seed_same_run() {
+        let a = run(config(7, global()), 60);
+        let b = run(config(7, global()), 60);
+        assert_eq!(a.now(), Duration::from_secs(60));
+        assert!(a.nodes[0].commits.len() > 20);
+        for (x, y) in a.nodes.iter().zip(&b.nodes) {
+            assert_eq!(x.commits, y.commits);
+            assert_eq!(x.state.committed_log, y.state.committed_log);
+        }
+        assert_eq!(a.delivered, b.delivered);
+
+        // Another seed moves the timing; its nodes still agree on the order
+        let c = run(config(8, global()), 60);
+        assert_ne!(a.nodes[0].commits, c.nodes[0].commits);
+        assert_agree(&c);
+    }
+
+    #[test]
+    fn test_latency_sets_the_pace() {
+        let local = run(config(1, vec![Region::EUWest; 4]), 20);
+        let spread = run(config(1, global()), 20);
+        let committed = |sim: &Simulator| sim.nodes[0].state.dag.committed_round;
+        assert!(committed(&local) > 5 * committed(&spread), "{} vs {}", committed(&local), committed(&spread));
+
+        // A crashed validator's anchors time out and are skipped
+        let mut crashed = config(1, global());
+        crashed.crashed = vec![3];
+        let sim = run(crashed, 60);
+        assert!(sim.nodes[0].state.skip_certs.values().all(|cert| cert.anchor_index == 3));
+        assert!(!sim.nodes[0].state.skip_certs.is_empty());
+        assert!(sim.nodes[0].state.dag.committed_round > 20);
+        assert_agree(&sim);
+    }
+}
@@ -0,0 +1,248 @@
+// Slashing protection: a durable record of everything a validator key has signed
+// Consulted before every consensus signature, so a restarted or migrated validator never signs a conflicting message
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use rkyv::ser::serializers::AllocSerializer;
+use rkyv::ser::Serializer;
+use rkyv::{check_archived_root, Archive, Deserialize as RkyvDeserialize, Serialize as RkyvSerialize};
+use crate::committee::hex_bytes;
+use crate::crypto::SigningMessage;
+use crate::types::{BlsPublicKey, Hash, ValidatorId};
+
+/// Kept beside the keystores in a key directory.
+pub const SLASHING_DB_FILE: &str = "slashing.db";
+pub const INTERCHANGE_VERSION: u32 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize, Archive, RkyvDeserialize, RkyvSerialize)]
+#[archive(check_bytes)]
+#[serde(rename_all = "snake_case")]
+pub enum SignedKind {
+    Vote,
+    SkipVote,
+}
+
+/// One signature the key produced. `author` is the vertex author for a vote
+/// and the skipped anchor for a skip vote; `digest` is the blake3 hash of the
+/// exact message signed.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Archive, RkyvDeserialize, RkyvSerialize)]
+#[archive(check_bytes)]
+pub struct SignedRecord {
+    pub kind: SignedKind,
+    pub epoch: u64,
+    pub round: u64,
+    pub author: ValidatorId,
+    #[serde(with = "hex_bytes")]
+    pub digest: Hash,
+}
+
+impl SignedRecord {
+    pub fn new(kind: SignedKind, epoch: u64, round: u64, author: ValidatorId, message: &SigningMessage) -> Self {
+        Self { kind, epoch, round, author, digest: *blake3::hash(message.as_bytes()).as_bytes() }
+    }
+
+    /// Two records for one slot conflict unless they are identical: one vertex
+    /// per author and round, but one skip vote per round whatever its anchor.
+    fn slot(&self) -> (SignedKind, u64, u64, ValidatorId) {
+        let author = match self.kind {
+            SignedKind::Vote => self.author,
+            SignedKind::SkipVote => 0,
+        };
+        (self.kind, self.epoch, self.round, author)
+    }
+}
+
+/// Portable form of a slashing database, for moving a validator to new
+/// hardware. Bound to the chain and key it was exported for.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SlashingInterchange {
+    pub version: u32,
+    pub chain_id: String,
+    #[serde(with = "hex_bytes")]
+    pub bls_public_key: BlsPublicKey,
+    pub records: Vec<SignedRecord>,
+}
+
+/// Every record signed with one key, optionally backed by an append-only file
+/// that is fsync'd before the signature is released.
+///
+/// Records are never dropped, and a restarted node's consensus starts over at
+/// round 1 of its genesis epoch. Up to `last_round` it is asked to vote in
+/// slots it already voted in, and signs only where the vertex came out the
+/// same as before.
+pub struct SlashingDb {
+    file: Option<File>,
+    signed: HashMap<(SignedKind, u64, u64, ValidatorId), SignedRecord>,
+}
+
+impl SlashingDb {
+    pub fn in_memory() -> Self {
+        Self { file: None, signed: HashMap::new() }
+    }
+
+    /// Open (or create) a database file and load what it holds. A record cut
+    /// short by a crash mid-write is truncated away; it was never signed.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut file = OpenOptions::new().read(true).append(true).create(true).open(path)?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+
+        let mut db = Self::in_memory();
+        let mut pos = 0;
+        while pos + 4 <= bytes.len() {
+            let len = u32::from_le_bytes(bytes[pos..pos + 4].try_into().unwrap()) as usize;
+            let Some(record) = bytes.get(pos + 4..pos + 4 + len) else { break };
+            let mut aligned = rkyv::AlignedVec::with_capacity(len);
+            aligned.extend_from_slice(record);
+            let archived = check_archived_root::<SignedRecord>(&aligned)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "corrupt slashing protection record"))?;
+            let record: SignedRecord = archived.deserialize(&mut rkyv::Infallible).unwrap();
+            db.signed.insert(record.slot(), record);
+            pos += 4 + len;
+        }
+        if pos < bytes.len() {
+            file.set_len(pos as u64)?;
+        }
+        db.file = Some(file);
+        Ok(db)
+    }
+
+    pub fn len(&self) -> usize {
+        self.signed.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.signed.is_empty()
+    }
+
+    /// Highest round of `epoch` this key signed a vote or skip vote in.
+    pub fn last_round(&self, epoch: u64) -> Option<u64> {
+        self.signed.values().filter(|r| r.epoch == epoch).map(|r| r.round).max()
+    }
+
+    /// Ok if `record` may be signed: its slot is new, in which case it is
+    /// persisted before returning, or it repeats what was signed there.
+    pub fn check_and_record(&mut self, record: SignedRecord) -> Result<(), String> {
+        if let Some(signed) = self.signed.get(&record.slot()) {
+            if *signed != record {
+                return Err(format!("conflicts with an earlier {:?} in round {} of epoch {}", signed.kind, signed.round, signed.epoch));
+            }
+            return Ok(());
+        }
+        self.append(record).map_err(|e| format!("slashing protection database: {}", e))
+    }
+
+    fn append(&mut self, record: SignedRecord) -> io::Result<()> {
+        if let Some(file) = self.file.as_mut() {
+            let mut ser = AllocSerializer::<256>::default();
+            ser.serialize_value(&record).unwrap();
+            let bytes = ser.into_serializer().into_inner();
+            file.write_all(&(bytes.len() as u32).to_le_bytes())?;
+            file.write_all(&bytes)?;
+            file.sync_data()?;
+        }
+        self.signed.insert(record.slot(), record);
+        Ok(())
+    }
+
+    pub fn export(&self, chain_id: &str, bls_public_key: &BlsPublicKey) -> SlashingInterchange {
+        let mut records: Vec<_> = self.signed.values().cloned().collect();
+        records.sort_by_key(|r| (r.epoch, r.round, r.kind, r.author));
+        SlashingInterchange { version: INTERCHANGE_VERSION, chain_id: chain_id.to_string(), bls_public_key: bls_public_key.clone(), records }
+    }
+
+    /// Merges records exported for the same chain and key, returning how many
+    /// were new. Nothing is imported if any record conflicts with this database.
+    pub fn import(&mut self, interchange: &SlashingInterchange, chain_id: &str, bls_public_key: &BlsPublicKey) -> Result<usize, String> {
+        if interchange.version != INTERCHANGE_VERSION {
+            return Err(format!("unsupported interchange version {}", interchange.version));
+        }
+        if interchange.chain_id != chain_id {
+            return Err(format!("records were exported for chain {:?}", interchange.chain_id));
+        }
+        if interchange.bls_public_key != *bls_public_key {
+            return Err("records were exported for another BLS key".into());
+        }
+        let mut fresh = HashMap::new();
+        for record in &interchange.records {
+            let signed = self.signed.get(&record.slot()).or_else(|| fresh.get(&record.slot()));
+            match signed {
+                Some(signed) if signed == record => {}
+                Some(_) => return Err(format!("record for round {} of epoch {} conflicts with one already held", record.round, record.epoch)),
+                None => { fresh.insert(record.slot(), record.clone()); }
+            }
+        }
+        let added = fresh.len();
+        for record in fresh.into_values() {
+            self.append(record).map_err(|e| format!("slashing protection database: {}", e))?;
+        }
+        Ok(added)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{SigningContext, SigningIntent};
+
+    fn vote(round: u64, author: ValidatorId, vertex: u8) -> SignedRecord {
+        let context = SigningContext { chain_id: "test".into(), epoch: 0 };
+        SignedRecord::new(SignedKind::Vote, 0, round, author, &context.message(&SigningIntent::VertexCertificate([vertex; 32])))
+    }
+
+    fn skip_vote(round: u64, anchor: ValidatorId) -> SignedRecord {
+        let context = SigningContext { chain_id: "test".into(), epoch: 0 };
+        SignedRecord::new(SignedKind::SkipVote, 0, round, anchor, &context.message(&SigningIntent::SkipVote { round, anchor }))
+    }
+
+    #[test]
+    fn test_records_survive_restart_and_migration() {
+        let path = std::env::temp_dir().join(format!("sublyne-slashing-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        {
+            let mut db = SlashingDb::open(&path).unwrap();
+            db.check_and_record(vote(1, 0, 1)).unwrap();
+            db.check_and_record(vote(1, 1, 2)).unwrap();
+            db.check_and_record(skip_vote(2, 2)).unwrap();
+            db.check_and_record(vote(1, 0, 1)).unwrap();
+            assert!(db.check_and_record(vote(1, 0, 9)).is_err());
+            assert!(db.check_and_record(skip_vote(2, 3)).is_err());
+        }
+        // Simulate a crash halfway through writing a record
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&100u32.to_le_bytes()).unwrap();
+        file.write_all(&[0u8; 10]).unwrap();
+        drop(file);
+
+        let mut db = SlashingDb::open(&path).unwrap();
+        assert_eq!(db.len(), 3);
+        assert!(db.check_and_record(vote(1, 0, 9)).is_err());
+        db.check_and_record(vote(3, 0, 3)).unwrap();
+        drop(db);
+        assert_eq!(SlashingDb::open(&path).unwrap().len(), 4);
+        let _ = std::fs::remove_file(&path);
+
+        // Export and import through JSON, onto a key that signed elsewhere too
+        let mut source = SlashingDb::in_memory();
+        source.check_and_record(vote(1, 0, 1)).unwrap();
+        source.check_and_record(skip_vote(2, 2)).unwrap();
+        let json = serde_json::to_string(&source.export("test", &vec![7; 96])).unwrap();
+        let interchange: SlashingInterchange = serde_json::from_str(&json).unwrap();
+
+        let mut target = SlashingDb::in_memory();
+        target.check_and_record(vote(5, 1, 5)).unwrap();
+        assert!(target.import(&interchange, "other", &vec![7; 96]).is_err());
+        assert!(target.import(&interchange, "test", &vec![8; 96]).is_err());
+        assert_eq!(target.import(&interchange, "test", &vec![7; 96]), Ok(2));
+        assert_eq!(target.import(&interchange, "test", &vec![7; 96]), Ok(0));
+        assert!(target.check_and_record(skip_vote(2, 3)).is_err());
+
+        let mut conflicting = SlashingDb::in_memory();
+        conflicting.check_and_record(vote(1, 0, 9)).unwrap();
+        assert!(conflicting.import(&interchange, "test", &vec![7; 96]).is_err());
+        assert_eq!(conflicting.len(), 1);
+    }
+}
@@ -0,0 +1,349 @@
+// Transport: what the node loop needs from a network, so sockets, QUIC and in-process channels are interchangeable
+// Messages travel as serialized `Message` bytes on every transport, and arrive as `Event`s
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use async_trait::async_trait;
+use parking_lot::Mutex;
+use rkyv::ser::serializers::AllocSerializer;
+use rkyv::ser::Serializer;
+use tokio::sync::mpsc;
+use crate::committee::Committee;
+use crate::net::{deliver, NetMetrics, NetworkHandle, PeerKey, PeerSet, PendingRequests, TcpNetwork};
+use crate::quic::{QuicHandle, QuicNetwork};
+use crate::types::{Event, Message, Request, Response, ValidatorId};
+
+/// A validator's network before it is started.
+#[async_trait]
+pub trait Transport: Send + 'static {
+    type Handle: TransportHandle + 'static;
+
+    /// Reach the rest of the committee. What peers send arrives on `event_tx`,
+    /// already checked against its sender; the handle sends.
+    async fn start(self, event_tx: mpsc::Sender<Event>) -> Arc<Self::Handle>;
+}
+
+/// Stream a message travels on, where a transport keeps more than one per
+/// peer, so bulk vertex data never queues in front of votes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Lane {
+    Vertices = 0,
+    Votes = 1,
+}
+
+impl Lane {
+    /// Vertices, and responses carrying them, take their own lane; every vote,
+    /// certificate and request takes the other.
+    pub fn of(msg: &Message) -> Lane {
+        match msg {
+            Message::Vertex(_) | Message::Response(..) => Lane::Vertices,
+            Message::CoA(_) | Message::AggregatedCoA(_) | Message::SkipVote(..) | Message::SkipCert(_)
+            | Message::Equivocation(_) | Message::Request(..) | Message::CoinShare(..) => Lane::Votes,
+        }
+    }
+}
+
+/// A started network. Sends are fire-and-forget: a peer that is down gets the
+/// message once it is reachable again, or never.
+#[async_trait]
+pub trait TransportHandle: Send + Sync {
+    /// Send to every other validator, on `lane` where the transport has lanes.
+    async fn broadcast(&self, lane: Lane, msg_bytes: Vec<u8>);
+
+    /// Send to one validator; ignored if `peer` is not in the committee.
+    async fn send_to(&self, peer: ValidatorId, lane: Lane, msg_bytes: Vec<u8>);
+
+    fn metrics(&self) -> NetMetrics;
+
+    /// Move to the committee of a new epoch, in which we are `id`. Peers are
+    /// known by key, so connections to validators that stay keep working
+    /// under their new ids; those that left are dropped and those that joined
+    /// are reached.
+    fn reconfigure(&self, id: ValidatorId, committee: &Committee);
+
+    /// Requests sent through this handle that await an answer.
+    fn pending(&self) -> &PendingRequests;
+
+    /// Serialize `msg` and send it to every other validator on its lane.
+    async fn broadcast_message(&self, msg: &Message) {
+        self.broadcast(Lane::of(msg), serialize(msg)).await
+    }
+
+    /// Serialize `msg` and send it to `peer` on its lane.
+    async fn send_message(&self, peer: ValidatorId, msg: &Message) {
+        self.send_to(peer, Lane::of(msg), serialize(msg)).await
+    }
+
+    /// Ask `peer`, and wait up to `timeout` for the response that carries the
+    /// request's correlation id.
+    async fn request(&self, peer: ValidatorId, request: Request, timeout: Duration) -> Result<Response, String> {
+        let (id, answer) = self.pending().register(peer);
+        self.send_message(peer, &Message::Request(id, request)).await;
+        match tokio::time::timeout(timeout, answer).await {
+            Ok(Ok(response)) => Ok(response),
+            _ => {
+                self.pending().forget(id);
+                Err(format!("validator {} did not answer request {} within {:?}", peer, id, timeout))
+            }
+        }
+    }
+
+    /// Answer the request `id` that `peer` sent.
+    async fn respond(&self, peer: ValidatorId, id: u64, response: Response) {
+        self.send_message(peer, &Message::Response(id, response)).await
+    }
+}
+
+fn serialize(msg: &Message) -> Vec<u8> {
+    let mut ser = AllocSerializer::<1024>::default();
+    ser.serialize_value(msg).unwrap();
+    ser.into_serializer().into_inner().to_vec()
+}
+
+#[async_trait]
+impl Transport for TcpNetwork {
+    type Handle = NetworkHandle;
+
+    async fn start(self, event_tx: mpsc::Sender<Event>) -> Arc<NetworkHandle> {
+        TcpNetwork::start(self, event_tx).await
+    }
+}
+
+#[async_trait]
+impl TransportHandle for NetworkHandle {
+    /// One stream per peer: every lane shares it.
+    async fn broadcast(&self, _lane: Lane, msg_bytes: Vec<u8>) {
+        self.broadcast_raw(msg_bytes).await
+    }
+
+    async fn send_to(&self, peer: ValidatorId, _lane: Lane, msg_bytes: Vec<u8>) {
+        NetworkHandle::send_to(self, peer, msg_bytes).await
+    }
+
+    fn metrics(&self) -> NetMetrics {
+        self.get_metrics()
+    }
+
+    fn reconfigure(&self, id: ValidatorId, committee: &Committee) {
+        NetworkHandle::reconfigure(self, id, committee)
+    }
+
+    fn pending(&self) -> &PendingRequests {
+        &self.pending
+    }
+}
+
+#[async_trait]
+impl Transport for QuicNetwork {
+    type Handle = QuicHandle;
+
+    async fn start(self, event_tx: mpsc::Sender<Event>) -> Arc<QuicHandle> {
+        QuicNetwork::start(self, event_tx).await
+    }
+}
+
+#[async_trait]
+impl TransportHandle for QuicHandle {
+    async fn broadcast(&self, lane: Lane, msg_bytes: Vec<u8>) {
+        self.broadcast_raw(lane, msg_bytes).await
+    }
+
+    async fn send_to(&self, peer: ValidatorId, lane: Lane, msg_bytes: Vec<u8>) {
+        QuicHandle::send_to(self, peer, lane, msg_bytes).await
+    }
+
+    fn metrics(&self) -> NetMetrics {
+        self.get_metrics()
+    }
+
+    fn reconfigure(&self, id: ValidatorId, committee: &Committee) {
+        QuicHandle::reconfigure(self, id, committee)
+    }
+
+    fn pending(&self) -> &PendingRequests {
+        &self.pending
+    }
+}
+
+/// Messages in flight to one validator, with the key of the one that sent them.
+type Inbox = mpsc::Sender<(PeerKey, Vec<u8>)>;
+
+/// In-process network: every validator of a committee in one process, joined
+/// by channels. The sender of a message is known without a handshake, but it
+/// is held to the same authorship rules as on a socket.
+pub struct ChannelNetwork {
+    pub key: PeerKey,
+    pub peers: Arc<PeerSet>,
+    pub pending: Arc<PendingRequests>,
+    pub metrics: Arc<Mutex<NetMetrics>>,
+    inboxes: Arc<HashMap<PeerKey, Inbox>>,
+    inbox: mpsc::Receiver<(PeerKey, Vec<u8>)>,
+}
+
+impl ChannelNetwork {
+    /// Connected networks for the validators of `committee`, in id order.
+    /// Only they can be reached, whatever committee a later epoch brings.
+    pub fn mesh(committee: &Committee) -> Vec<ChannelNetwork> {
+        let keys: Vec<PeerKey> = committee.validators.iter()
+            .map(|v| v.ed25519_public_key.as_slice().try_into().expect("32 byte ed25519 key"))
+            .collect();
+        let (senders, receivers): (Vec<Inbox>, Vec<_>) = keys.iter().map(|_| mpsc::channel(1_000_000)).unzip();
+        let inboxes = Arc::new(keys.iter().copied().zip(senders).collect::<HashMap<_, _>>());
+        receivers.into_iter().zip(&committee.validators).zip(keys).map(|((inbox, validator), key)| ChannelNetwork {
+            key,
+            peers: Arc::new(PeerSet::new(validator.id, committee)),
+            pending: Arc::new(PendingRequests::default()),
+            metrics: Arc::new(Mutex::new(NetMetrics {
+                ser_micros: 0, deser_micros: 0, bytes_sent: 0, bytes_recv: 0
+            })),
+            inboxes: inboxes.clone(),
+            inbox,
+        }).collect()
+    }
+}
+
+#[async_trait]
+impl Transport for ChannelNetwork {
+    type Handle = ChannelHandle;
+
+    async fn start(mut self, event_tx: mpsc::Sender<Event>) -> Arc<ChannelHandle> {
+        let (peers, pending, metrics) = (self.peers.clone(), self.pending.clone(), self.metrics.clone());
+        tokio::spawn(async move {
+            while let Some((key, msg_bytes)) = self.inbox.recv().await {
+                // A malformed message has no connection to close; drop just it
+                let Some(peer) = peers.id_of(&key) else { continue };
+                deliver(&msg_bytes, peers.id(), peer, &event_tx, &pending, &metrics).await;
+            }
+        });
+        Arc::new(ChannelHandle { key: self.key, peers: self.peers, inboxes: self.inboxes, pending: self.pending, metrics: self.metrics })
+    }
+}
+
+pub struct ChannelHandle {
+    pub key: PeerKey,
+    pub peers: Arc<PeerSet>,
+    inboxes: Arc<HashMap<PeerKey, Inbox>>,
+    pub pending: Arc<PendingRequests>,
+    pub metrics: Arc<Mutex<NetMetrics>>,
+}
+
+impl ChannelHandle {
+    async fn send_to_key(&self, key: &PeerKey, msg_bytes: Vec<u8>) {
+        if let Some(inbox) = self.inboxes.get(key) {
+            self.metrics.lock().bytes_sent += msg_bytes.len() as u64;
+            let _ = inbox.send((self.key, msg_bytes)).await;
+        }
+    }
+}
+
+#[async_trait]
+impl TransportHandle for ChannelHandle {
+    /// Channels never stall on loss, so lanes are not kept apart.
+    async fn broadcast(&self, _lane: Lane, msg_bytes: Vec<u8>) {
+        for (key, _) in self.peers.peers() {
+            self.send_to_key(&key, msg_bytes.clone()).await;
+        }
+    }
+
+    async fn send_to(&self, peer: ValidatorId, _lane: Lane, msg_bytes: Vec<u8>) {
+        if let Some(key) = self.peers.key_of(peer) {
+            self.send_to_key(&key, msg_bytes).await;
+        }
+    }
+
+    fn metrics(&self) -> NetMetrics {
+        self.metrics.lock().clone()
+    }
+
+    fn reconfigure(&self, id: ValidatorId, committee: &Committee) {
+        self.peers.reconfigure(id, committee)
+    }
+
+    fn pending(&self) -> &PendingRequests {
+        &self.pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::committee::test_committee;
+    use crate::types::{CoA, Vertex};
+
+    fn vertex(author: ValidatorId) -> Message {
+        Message::Vertex(Vertex { epoch: 0, round: 1, author, batch_hash: [0; 32], parents: vec![], reconfiguration: None })
+    }
+
+    #[test]
+    fn test_messages_take_their_lane() {
+        assert_eq!(Lane::of(&vertex(0)), Lane::Vertices);
+        assert_eq!(Lane::of(&Message::Response(0, Response::Certified(vec![]))), Lane::Vertices);
+        assert_eq!(Lane::of(&Message::CoA(CoA { batch_hash: [0; 32], signatures: vec![(0, vec![0; 48])] })), Lane::Votes);
+        assert_eq!(Lane::of(&Message::SkipVote(0, 1, 2, 0, vec![0; 48])), Lane::Votes);
+        assert_eq!(Lane::of(&Message::Request(0, Request::Certified(vec![]))), Lane::Votes);
+    }
+
+    /// Start any transport and hand back its handle as the node loop sees it.
+    async fn start<T: Transport>(network: T) -> (Arc<dyn TransportHandle>, mpsc::Receiver<Event>) {
+        let (tx, rx) = mpsc::channel(100);
+        (network.start(tx).await, rx)
+    }
+
+    #[tokio::test]
+    async fn test_channel_network_broadcasts_and_sends_to_one() {
+        let mut nodes = Vec::new();
+        for network in ChannelNetwork::mesh(&test_committee(3)) {
+            nodes.push(start(network).await);
+        }
+        nodes[0].0.broadcast_message(&vertex(0)).await;
+        for (_, rx) in &mut nodes[1..] {
+            assert!(matches!(rx.recv().await, Some(Event::VertexReceived(v)) if v.author == 0));
+        }
+
+        // Only the addressee hears a unicast, and only its author's votes
+        nodes[2].0.send_message(1, &Message::CoA(CoA { batch_hash: [0; 32], signatures: vec![(0, vec![])] })).await;
+        nodes[2].0.send_message(1, &Message::CoA(CoA { batch_hash: [0; 32], signatures: vec![(2, vec![])] })).await;
+        nodes[2].0.send_to(1, Lane::Votes, vec![1, 2, 3]).await;
+        assert!(matches!(nodes[1].1.recv().await, Some(Event::CoAReceived(coa)) if coa.signatures[0].0 == 2));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(nodes[0].1.try_recv().is_err());
+        assert!(nodes[1].1.try_recv().is_err());
+        assert!(nodes[2].1.try_recv().is_err());
+
+        assert!(nodes[0].0.metrics().bytes_sent > 0);
+        assert!(nodes[1].0.metrics().bytes_recv > 0);
+    }
+
+    #[tokio::test]
+    async fn test_request_is_answered_by_correlation_id() {
+        let mut nodes = Vec::new();
+        for network in ChannelNetwork::mesh(&test_committee(3)) {
+            nodes.push(start(network).await);
+        }
+        let (asker, responder) = (nodes[0].0.clone(), nodes[1].0.clone());
+        let mut requests = nodes.remove(1).1;
+        tokio::spawn(async move {
+            while let Some(Event::RequestReceived(peer, id, Request::Certified(hashes))) = requests.recv().await {
+                // Answer the second request only, so the first times out
+                if hashes.len() == 2 {
+                    responder.respond(peer, id, Response::Certified(vec![])).await;
+                }
+            }
+        });
+
+        let unanswered = asker.request(1, Request::Certified(vec![[1; 32]]), Duration::from_millis(100)).await;
+        assert!(unanswered.unwrap_err().contains("did not answer"));
+        assert!(asker.pending().waiting.lock().is_empty());
+
+        let answered = asker.request(1, Request::Certified(vec![[1; 32], [2; 32]]), Duration::from_secs(5)).await;
+        assert!(matches!(answered, Ok(Response::Certified(v)) if v.is_empty()));
+
+        // Only the validator asked can answer, and only while the request waits
+        let (id, mut answer) = asker.pending().register(1);
+        nodes[1].0.respond(0, id, Response::Certified(vec![])).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(answer.try_recv().is_err());
+        asker.pending().forget(id);
+    }
+}
@@ -1,6 +1,9 @@
 use serde::{Deserialize, Serialize};
 use rkyv::{Archive, Deserialize as RkyvDeserialize, Serialize as RkyvSerialize};
 use bytecheck::CheckBytes;
+use crate::coin::CoinDealing;
+use crate::committee::ValidatorInfo;
+pub use crate::bitset::SignerBitmap;
 
 pub type ValidatorId = u32;
 pub type Hash = [u8; 32];
@@ -10,15 +13,31 @@ pub type Signature = Vec<u8>;
 // Using Vec for serde compatibility (protocol enforces exact byte sizes)
 pub type BlsSignature = Vec<u8>;  // BLS12-381 G1 signature (48 bytes compressed)
 pub type BlsPublicKey = Vec<u8>;  // BLS12-381 G2 public key (96 bytes compressed)
-pub type SignerBitmap = u64;       // Supports up to 64 validators
 
-#[derive(Clone, Serialize, Deserialize, Archive, RkyvDeserialize, RkyvSerialize, Debug)]
+/// Committee for `epoch`, proposed in a vertex of the epoch before it. It takes
+/// effect after the anchor whose commit first brings identical proposals from
+/// a stake quorum of the current committee.
+#[derive(Clone, Serialize, Deserialize, Archive, RkyvDeserialize, RkyvSerialize, Debug, PartialEq)]
+#[archive(check_bytes)]
+pub struct ReconfigurationProposal {
+    pub epoch: u64,
+    pub validators: Vec<ValidatorInfo>,
+    /// Coin of the proposed committee; proposals only match if they carry the
+    /// same dealing. Boxed, since most vertices propose nothing.
+    pub coin: Box<CoinDealing>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Archive, RkyvDeserialize, RkyvSerialize, Debug, PartialEq)]
 #[archive(check_bytes)]
 pub struct Vertex {
+    pub epoch: u64,
     pub round: u64,
     pub author: ValidatorId,
     pub batch_hash: Hash,
-    pub parent_indices: Vec<u32>,
+    /// Hashes of the round-1 vertices this one builds on. Unlike positions in
+    /// `round_to_vertices`, these resolve the same way on every node.
+    pub parents: Vec<Hash>,
+    pub reconfiguration: Option<ReconfigurationProposal>,
 }
 
 #[derive(Clone, Serialize, Deserialize, Archive, RkyvDeserialize, RkyvSerialize, Debug)]
@@ -29,13 +48,14 @@ pub struct CoA {
 }
 
 // Phase E.4: Aggregated Certificate of Availability (O(1) size)
-// Total size: 32 (hash) + 48 (BLS G1 sig) + 8 (bitmap) = 88 bytes fixed
-#[derive(Clone, Serialize, Deserialize, Archive, RkyvDeserialize, RkyvSerialize, Debug)]
+// Total size: 8 (epoch) + 32 (hash) + 48 (BLS G1 sig) + signer set (at most 3 + n/8 bytes, a few when near-full)
+#[derive(Clone, Serialize, Deserialize, Archive, RkyvDeserialize, RkyvSerialize, Debug, PartialEq)]
 #[archive(check_bytes)]
 pub struct AggregatedCoA {
+    pub epoch: u64,
     pub batch_hash: Hash,
     pub aggregated_signature: BlsSignature,  // 48 bytes (G1 compressed)
-    pub signer_bitmap: SignerBitmap,         // 8 bytes (up to 64 validators)
+    pub signer_bitmap: SignerBitmap,         // sized to the committee
 }
 
 #[derive(Clone, Serialize, Deserialize, Archive, RkyvDeserialize, RkyvSerialize, Debug)]
@@ -56,11 +76,43 @@ pub struct AggregatedCertifiedVertex {
 #[derive(Clone, Serialize, Deserialize, Archive, RkyvDeserialize, RkyvSerialize, Debug)]
 #[archive(check_bytes)]
 pub struct SkipCert {
+    pub epoch: u64,
     pub round: u64,
     pub anchor_index: ValidatorId,
     pub signatures: Vec<(ValidatorId, Signature)>,
 }
 
+/// A vertex with its author's BLS signature over `hash_vertex(vertex)`, i.e. the
+/// author's own vote on it.
+#[derive(Clone, Serialize, Deserialize, Archive, RkyvDeserialize, RkyvSerialize, Debug)]
+#[archive(check_bytes)]
+pub struct SignedVertex {
+    pub vertex: Vertex,
+    pub signature: BlsSignature,
+}
+
+/// Proof that an author signed two different vertices for the same round.
+#[derive(Clone, Serialize, Deserialize, Archive, RkyvDeserialize, RkyvSerialize, Debug)]
+#[archive(check_bytes)]
+pub struct EquivocationEvidence {
+    pub first: SignedVertex,
+    pub second: SignedVertex,
+}
+
+/// One committed anchor and the part of its causal history that had not been
+/// committed before, in total order. `certificates[i]` certifies `vertices[i]`.
+#[derive(Clone, Serialize, Deserialize, Archive, RkyvDeserialize, RkyvSerialize, Debug, PartialEq)]
+#[archive(check_bytes)]
+pub struct CommittedSubDag {
+    /// Position in the commit stream, starting at 0. It runs on across epochs.
+    pub index: u64,
+    pub epoch: u64,
+    pub anchor: Hash,
+    pub round: u64,
+    pub vertices: Vec<Vertex>,
+    pub certificates: Vec<AggregatedCoA>,
+}
+
 pub enum VertexState {
     Pending,
     Certified(CoA),
@@ -70,8 +122,28 @@ pub enum Event {
     VertexReceived(Vertex),
     CoAReceived(CoA),
     AggregatedCoAReceived(AggregatedCoA),  // Phase E.4
-    SkipVoteReceived(u64, u32, ValidatorId, Signature),
+    SkipVoteReceived(u64, u64, u32, ValidatorId, Signature),  // epoch, round, anchor, voter, signature
+    SkipCertReceived(SkipCert),
+    EquivocationReceived(EquivocationEvidence),
     Timeout(u64),
+    /// A peer's request and its correlation id, for the node to answer.
+    RequestReceived(ValidatorId, u64, Request),
+    CoinShareReceived(u64, u64, ValidatorId, BlsSignature),  // epoch, round, voter, share of the round's coin
+}
+
+/// Asked of one peer, which answers with a `Response` under the same correlation id.
+#[derive(Clone, Serialize, Deserialize, Archive, RkyvDeserialize, RkyvSerialize, Debug)]
+#[archive(check_bytes)]
+pub enum Request {
+    /// Certified vertices by hash, such as the parents a buffered vertex waits on.
+    Certified(Vec<Hash>),
+}
+
+#[derive(Clone, Serialize, Deserialize, Archive, RkyvDeserialize, RkyvSerialize, Debug)]
+#[archive(check_bytes)]
+pub enum Response {
+    /// Those of the requested vertices the peer holds certified.
+    Certified(Vec<AggregatedCertifiedVertex>),
 }
 
 #[derive(Clone, Serialize, Deserialize, Archive, RkyvDeserialize, RkyvSerialize, Debug)]
@@ -80,7 +152,12 @@ pub enum Message {
     Vertex(Vertex),
     CoA(CoA),
     AggregatedCoA(AggregatedCoA),  // Phase E.4
-    SkipVote(u64, u32, ValidatorId, Signature),
+    SkipVote(u64, u64, u32, ValidatorId, Signature),
+    SkipCert(SkipCert),
+    Equivocation(EquivocationEvidence),
+    Request(u64, Request),
+    Response(u64, Response),
+    CoinShare(u64, u64, ValidatorId, BlsSignature),
 }
 
 impl Message {
@@ -90,4 +167,20 @@ impl Message {
             _ => None,
         }
     }
+
+    /// What this message becomes once received from `peer`. A response is not
+    /// an event: it goes to the request waiting for it.
+    pub fn into_event(self, peer: ValidatorId) -> Option<Event> {
+        Some(match self {
+            Message::Vertex(v) => Event::VertexReceived(v),
+            Message::CoA(coa) => Event::CoAReceived(coa),
+            Message::AggregatedCoA(agg) => Event::AggregatedCoAReceived(agg),
+            Message::SkipVote(epoch, round, anchor, voter, sig) => Event::SkipVoteReceived(epoch, round, anchor, voter, sig),
+            Message::SkipCert(cert) => Event::SkipCertReceived(cert),
+            Message::Equivocation(ev) => Event::EquivocationReceived(ev),
+            Message::Request(id, request) => Event::RequestReceived(peer, id, request),
+            Message::Response(..) => return None,
+            Message::CoinShare(epoch, round, voter, sig) => Event::CoinShareReceived(epoch, round, voter, sig),
+        })
+    }
 }